@@ -1,20 +1,398 @@
 use structopt::StructOpt;
-use std::path::PathBuf;
-use std::io::Result;
+use rayon::prelude::*;
+use std::collections::BTreeMap;
+use std::fs::read_dir;
+use std::io::{Error, ErrorKind, Result};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::Duration;
 
-use pietra_dura_tiled::TiledConverter;
+use pietra_dura_tiled::{
+    map_dependencies, BuildManifest, ConversionSettings, MapStats, MapWatcher, ObjectMapping, TiledConverter,
+    ldtk_levels, parse_ldtk_level, parse_world, validate_map, OutputFormat, ValidationReport, WritePlan,
+};
 use example_game_integration::LevelPrefab;
 
 #[derive(StructOpt, Debug)]
 struct Cli {
-    #[structopt(parse(from_os_str))]
-    map: std::path::PathBuf,
+    /// Maps to convert. Directories are searched for .tmx, .tmj, .ldtk and .world files and globs are expanded.
+    #[structopt(required = true)]
+    maps: Vec<String>,
+    /// Each map is written to a directory inside this one named after its path from the directory or glob it was found through
+    #[structopt(long = "output", default_value = "assets", parse(from_os_str))]
+    output: PathBuf,
+    /// The game's assets directory, which must contain the output. Prefabs refer to their files from here.
+    #[structopt(long = "assets", default_value = "assets", parse(from_os_str))]
+    assets: PathBuf,
+    /// RON file mapping object types to prefab details
+    #[structopt(long = "mapping", parse(from_os_str))]
+    mapping: Option<PathBuf>,
+    /// Keep running and convert maps again whenever they, their tilesets or their images change
+    #[structopt(long = "watch")]
+    watch: bool,
+    /// How the prefab is written: pretty-ron, ron, json or bincode
+    #[structopt(long = "format", default_value = "pretty-ron")]
+    format: OutputFormat,
+    /// Write each map into a single `<name>.zip` archive instead of a directory
+    #[structopt(long = "archive")]
+    archive: bool,
+    /// Show what would be written, with a diff of the prefab, without writing anything
+    #[structopt(long = "dry-run")]
+    dry_run: bool,
+    /// Convert maps even if their output is already up to date
+    #[structopt(long = "force")]
+    force: bool,
+    /// Check maps for problems instead of converting them
+    #[structopt(long = "validate")]
+    validate: bool,
+    /// Print the validation report as JSON
+    #[structopt(long = "json")]
+    json: bool,
+    /// Where packed sheets of image collection tilesets are kept, so unchanged ones aren't packed again
+    #[structopt(long = "cache", default_value = "target/asset_prep", parse(from_os_str))]
+    cache: PathBuf,
+    /// Split maps into regions of this many tiles, given as WIDTHxHEIGHT, to stream in around the camera
+    #[structopt(long = "regions", parse(try_from_str = "parse_region_size"))]
+    regions: Option<(u32, u32)>,
+}
+
+fn parse_region_size(size: &str) -> std::result::Result<(u32, u32), String> {
+    let mut parts = size.splitn(2, 'x');
+    let mut next = || parts.next()
+        .and_then(|p| p.trim().parse::<u32>().ok())
+        .filter(|n| *n > 0)
+        .ok_or_else(|| format!("expected WIDTHxHEIGHT in tiles, got {:?}", size));
+    Ok((next()?, next()?))
+}
+
+enum Outcome {
+    Converted(MapStats),
+    Planned(WritePlan),
+    UpToDate,
+    Failed(String),
+}
+
+fn is_wildcard(c: char) -> bool {
+    c == '*' || c == '?' || c == '['
+}
+
+// A map file named on the command line or found through a directory or glob. Its
+// output goes under its path from that directory or glob, without the extension, so
+// maps with the same file name in different directories don't overwrite each other.
+#[derive(Debug, Clone)]
+struct MapSource {
+    path: PathBuf,
+    name: PathBuf,
+}
+
+impl MapSource {
+    fn new(base: &Path, path: PathBuf) -> Self {
+        let name = path.strip_prefix(base).unwrap_or(&path).with_extension("");
+        MapSource { path, name }
+    }
+}
+
+fn find_maps(patterns: &[String]) -> Vec<MapSource> {
+    let mut maps = Vec::new();
+    for pattern in patterns {
+        let path = PathBuf::from(pattern);
+        if path.is_dir() {
+            find_maps_in_dir(&path, &path, &mut maps);
+        } else if pattern.contains(is_wildcard) {
+            // Names start from the last directory before the first wildcard
+            let base: PathBuf = path.iter()
+                .take_while(|c| !c.to_string_lossy().contains(is_wildcard))
+                .collect();
+            maps.extend(
+                glob::glob(pattern)
+                    .expect("Invalid glob pattern")
+                    .filter_map(|p| p.ok())
+                    .map(|p| MapSource::new(&base, p))
+            );
+        } else {
+            let base = path.parent().unwrap_or_else(|| Path::new("")).to_path_buf();
+            maps.push(MapSource::new(&base, path));
+        }
+    }
+    maps.sort_by(|a, b| a.path.cmp(&b.path));
+    maps.dedup_by(|a, b| a.path == b.path);
+    maps
+}
+
+fn find_maps_in_dir(base: &Path, dir: &Path, maps: &mut Vec<MapSource>) {
+    for entry in read_dir(dir).expect("Unable to read directory") {
+        let path = entry.expect("Unable to read directory entry").path();
+        if path.is_dir() {
+            find_maps_in_dir(base, &path, maps);
+        } else if path.extension().map(|e| e == "tmx" || e == "tmj" || e == "ldtk" || e == "world").unwrap_or(false) {
+            maps.push(MapSource::new(base, path));
+        }
+    }
+}
+
+// A map, or one level of an LDtk project
+struct MapInput {
+    path: PathBuf,
+    name: PathBuf,
+    level: Option<String>,
+}
+
+impl MapInput {
+    fn label(&self) -> String {
+        match &self.level {
+            Some(level) => format!("{}#{}", self.path.display(), level),
+            None => self.path.display().to_string(),
+        }
+    }
+}
+
+fn is_world(path: &Path) -> bool {
+    path.extension().map(|e| e == "world").unwrap_or(false)
+}
+
+// LDtk projects are converted a level at a time, each into a directory named after
+// it inside one named after the project, as LDtk's default level names are the same
+// in every project.
+// Worlds are expanded into their maps, for validation.
+fn expand_levels(maps: &[MapSource]) -> Vec<MapInput> {
+    let mut inputs = Vec::new();
+    for source in maps {
+        let path = &source.path;
+        if path.extension().map(|e| e == "ldtk").unwrap_or(false) {
+            match ldtk_levels(path) {
+                Ok(levels) => inputs.extend(levels.into_iter().map(|level| MapInput {
+                    path: path.clone(),
+                    name: source.name.join(&level),
+                    level: Some(level),
+                })),
+                Err(e) => println!("error: {}: {}", path.display(), e),
+            }
+        } else if is_world(path) {
+            let dir = path.parent().unwrap_or_else(|| Path::new(""));
+            match parse_world(path) {
+                Ok(world) => inputs.extend(world.maps.iter().map(|m| MapInput {
+                    path: dir.join(&m.file_name),
+                    name: source.name.join(m.name()),
+                    level: None,
+                })),
+                Err(e) => println!("error: {}", e),
+            }
+        } else {
+            inputs.push(MapInput { path: path.clone(), name: source.name.clone(), level: None });
+        }
+    }
+    inputs
+}
+
+// Fails if two maps would be written to the same place
+fn check_outputs(outputs: impl Iterator<Item = (PathBuf, String)>) -> Result<()> {
+    let mut written = BTreeMap::new();
+    for (name, label) in outputs {
+        if let Some(other) = written.insert(name.clone(), label.clone()) {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{} and {} would both be written to {}", other, label, name.display()),
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Where a map's files are found from the assets directory, which is how its prefab
+// refers to them. `main` checks the output is inside the assets directory.
+fn map_prefix(args: &Cli, name: &Path) -> PathBuf {
+    args.output.join(name)
+        .strip_prefix(&args.assets)
+        .expect("Output isn't inside the assets directory")
+        .to_path_buf()
+}
+
+fn convert(map: &MapInput, args: &Cli, settings: &ConversionSettings) -> Outcome {
+    let name = map.name.clone();
+    let output = args.output.join(&name);
+    let prefix = map_prefix(args, &name);
+    let mut inputs = map_dependencies(&map.path);
+    inputs.extend(args.mapping.iter().cloned());
+    let up_to_date = BuildManifest::is_current(&output, &inputs, &LevelPrefab::conversion_key(settings))
+        && output.join(settings.output_format.prefab_file()).is_file();
+    // Archives don't have a build manifest so are always rebuilt
+    if !args.force && !args.archive && up_to_date {
+        return Outcome::UpToDate;
+    }
+
+    // A broken map shouldn't stop the rest of the batch
+    let result = catch_unwind(AssertUnwindSafe(|| -> Result<Outcome> {
+        let mut prefab = match &map.level {
+            Some(level) => LevelPrefab::from_ldtk_level(&map.path, Some(level.as_str()), &prefix, settings),
+            None => LevelPrefab::from_map_with_settings(&map.path, &prefix, settings),
+        };
+        if let Some(mapping) = &args.mapping {
+            prefab.add_input(mapping);
+        }
+        if args.dry_run {
+            return Ok(Outcome::Planned(prefab.plan(&output)?));
+        }
+        if args.archive {
+            prefab.write_archive(&output.with_extension("zip"))?;
+        } else {
+            prefab.write(&output)?;
+        }
+        Ok(Outcome::Converted(prefab.stats().clone()))
+    }));
+    outcome(result)
+}
+
+fn outcome(result: std::thread::Result<Result<Outcome>>) -> Outcome {
+    match result {
+        Ok(Ok(outcome)) => outcome,
+        Ok(Err(e)) => Outcome::Failed(e.to_string()),
+        Err(panic) => Outcome::Failed(
+            panic.downcast_ref::<String>().cloned()
+                .or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()))
+                .unwrap_or_else(|| "conversion panicked".to_string())
+        ),
+    }
+}
+
+// Worlds are always converted as a whole, into a directory named like a map would be
+fn convert_world(world: &MapSource, args: &Cli, settings: &ConversionSettings) -> Outcome {
+    if args.dry_run || args.archive {
+        return Outcome::Failed("--dry-run and --archive aren't supported for worlds".to_string());
+    }
+    let prefix = map_prefix(args, &world.name);
+    let result = catch_unwind(AssertUnwindSafe(|| -> Result<Outcome> {
+        let prefab = LevelPrefab::from_world(&world.path, &prefix, settings);
+        prefab.write(&args.output.join(&world.name))?;
+        let mut stats = MapStats::default();
+        for (map, prefab) in prefab.maps() {
+            stats.entities += prefab.stats().entities;
+            stats.sprite_sheets += prefab.stats().sprite_sheets;
+            stats.warnings.extend(prefab.stats().warnings.iter().map(|w| format!("{}: {}", map, w)));
+        }
+        Ok(Outcome::Converted(stats))
+    }));
+    outcome(result)
+}
+
+fn convert_all(maps: &[MapSource], args: &Cli, settings: &ConversionSettings) -> Result<()> {
+    let (worlds, maps): (Vec<MapSource>, Vec<MapSource>) = maps.iter().cloned().partition(|m| is_world(&m.path));
+    let maps = expand_levels(&maps);
+    check_outputs(
+        maps.iter().map(|map| (map.name.clone(), map.label()))
+            .chain(worlds.iter().map(|world| (world.name.clone(), world.path.display().to_string())))
+    )?;
+    let mut results: Vec<_> = maps.par_iter()
+        .map(|map| (map.label(), convert(map, args, settings)))
+        .collect();
+    results.extend(worlds.iter().map(|world| (world.path.display().to_string(), convert_world(world, args, settings))));
+
+    println!("{:<40} {:>10} {:>8} {:>10}", "map", "entities", "sheets", "warnings");
+    for (map, outcome) in &results {
+        match outcome {
+            Outcome::Converted(stats) => println!(
+                "{:<40} {:>10} {:>8} {:>10}",
+                map, stats.entities, stats.sprite_sheets, stats.warnings.len(),
+            ),
+            Outcome::Planned(plan) => println!(
+                "{:<40} {:>30}", map, if plan.has_changes() { "would change" } else { "no changes" },
+            ),
+            Outcome::UpToDate => println!("{:<40} {:>30}", map, "up to date"),
+            Outcome::Failed(_) => println!("{:<40} {:>30}", map, "FAILED"),
+        }
+    }
+    for (map, outcome) in &results {
+        match outcome {
+            Outcome::Converted(stats) => {
+                for warning in &stats.warnings {
+                    println!("warning: {}: {}", map, warning);
+                }
+            },
+            Outcome::Planned(plan) => {
+                if plan.has_changes() {
+                    println!("\n{}:\n{}", map, plan);
+                }
+            },
+            Outcome::Failed(error) => println!("error: {}: {}", map, error),
+            Outcome::UpToDate => (),
+        }
+    }
+    Ok(())
+}
+
+// Returns true if any map has errors
+fn validate_all(maps: &[MapSource], args: &Cli, settings: &ConversionSettings) -> bool {
+    let reports: BTreeMap<String, ValidationReport> = expand_levels(maps).par_iter()
+        .map(|map| {
+            let report = match &map.level {
+                Some(level) => match parse_ldtk_level(&map.path, Some(level.as_str())) {
+                    Ok(level) => {
+                        let mut rules = LevelPrefab::validation_rules();
+                        if let Some(mapping) = &settings.object_mapping {
+                            rules.add_mapping(mapping);
+                        }
+                        validate_map(&level, &rules)
+                    },
+                    Err(e) => ValidationReport::parse_error(e),
+                },
+                None => LevelPrefab::validate(&map.path, settings),
+            };
+            (map.label(), report)
+        })
+        .collect();
+
+    if args.json {
+        let reports: BTreeMap<_, _> = reports.iter()
+            .map(|(map, report)| (map.clone(), &report.issues))
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&reports).expect("Failed to encode validation reports"));
+    } else {
+        for (map, report) in &reports {
+            println!("{}\n{}\n", map, report);
+        }
+    }
+    reports.values().any(|r| r.has_errors())
 }
 
 fn main() -> Result<()> {
     let args = Cli::from_args();
-    LevelPrefab::from_map(&args.map, &PathBuf::from("map"))
-        .write(&PathBuf::from("assets/map"))?;
+    let object_mapping = match &args.mapping {
+        Some(path) => Some(ObjectMapping::load(path).map_err(|e| Error::new(ErrorKind::Other, e))?),
+        None => None,
+    };
+    let settings = ConversionSettings {
+        object_mapping,
+        output_format: args.format,
+        region_size: args.regions,
+        sheet_cache: Some(args.cache.clone()),
+        ..ConversionSettings::default()
+    };
+    if !args.output.starts_with(&args.assets) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("--output {} isn't inside --assets {}", args.output.display(), args.assets.display()),
+        ));
+    }
+    let maps = find_maps(&args.maps);
+    if args.validate {
+        if validate_all(&maps, &args, &settings) {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    convert_all(&maps, &args, &settings)?;
+
+    if args.watch {
+        let mut watchers: Vec<_> = maps.iter().map(|m| (MapWatcher::new(m.path.clone()), m)).collect();
+        loop {
+            sleep(Duration::from_millis(500));
+            let changed: Vec<MapSource> = watchers.iter_mut()
+                .filter_map(|(w, m)| if w.changed() { Some((*m).clone()) } else { None })
+                .collect();
+            if !changed.is_empty() {
+                convert_all(&changed, &args, &settings)?;
+            }
+        }
+    }
     Ok(())
 }
-
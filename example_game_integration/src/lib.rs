@@ -1,41 +1,64 @@
 use amethyst::{
-    assets::{PrefabData, ProgressCounter},
-    core::Transform,
+    assets::{AssetStorage, Handle, Prefab, PrefabData, ProgressCounter},
+    core::{Named, Transform},
     derive::PrefabData,
     ecs::prelude::*,
     error::Error,
-    renderer::sprite::prefab::{SpriteRenderPrefab, SpriteSheetPrefab},
+    renderer::sprite::{
+        prefab::{SpriteRenderPrefab, SpriteSheetPrefab},
+        SpriteRender, SpriteSheet,
+    },
 };
 use serde::{Deserialize, Serialize};
 use specs_derive::Component;
 
 use pietra_dura_nphysics::{
-    PhysicsEntityPrefab
+    CollisionGroupPrefab, ColliderPrefab, PhysicsEntity, PhysicsEntityPrefab, ShapePrefab
 };
-
-#[cfg(feature = "asset-prep")]
 use pietra_dura_tiled::{
-    TiledConverter, SpriteContext, SpriteSheetPrefab as SpriteSheetPrefabProxy, SpriteRenderPrefab as SpriteRenderPrefabProxy
-};
-#[cfg(feature = "asset-prep")]
-use pietra_dura_nphysics::{
-    CollisionGroupPrefab, ColliderPrefab, ShapePrefab
-};
-#[cfg(feature = "asset-prep")]
-use amethyst::{
-    renderer::sprite::prefab::SpriteSheetReference,
+    derive::{FromTiledObject, TiledDetail},
+    sprite_sheet_tileset, ColliderCells, MapExport, Object, ObjectShape, ObjectType, Parallax, SpriteContext,
+    TileCell, TileIndex, TiledExporter, YSort,
 };
+
 #[cfg(feature = "asset-prep")]
-use tiled::{Object, ObjectShape};
+use pietra_dura_tiled::derive::{PrefabProxy, TiledConverter};
 
 pub type LevelPrefabHandle = amethyst::assets::Handle<amethyst::assets::Prefab<LevelPrefab>>;
 
+// With the asset-prep feature the TiledConverter implementation is derived. It
+// gives every tile and every object with a tile image a sprite and transform,
+// and leaves the rest to `Detail`.
+//
+// Because a number of important Prefabs in Amethyst are currently impossible
+// to construct or serialize outside of amethyst itself the converter actually
+// produces a proxy type which will result in the same RON output as the real
+// types would. `PrefabProxy` generates `LevelPrefabProxy` with the same fields
+// as `LevelPrefab`, swapping in pietra_dura_tiled's versions of the sprite prefabs.
 #[derive(Debug, Clone, Deserialize, Serialize, PrefabData)]
+#[cfg_attr(feature = "asset-prep", derive(PrefabProxy, TiledConverter))]
+#[cfg_attr(feature = "asset-prep", proxy(name = "LevelPrefabProxy"), tiled(proxy = "LevelPrefabProxy"))]
 pub struct LevelPrefab {
-    sheet: Option<SpriteSheetPrefab>,
-    render: Option<SpriteRenderPrefab>,
-    transform: Option<Transform>,
-    detail: Detail,
+    pub sheet: Option<SpriteSheetPrefab>,
+    pub render: Option<SpriteRenderPrefab>,
+    pub transform: Option<Transform>,
+    // Only tiles and objects from layers with parallax factors have one. The proxy
+    // leaves it out of RON and JSON when it's None.
+    #[serde(default)]
+    pub parallax: Option<Parallax>,
+    // Only objects from y sorted object groups have one
+    #[serde(default)]
+    pub y_sort: Option<YSort>,
+    // Objects keep their names and types so they can be found through `ObjectIndex`
+    #[serde(default)]
+    pub name: Option<Named>,
+    #[serde(default)]
+    pub object_type: Option<ObjectType>,
+    // Collision rectangles keep the cells under them so they can be rebuilt by
+    // `rebuild_collision` when those tiles are edited
+    #[serde(default)]
+    pub collider_cells: Option<ColliderCells>,
+    pub detail: Detail,
 }
 
 #[derive(Copy, Clone, Debug, Deserialize, Serialize)]
@@ -49,167 +72,177 @@ impl Into<usize> for CollisionTypes {
     }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize, PrefabData)]
+// The tiled attributes pick which variant each tile or object type becomes.
+#[derive(Debug, Clone, Deserialize, Serialize, PrefabData, TiledDetail)]
 pub enum Detail {
-    Tile(#[prefab(Component)] Tile),
+    // Keeping the cell on the tile lets it be found and replaced at runtime
+    // through `TileEdits`.
+    #[tiled(tile)]
+    Tile(#[prefab(Component)] TileCell),
+    #[tiled(object_type = "static", requires_sprite)]
     StaticSprite(#[prefab(Component)] StaticSprite),
+    #[tiled(object_type = "dynamic", requires_sprite, collider, with = "dynamic_physics")]
+    #[tiled(object_type = "collision", collider, with = "collision_physics")]
     Physics(PhysicsEntityPrefab<CollisionTypes>),
 }
-#[derive(Default, Debug, Copy, Clone, Component, Serialize, Deserialize)]
-pub struct Tile;
 
-#[derive(Default, Debug, Copy, Clone, Component, Serialize, Deserialize)]
+#[derive(Default, Debug, Copy, Clone, Component, Serialize, Deserialize, FromTiledObject)]
 pub struct StaticSprite;
 
-// Because a number of important Prefabs in Amethyst are currently impossible
-// to construct or serialize outside of amethyst itself we will actually
-// produce a proxy type which will result in the same RON output as the real
-// types would.
-//
-// Some types, like `Transform`, serialize perfectly so we can use those directly.
-#[cfg(feature = "asset-prep")]
-#[derive(Debug, Clone, Serialize)]
-pub struct LevelPrefabProxy {
-    pub sheet: Option<SpriteSheetPrefabProxy>,
-    pub render: Option<SpriteRenderPrefabProxy>,
-    pub transform: Option<Transform>,
-    pub detail: Detail,
-
+fn dynamic_physics(_ctx: &Option<SpriteContext>, object: &Object) -> Option<PhysicsEntityPrefab<CollisionTypes>> {
+    if let ObjectShape::Rect { width, height } = object.shape {
+        Some(PhysicsEntityPrefab {
+            colliders: vec![ColliderPrefab {
+                // Assume that everything is a circle
+                shape: ShapePrefab::Ball { radius: width / 2.0 },
+                density: 1.0,
+                restitution: 0.8,
+                friction: 0.5,
+                offset_x: 0.0,
+                offset_y: 0.0,
+                is_sensor: false,
+                collision_group: CollisionGroupPrefab {
+                    membership: vec![CollisionTypes::Main],
+                    whitelist: vec![CollisionTypes::Main],
+                    blacklist: vec![],
+                },
+                location: None,
+            }],
+            collider_only: false,
+            gravity_enabled: true,
+            no_rotate: false,
+            location: Some((object.x + width / 2.0, -object.y + height / 2.0)),
+        })
+    } else {
+        None
+    }
 }
-#[cfg(feature = "asset-prep")]
-impl TiledConverter<'_, LevelPrefab> for LevelPrefab {
-    type PrefabProxy = LevelPrefabProxy;
 
-    fn convert_tile(ctx: &Option<SpriteContext>, x: f32, y: f32, layer: usize) -> Option<Self::PrefabProxy> {
-        // The SpriteContext contains information about the sprite sheet which this tile
-        // references. If ctx is None then this is an empty tile.
-        if let Some(ctx) = ctx {
-            let render = SpriteRenderPrefabProxy {
-                sheet: Some(SpriteSheetReference::Name(ctx.name.clone())),
-                sprite_number: ctx.sprite_id as usize,
-            };
-            let mut transform = Transform::default();
-            transform.set_translation_xyz(
-                x,
-                // In tiled's coordinate system down is positive, so flip it.
-                -y,
-                layer as f32,
-            );
-            Some(Self::PrefabProxy {
-                // ctx.sprite_sheet will be Some(SpriteSheetPrefabProxy) if
-                // this is the first time this sheet is being referenced
-                // otherwise it will be None. You shouldn't have to worry
-                // about it unless you're doing something complicated.
-                sheet: ctx.sprite_sheet.clone(),
-                render: Some(render),
-                transform: Some(transform),
-                detail: Detail::Tile(Tile),
-            })
-        } else {
-            None
-        }
+fn collision_physics(_ctx: &Option<SpriteContext>, object: &Object) -> Option<PhysicsEntityPrefab<CollisionTypes>> {
+    if let ObjectShape::Rect { width, height } = object.shape {
+        Some(collision_rect(object.x + width / 2.0, -object.y - height / 2.0, width, height))
+    } else {
+        None
     }
+}
 
-    fn convert_object(ctx: &Option<SpriteContext>, layer: usize, object: &Object) -> Option<Self::PrefabProxy> {
-        // If ctx is None then this object doesn't have a tile image associated with it.
-        match object.obj_type.as_ref() {
-            "static" => {
-                if let Some(ctx) = ctx {
-                    if let ObjectShape::Rect { width, height } = object.shape {
-                        let render = SpriteRenderPrefabProxy {
-                            sheet: Some(SpriteSheetReference::Name(ctx.name.clone())),
-                            sprite_number: ctx.sprite_id as usize,
-                        };
-                        let mut transform = Transform::default();
-                        transform.set_translation_xyz(
-                            object.x + width / 2.0,
-                            -object.y + height / 2.0,
-                            layer as f32,
-                        );
-                        return Some(Self::PrefabProxy {
-                            sheet: ctx.sprite_sheet.clone(),
-                            render: Some(render),
-                            transform: Some(transform),
-                            detail: Detail::StaticSprite(StaticSprite),
-                        })
-                    }
-                }
+fn collision_rect(x: f32, y: f32, width: f32, height: f32) -> PhysicsEntityPrefab<CollisionTypes> {
+    PhysicsEntityPrefab {
+        colliders: vec![ColliderPrefab {
+            shape: ShapePrefab::Rect { width, height },
+            density: 1.0,
+            restitution: 0.8,
+            friction: 0.5,
+            offset_x: 0.0,
+            offset_y: 0.0,
+            is_sensor: false,
+            collision_group: CollisionGroupPrefab {
+                membership: vec![CollisionTypes::Main],
+                whitelist: vec![CollisionTypes::Main],
+                blacklist: vec![],
             },
-            "dynamic" => {
-                if let Some(ctx) = ctx {
-                    if let ObjectShape::Rect { width, height } = object.shape {
-                        let render = SpriteRenderPrefabProxy {
-                            sheet: Some(SpriteSheetReference::Name(ctx.name.clone())),
-                            sprite_number: ctx.sprite_id as usize,
-                        };
-                        let mut transform = Transform::default();
-                        transform.set_translation_xyz(
-                            object.x + width / 2.0,
-                            -object.y + height / 2.0,
-                            layer as f32,
-                        );
-                        return Some(Self::PrefabProxy {
-                            sheet: ctx.sprite_sheet.clone(),
-                            render: Some(render),
-                            transform: Some(transform),
-                            detail: Detail::Physics(PhysicsEntityPrefab {
-                                colliders: vec![ColliderPrefab {
-                                    // Assume that everything is a circle
-                                    shape: ShapePrefab::Ball { radius: width / 2.0 },
-                                    density: 1.0,
-                                    restitution: 0.8,
-                                    friction: 0.5,
-                                    offset_x: 0.0,
-                                    offset_y: 0.0,
-                                    is_sensor: false,
-                                    collision_group: CollisionGroupPrefab {
-                                        membership: vec![CollisionTypes::Main],
-                                        whitelist: vec![CollisionTypes::Main],
-                                        blacklist: vec![],
-                                    },
-                                    location: None,
-                                }],
-                                collider_only: false,
-                                gravity_enabled: true,
-                                no_rotate: false,
-                                location: Some((object.x + width / 2.0, -object.y + height / 2.0)),
-                            }),
-                        })
+            location: Some((x, y)),
+        }],
+        collider_only: true,
+        gravity_enabled: false,
+        no_rotate: false,
+        location: None,
+    }
+}
+
+// Given to `TileEdits::with_collider_rebuild`. Collision rectangles are cut down to
+// the tiles of their own level or region still under them, on the layer they were
+// converted standing on, when tiles are edited.
+pub fn rebuild_collision(cells: &ColliderCells, root: Entity, index: &TileIndex) -> Vec<LevelPrefab> {
+    cells.runs(|x, y| index.get(root, &TileCell::new(cells.layer, x, y)).is_some())
+        .into_iter()
+        .map(|run| {
+            let (x, y, width, height) = run.rect();
+            LevelPrefab {
+                sheet: None,
+                render: None,
+                transform: None,
+                parallax: None,
+                y_sort: None,
+                name: None,
+                object_type: Some(ObjectType("collision".to_string())),
+                collider_cells: Some(run),
+                detail: Detail::Physics(collision_rect(x, y, width, height)),
+            }
+        })
+        .collect()
+}
+
+// Writes the level in the world back out as a Tiled map. Tiles and sprite objects
+// are read from their components, objects keeping their `Named` and `ObjectType`.
+// Collision rectangles only exist in the physics world by then, so they are copied
+// from the original map.
+pub struct LevelExporter;
+impl<'a> TiledExporter<'a> for LevelExporter {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, LevelPrefabHandle>,
+        Read<'a, AssetStorage<Prefab<LevelPrefab>>>,
+        ReadStorage<'a, SpriteRender>,
+        ReadStorage<'a, Transform>,
+        ReadStorage<'a, TileCell>,
+        ReadStorage<'a, StaticSprite>,
+        ReadStorage<'a, PhysicsEntity>,
+        ReadStorage<'a, Named>,
+        ReadStorage<'a, ObjectType>,
+    );
+
+    fn export(
+        (entities, levels, prefabs, sprites, transforms, cells, static_sprites, physics, names, types): Self::SystemData,
+        export: &mut MapExport,
+    ) {
+        // Once loaded each sheet in the prefab is replaced by its handle, keeping the
+        // name the converter gave it, which says which tileset it came from
+        let mut sheets: Vec<(Handle<SpriteSheet>, usize)> = Vec::new();
+        for prefab in levels.join().filter_map(|handle| prefabs.get(handle)) {
+            for entity in prefab.entities() {
+                if let Some(LevelPrefab { sheet: Some(SpriteSheetPrefab::Handle((Some(name), handle))), .. }) = entity.data() {
+                    if let Some(tileset) = sprite_sheet_tileset(name) {
+                        sheets.push((handle.clone(), tileset));
                     }
                 }
-            },
-            "collision" => {
-                if let ObjectShape::Rect { width, height } = object.shape {
-                    return Some(Self::PrefabProxy {
-                        sheet: None,
-                        render: None,
-                        transform: None,
-                        detail: Detail::Physics(PhysicsEntityPrefab {
-                            colliders: vec![ColliderPrefab {
-                                shape: ShapePrefab::Rect { width, height },
-                                density: 1.0,
-                                restitution: 0.8,
-                                friction: 0.5,
-                                offset_x: 0.0,
-                                offset_y: 0.0,
-                                is_sensor: false,
-                                collision_group: CollisionGroupPrefab {
-                                    membership: vec![CollisionTypes::Main],
-                                    whitelist: vec![CollisionTypes::Main],
-                                    blacklist: vec![],
-                                },
-                                location: Some((object.x + width / 2.0, -object.y - height / 2.0)),
-                            }],
-                            collider_only: true,
-                            gravity_enabled: false,
-                            no_rotate: false,
-                            location: None,
-                        }),
-                    })
-                }
-            },
-            _ => (),
+            }
         }
-        None
+        let gid = |sprite: &SpriteRender| {
+            sheets.iter()
+                .find(|(handle, _)| *handle == sprite.sprite_sheet)
+                .and_then(|(_, tileset)| export.gid(*tileset, sprite.sprite_number))
+        };
+
+        let mut tiles = Vec::new();
+        let mut objects = Vec::new();
+        for (entity, sprite, transform) in (&*entities, &sprites, &transforms).join() {
+            let gid = match gid(sprite) {
+                Some(gid) => gid,
+                None => continue,
+            };
+            let obj_type = if let Some(cell) = cells.get(entity) {
+                tiles.push((*cell, gid));
+                continue;
+            } else if static_sprites.contains(entity) {
+                "static"
+            } else if physics.contains(entity) {
+                "dynamic"
+            } else {
+                continue;
+            };
+            let obj_type = types.get(entity).map_or(obj_type, |t| t.0.as_str());
+            let name = names.get(entity).map(|n| n.name.to_string());
+            objects.push((obj_type, name, gid, transform.clone()));
+        }
+
+        for (cell, gid) in tiles {
+            export.set_tile(cell, gid);
+        }
+        for (obj_type, name, gid, transform) in objects {
+            let group = export.group_of_type(obj_type);
+            export.add_tile_object(group, obj_type, name.as_ref().map(String::as_str), gid, &transform);
+        }
+        export.keep_objects(|object| object.obj_type == "collision");
     }
 }
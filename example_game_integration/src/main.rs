@@ -1,5 +1,5 @@
 use amethyst::{
-    assets::{PrefabLoader, PrefabLoaderSystem, Processor, ProgressCounter, RonFormat},
+    assets::{PrefabLoader, PrefabLoaderSystem, Processor, Progress, RonFormat},
     core::transform::Transform,
     core::TransformBundle,
     ecs::{Join, ReadExpect, Resources, SystemData},
@@ -19,12 +19,19 @@ use amethyst::{
         types::DefaultBackend,
         GraphCreator, RenderingSystem,
     },
+    shrev::{EventChannel, ReaderId},
     utils::application_root_dir,
     window::{ScreenDimensions, Window, WindowBundle, DisplayConfig},
     Application, GameData, GameDataBuilder, SimpleState, SimpleTrans, StateData, Trans,
 };
 use pietra_dura_nphysics::PhysicsBundle;
-use example_game_integration::LevelPrefab;
+use pietra_dura_tiled::{
+    HotReloadLevel, LevelLoaded, LevelLoadedSystem, LevelReloadSystem, MapWatcher, NavGrid,
+    ObjectIndexSystem, ParallaxSystem, TileEditSystem, TileEdits, TileIndexSystem, TrackLevel, YSortSystem,
+};
+use example_game_integration::{rebuild_collision, LevelPrefab, LevelPrefabHandle};
+#[cfg(feature = "asset-prep")]
+use pietra_dura_tiled::TmxFormat;
 use std::sync::Arc;
 use nalgebra::Vector2;
 use nphysics2d::world::World as PhysicsWorld;
@@ -32,44 +39,90 @@ use nphysics2d::world::World as PhysicsWorld;
 /// The main state
 #[derive(Default)]
 struct Example {
-    /// A progress tracker to check that assets are loaded
-    pub progress_counter: Option<ProgressCounter>,
+    /// Reads the `LevelLoaded` events published once the level is spawned
+    level_loaded: Option<ReaderId<LevelLoaded>>,
 }
 
 impl SimpleState for Example {
     fn on_start(&mut self, data: StateData<'_, GameData<'_, '_>>) {
         let StateData { world, .. } = data;
-        // Crates new progress counter
-        self.progress_counter = Some(Default::default());
+        self.level_loaded = Some(world.write_resource::<EventChannel<LevelLoaded>>().register_reader());
 
         let mut physics_world: PhysicsWorld<f32> = PhysicsWorld::new();
         physics_world.set_gravity(Vector2::new(0.0, -980.0));
         world.add_resource(physics_world);
 
-        // Starts asset loading
+        // The navigation grid is plain RON so it can be read directly
+        let nav_path = application_root_dir().unwrap().join("assets/map/nav.ron");
+        world.add_resource(NavGrid::load(&nav_path).expect("Unable to load navigation grid"));
+
+        // Collision is cut down to the tiles left under it when tiles are edited
+        world.add_resource(TileEdits::<LevelPrefab>::default().with_collider_rebuild(rebuild_collision));
+
+        // Starts asset loading, tracking the level's progress on its own
+        let mut track_level = TrackLevel::new("map");
         let level_prefab = world.exec(|loader: PrefabLoader<'_, LevelPrefab>| {
-            loader.load(
-                "map/map.ron",
-                RonFormat,
-                self.progress_counter.as_mut().unwrap(),
-            )
+            load_level(&loader, track_level.progress_mut())
         });
 
-        // Creates level
-        world.create_entity().with(level_prefab).build();
+        // Creates level, which will be reloaded whenever the map changes on disk
+        world
+            .create_entity()
+            .with(level_prefab)
+            .with(track_level)
+            .with(HotReloadLevel::new(level_watcher(), |loader| load_level(loader, ())).with_nav_grid(nav_path))
+            .build();
         // Creates a new camera
         initialise_camera(world);
     }
 
     fn update(&mut self, data: &mut StateData<'_, GameData<'_, '_>>) -> SimpleTrans {
-        println!(
-            "{} entities in world",
-            (&data.world.entities()).join().count()
-        );
+        let loaded: Vec<LevelLoaded> = data.world.read_resource::<EventChannel<LevelLoaded>>()
+            .read(self.level_loaded.as_mut().unwrap())
+            .cloned()
+            .collect();
+        for level in loaded {
+            println!(
+                "{} loaded, {} entities in world",
+                level.map,
+                (&data.world.entities()).join().count()
+            );
+        }
         Trans::None
     }
 }
 
+#[cfg(not(feature = "asset-prep"))]
+fn load_level<P: Progress>(loader: &PrefabLoader<'_, LevelPrefab>, progress: P) -> LevelPrefabHandle {
+    loader.load(
+        "map/map.ron",
+        RonFormat,
+        progress,
+    )
+}
+
+// Run `asset_prep --watch` to regenerate this as the map is edited
+#[cfg(not(feature = "asset-prep"))]
+fn level_watcher() -> MapWatcher {
+    MapWatcher::new(application_root_dir().unwrap().join("assets/map/map.ron"))
+}
+
+// With the converter compiled in the map can be loaded straight from the Tiled file
+#[cfg(feature = "asset-prep")]
+fn load_level<P: Progress>(loader: &PrefabLoader<'_, LevelPrefab>, progress: P) -> LevelPrefabHandle {
+    let assets_directory = application_root_dir().unwrap().join("assets");
+    loader.load(
+        "../raw_assets/map.tmx",
+        TmxFormat::<LevelPrefab>::new(assets_directory, "map"),
+        progress,
+    )
+}
+
+#[cfg(feature = "asset-prep")]
+fn level_watcher() -> MapWatcher {
+    MapWatcher::new(application_root_dir().unwrap().join("raw_assets/map.tmx"))
+}
+
 fn initialise_camera(world: &mut World) {
     let (width, height) = {
         let dim = world.read_resource::<ScreenDimensions>();
@@ -96,13 +149,34 @@ fn main() -> amethyst::Result<()> {
 
     let game_data = GameDataBuilder::default()
         .with_bundle(WindowBundle::from_config(DisplayConfig::default()))?
-        .with_bundle(TransformBundle::new())?
+        // Parallax layers are moved and y sorted entities sorted before their
+        // transforms are updated
+        .with(ParallaxSystem, "parallax", &[])
+        .with(YSortSystem, "y_sort", &[])
+        .with_bundle(TransformBundle::new().with_dep(&["parallax", "y_sort"]))?
         .with_bundle(PhysicsBundle::new())?
         .with(
             PrefabLoaderSystem::<LevelPrefab>::default(),
             "scene_loader",
             &[],
         )
+        .with(
+            LevelReloadSystem::<LevelPrefab>::default(),
+            "level_reload",
+            &[],
+        )
+        .with(TileIndexSystem::default(), "tile_index", &[])
+        .with(ObjectIndexSystem, "object_index", &[])
+        .with(
+            LevelLoadedSystem::<LevelPrefab>::default(),
+            "level_loaded",
+            &["scene_loader"],
+        )
+        .with(
+            TileEditSystem::<LevelPrefab>::default(),
+            "tile_edits",
+            &["tile_index"],
+        )
         .with(
             Processor::<SpriteSheet>::new(),
             "sprite_sheet_processor",
@@ -0,0 +1,49 @@
+#![cfg(feature = "asset-prep")]
+
+// Maps built in code go through the same conversion as ones read from files.
+
+mod common;
+
+use example_game_integration::Detail;
+use pietra_dura_tiled::{MapBuilder, Object};
+
+use common::{builder, convert, decode, entities};
+
+#[test]
+fn built_map_converts() {
+    let (mut builder, tiles) = builder(4, 3);
+    builder.layer("ground")
+        .fill_rect(0, 2, 4, 1, tiles)
+        .set(0, 1, tiles + 2);
+    builder.object_group("objects")
+        .add(Object::rect("collision", 0.0, 64.0, 128.0, 32.0))
+        .add(Object::tile("static", tiles + 1, 64.0, 64.0, 32.0, 32.0))
+        // Static sprites need a tile so this one is skipped
+        .add(Object::rect("static", 0.0, 0.0, 32.0, 32.0));
+    let map = builder.build();
+
+    let prefab = convert(map, "built");
+    let stats = prefab.stats();
+    assert_eq!(stats.entities, 7);
+    assert_eq!(stats.entity_kinds.get("Tile"), Some(&5));
+    assert_eq!(stats.entity_kinds.get("StaticSprite"), Some(&1));
+    assert_eq!(stats.entity_kinds.get("Physics"), Some(&1));
+    assert_eq!(stats.sprite_sheets, 1);
+
+    let prefab = decode(&prefab);
+    let entities = entities(&prefab);
+    assert_eq!(entities.len(), 7);
+    let tiles = entities.iter().filter(|e| match e.detail { Detail::Tile(_) => true, _ => false }).count();
+    assert_eq!(tiles, 5);
+    // Only the first entity using a sprite sheet carries it
+    assert_eq!(entities.iter().filter(|e| e.sheet.is_some()).count(), 1);
+}
+
+#[test]
+fn gids_follow_on_between_tilesets() {
+    let mut builder = MapBuilder::new(1, 1, 32, 32);
+    assert_eq!(builder.image_tileset("tiles", "tiles.png", 32, 32, 96, 32), 1);
+    assert_eq!(builder.image_tileset("more", "tiles.png", 32, 32, 96, 32), 4);
+    let map = builder.build();
+    assert_eq!(map.tilesets[1].first_gid, 4);
+}
@@ -0,0 +1,50 @@
+// Shared by the integration tests, each of which only uses some of it
+#![allow(dead_code)]
+
+use std::fs::{remove_file, write};
+use std::path::{Path, PathBuf};
+
+use amethyst::assets::Prefab;
+use example_game_integration::{LevelPrefab, LevelPrefabProxy};
+use pietra_dura_tiled::{parse_map, write_tmx, ConversionSettings, Map, MapBuilder, MapPrefab, TiledConverter};
+
+pub fn raw_assets() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("raw_assets")
+}
+
+// A map builder with `raw_assets/tiles.png` as its first tileset, returning the
+// gid of the tileset's first tile alongside it
+pub fn builder(width: u32, height: u32) -> (MapBuilder, u32) {
+    let mut builder = MapBuilder::new(width, height, 32, 32);
+    let tiles = builder.image_tileset("tiles", "tiles.png", 32, 32, 96, 32);
+    (builder, tiles)
+}
+
+// Writes the map as TMX and parses it again
+pub fn read_back(map: &Map, name: &str) -> Map {
+    let path = std::env::temp_dir().join(format!("pietra_dura_{}_{}.tmx", name, std::process::id()));
+    write(&path, write_tmx(map)).expect("Unable to write map");
+    let read = parse_map(&path);
+    remove_file(&path).expect("Unable to clean up output");
+    read.expect("Unable to parse written map")
+}
+
+pub fn convert(map: Map, prefix: &str) -> MapPrefab<LevelPrefab, LevelPrefabProxy> {
+    convert_with_settings(map, prefix, &ConversionSettings::default())
+}
+
+pub fn convert_with_settings(map: Map, prefix: &str, settings: &ConversionSettings) -> MapPrefab<LevelPrefab, LevelPrefabProxy> {
+    LevelPrefab::from_map_data(map, &raw_assets(), Path::new(prefix), settings)
+}
+
+// The converted prefab as the game would load it
+pub fn decode(map: &MapPrefab<LevelPrefab, LevelPrefabProxy>) -> Prefab<LevelPrefab> {
+    map.output_format()
+        .decode(map.prefab_data().expect("No prefab was produced"))
+        .expect("Unable to decode prefab")
+}
+
+// Every entity's data, leaving out the empty root
+pub fn entities(prefab: &Prefab<LevelPrefab>) -> Vec<&LevelPrefab> {
+    prefab.entities().filter_map(|entity| entity.data()).collect()
+}
@@ -0,0 +1,57 @@
+#![cfg(feature = "asset-prep")]
+
+mod common;
+
+use pietra_dura_tiled::{object_bottom, DepthSettings, Map, Object};
+
+use common::{builder, convert, decode, entities};
+
+// A ground layer, a layer put in front of everything with its `z` property and a
+// y sorted group of two tile objects, one further down than the other
+fn depth_map() -> Map {
+    let (mut builder, tiles) = builder(4, 4);
+    builder.layer("ground").fill(tiles);
+    builder.layer("roofs").set(0, 0, tiles).property("z", 100.0f32);
+    builder.object_group("things")
+        .property("ysort", true)
+        .add(Object::tile("static", tiles + 1, 0.0, 64.0, 32.0, 32.0))
+        .add(Object::tile("static", tiles + 1, 32.0, 96.0, 32.0, 32.0));
+    builder.build()
+}
+
+#[test]
+fn layers_are_spaced_from_their_base() {
+    let map = depth_map();
+    let depth = DepthSettings::default().with_layer_base(10.0).with_group_base(20.0).with_spacing(2.0);
+    assert_eq!(depth.layer_depth(&map, 0, 0.0).z, 10.0);
+    assert_eq!(depth.layer_depth(&map, 1, 0.0).z, 100.0);
+    assert_eq!(depth.group_depth(&map, 0, 0.0).z, 20.0);
+}
+
+#[test]
+fn y_sorted_objects_lower_down_are_in_front() {
+    let map = depth_map();
+    let depth = DepthSettings::default().group_depth(&map, 0, 0.0);
+    assert!(depth.y_sort.is_some());
+    let objects = &map.object_groups[0].objects;
+    let (upper, lower) = (depth.z(object_bottom(&objects[0])), depth.z(object_bottom(&objects[1])));
+    assert!(upper < lower);
+    // Sorting stays within the group's own z
+    assert!(0.0 <= upper && lower < 1.0);
+}
+
+#[test]
+fn y_sorted_objects_keep_sorting() {
+    let prefab = decode(&convert(depth_map(), "depth"));
+    let entities = entities(&prefab);
+    // Only the two objects, tiles are sorted once when they are converted
+    let sorted: Vec<_> = entities.iter()
+        .filter(|e| e.y_sort.is_some())
+        .map(|e| e.transform.as_ref().expect("Sorted objects have a transform").translation().z)
+        .collect();
+    assert_eq!(sorted.len(), 2);
+    assert!(sorted[0] < sorted[1]);
+    // The roof is in front of everything
+    let roof = entities[16].transform.as_ref().unwrap().translation().z;
+    assert_eq!(roof, 100.0);
+}
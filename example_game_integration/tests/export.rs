@@ -0,0 +1,218 @@
+#![cfg(feature = "asset-prep")]
+
+// Exported maps have to read back as the map they were made from, otherwise levels
+// would drift every time they went from the game to Tiled and back.
+
+mod common;
+
+use std::collections::HashMap;
+use std::fs::{copy, create_dir_all, remove_dir_all, write};
+use std::path::Path;
+use std::sync::Arc;
+
+use amethyst::{
+    assets::{AssetStorage, Handle, Loader, Prefab},
+    core::{Named, Transform},
+    ecs::prelude::*,
+    renderer::{
+        formats::texture::ImageFormat,
+        sprite::{prefab::{SpriteSheetPrefab, SpriteSheetReference}, SpriteRender, SpriteSheet},
+        types::Texture,
+    },
+};
+use nphysics2d::object::BodyHandle;
+
+use example_game_integration::{Detail, LevelExporter, LevelPrefab, StaticSprite};
+use pietra_dura_nphysics::PhysicsEntity;
+use pietra_dura_tiled::{
+    parse_map, sprite_sheets_from_tilesets, write_tmx, ConversionSettings, DepthSettings, MapExport, Object,
+    ObjectType, PropertyValue, TileCell, TiledExporter,
+};
+
+use common::{builder, convert, convert_with_settings, decode, entities, raw_assets, read_back};
+
+#[test]
+fn written_tmx_reads_back_the_same() {
+    let map = parse_map(&raw_assets().join("map.tmx")).expect("Unable to parse map");
+    assert_eq!(read_back(&map, "export"), map);
+}
+
+#[test]
+fn external_tilesets_are_referred_to_again() {
+    let map = parse_map(&raw_assets().join("tsj_map.tmx")).expect("Unable to parse map");
+    let sources: Vec<_> = map.tilesets.iter().map(|t| t.source.clone()).collect();
+    assert_eq!(sources, vec![None, Some("objects.tsj".to_string())]);
+
+    let tmx = write_tmx(&map);
+    assert!(tmx.contains("<tileset firstgid=\"4\" source=\"objects.tsj\"/>"), "{}", tmx);
+    // Written next to a copy of the tileset so it is found
+    let dir = std::env::temp_dir().join(format!("pietra_dura_tsj_{}", std::process::id()));
+    create_dir_all(&dir).expect("Unable to create output directory");
+    for file in &["objects.tsj", "ball.png", "tiles.png"] {
+        copy(raw_assets().join(file), dir.join(file)).expect("Unable to copy assets");
+    }
+    let path = dir.join("export.tmx");
+    write(&path, tmx).expect("Unable to write map");
+    let read = parse_map(&path);
+    remove_dir_all(&dir).expect("Unable to clean up output");
+    assert_eq!(read.expect("Unable to parse written map"), map);
+}
+
+#[test]
+fn sprites_map_back_to_their_gids() {
+    let map = parse_map(&raw_assets().join("map.tmx")).expect("Unable to parse map");
+    let (_, gid_map) = sprite_sheets_from_tilesets(&map, &raw_assets(), Path::new("map"));
+    let export = MapExport::new(&map);
+    for (gid, (tileset, sprite)) in gid_map {
+        assert_eq!(export.gid(tileset, sprite), Some(gid as u32));
+    }
+}
+
+#[test]
+fn export_starts_empty_and_keeps_requested_objects() {
+    let map = parse_map(&raw_assets().join("map.tmx")).expect("Unable to parse map");
+    let mut export = MapExport::new(&map);
+    assert!(export.map().layers.iter().all(|l| l.tiles.iter().flatten().all(|gid| *gid == 0)));
+    assert!(export.map().object_groups.iter().all(|g| g.objects.is_empty()));
+
+    export.keep_objects(|object| object.obj_type == "collision");
+    let kept: Vec<&Object> = export.map().object_groups.iter().flat_map(|g| g.objects.iter()).collect();
+    let original = map.object_groups.iter()
+        .flat_map(|g| g.objects.iter())
+        .filter(|o| o.obj_type == "collision")
+        .count();
+    assert_eq!(kept.len(), original);
+}
+
+#[test]
+fn tile_objects_go_back_to_their_group_whatever_their_depth() {
+    let (mut builder, tiles) = builder(4, 4);
+    builder.object_group("walls").add(Object::rect("collision", 0.0, 96.0, 128.0, 32.0));
+    builder.object_group("things").add(Object::tile("static", tiles + 1, 32.0, 64.0, 32.0, 32.0));
+    let map = builder.build();
+    let settings = ConversionSettings::default()
+        .with_depth(DepthSettings::default().with_group_base(10.0).with_spacing(5.0));
+    let prefab = decode(&convert_with_settings(map.clone(), "export_depth", &settings));
+    let transform = entities(&prefab).into_iter()
+        .find(|e| match e.detail { Detail::StaticSprite(_) => true, _ => false })
+        .and_then(|e| e.transform.clone())
+        .expect("No static sprite was converted");
+    assert_eq!(transform.translation().z, 15.0);
+
+    let mut export = MapExport::new(&map);
+    export.add_tile_object(export.group_of_type("static"), "static", None, tiles + 1, &transform);
+    let groups = &export.map().object_groups;
+    assert_eq!(groups.len(), 2);
+    assert!(groups[0].objects.is_empty());
+    let object = &groups[1].objects[0];
+    assert_eq!((object.gid, object.x, object.y), (tiles + 1, 32.0, 64.0));
+}
+
+#[test]
+fn offset_is_taken_back_off_objects() {
+    let (mut builder, tiles) = builder(4, 4);
+    builder.object_group("things").add(Object::tile("static", tiles + 1, 32.0, 64.0, 32.0, 32.0));
+    let map = builder.build();
+    let settings = ConversionSettings::default().with_offset((320.0, 160.0));
+    let prefab = decode(&convert_with_settings(map.clone(), "export_offset", &settings));
+    let transform = entities(&prefab).into_iter()
+        .find(|e| match e.detail { Detail::StaticSprite(_) => true, _ => false })
+        .and_then(|e| e.transform.clone())
+        .expect("No static sprite was converted");
+
+    let mut export = MapExport::new(&map).with_offset((320.0, 160.0));
+    export.add_tile_object(0, "static", None, tiles + 1, &transform);
+    let object = &export.map().object_groups[0].objects[0];
+    assert_eq!((object.x, object.y), (32.0, 64.0));
+}
+
+// Spawns the converted level the way the game's prefab loader would leave it, with
+// each sheet replaced by a handle which keeps its name. Textures can't be processed
+// without a renderer, so the handles are never loaded, which exporting doesn't need.
+fn spawn_level(world: &mut World, mut prefab: Prefab<LevelPrefab>) {
+    world.register::<SpriteRender>();
+    world.register::<Transform>();
+    world.register::<TileCell>();
+    world.register::<StaticSprite>();
+    world.register::<PhysicsEntity>();
+    world.register::<Named>();
+    world.register::<ObjectType>();
+    world.register::<Handle<Prefab<LevelPrefab>>>();
+    world.add_resource(AssetStorage::<Prefab<LevelPrefab>>::new());
+    world.add_resource(AssetStorage::<SpriteSheet>::new());
+    world.add_resource(AssetStorage::<Texture>::new());
+    let pool = Arc::new(rayon::ThreadPoolBuilder::new().build().expect("Unable to build thread pool"));
+    world.add_resource(Loader::new(raw_assets(), pool));
+
+    let texture = world.read_resource::<Loader>()
+        .load("tiles.png", ImageFormat::default(), (), &world.read_resource::<AssetStorage<Texture>>());
+    let mut sheets = HashMap::new();
+    for i in 0..prefab.len() {
+        let data = match prefab.entity(i).and_then(|entity| entity.data_mut()) {
+            Some(data) => data,
+            None => continue,
+        };
+        if let Some(SpriteSheetPrefab::Sheet { name: Some(name), .. }) = &data.sheet {
+            let sheet = SpriteSheet { texture: texture.clone(), sprites: Vec::new() };
+            let handle = world.write_resource::<AssetStorage<SpriteSheet>>().insert(sheet);
+            sheets.insert(name.clone(), handle.clone());
+            data.sheet = Some(SpriteSheetPrefab::Handle((Some(name.clone()), handle)));
+        }
+    }
+
+    for data in entities(&prefab) {
+        let (render, transform) = match (&data.render, &data.transform) {
+            (Some(render), Some(transform)) => (render, transform),
+            _ => continue,
+        };
+        let sprite_sheet = match &render.sheet {
+            Some(SpriteSheetReference::Name(name)) => sheets[name].clone(),
+            _ => continue,
+        };
+        let builder = world.create_entity()
+            .with(SpriteRender { sprite_sheet, sprite_number: render.sprite_number })
+            .with(transform.clone());
+        let builder = match &data.detail {
+            Detail::Tile(cell) => builder.with(*cell),
+            Detail::StaticSprite(sprite) => builder.with(*sprite),
+            Detail::Physics(_) => builder.with(PhysicsEntity { handle: BodyHandle::ground() }),
+        };
+        let builder = match &data.name {
+            Some(name) => builder.with(name.clone()),
+            None => builder,
+        };
+        let builder = match &data.object_type {
+            Some(object_type) => builder.with(object_type.clone()),
+            None => builder,
+        };
+        builder.build();
+    }
+    let handle = world.write_resource::<AssetStorage<Prefab<LevelPrefab>>>().insert(prefab);
+    world.create_entity().with(handle).build();
+}
+
+#[test]
+fn spawned_level_exports_as_the_original_map() {
+    let mut map = parse_map(&raw_assets().join("map.tmx")).expect("Unable to parse map");
+    let spawn = map.object_groups.iter_mut()
+        .flat_map(|group| group.objects.iter_mut())
+        .find(|object| object.obj_type == "static")
+        .expect("No static object in the map");
+    spawn.name = "player_spawn".to_string();
+    spawn.properties.insert("facing".to_string(), PropertyValue::String("left".to_string()));
+
+    let mut world = World::new();
+    spawn_level(&mut world, decode(&convert(map.clone(), "export_world")));
+    let exported = LevelExporter::export_world(&mut world, &map).into_map();
+
+    assert_eq!(exported.layers, map.layers);
+    for (original, exported) in map.object_groups.iter().zip(&exported.object_groups) {
+        assert_eq!(exported.objects.len(), original.objects.len(), "{}", original.name);
+        for object in &original.objects {
+            assert!(
+                exported.objects.iter().any(|o| Object { id: object.id, ..o.clone() } == *object),
+                "{:?} wasn't exported from {:?}", object, exported.objects,
+            );
+        }
+    }
+}
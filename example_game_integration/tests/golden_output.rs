@@ -0,0 +1,91 @@
+#![cfg(feature = "asset-prep")]
+
+// Converting the example map has to give exactly the checked in assets, so
+// regenerating them doesn't produce noisy diffs. If the output changes on purpose
+// regenerate them with `asset_prep --force raw_assets/map.tmx`.
+
+use std::fs::{read, read_dir, remove_dir_all};
+use std::path::{Path, PathBuf};
+
+use example_game_integration::LevelPrefab;
+use pietra_dura_tiled::{MapFile, TiledConverter};
+
+fn manifest_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+}
+
+fn convert() -> Vec<(PathBuf, Vec<u8>)> {
+    convert_map("raw_assets/map.tmx")
+}
+
+fn convert_map(path: &str) -> Vec<(PathBuf, Vec<u8>)> {
+    let map = LevelPrefab::from_map(&manifest_dir().join(path), Path::new("map"));
+    map.files().iter()
+        .map(|f| match f {
+            MapFile::Path(src, dest) => (dest.clone(), read(src).expect("Unable to read source image")),
+            MapFile::Data(dest, buffer) => (dest.clone(), buffer.clone()),
+        })
+        .collect()
+}
+
+fn written_files(dir: &Path) -> Vec<(PathBuf, Vec<u8>)> {
+    let mut files: Vec<_> = read_dir(dir).expect("Unable to read output directory")
+        .map(|e| e.expect("Unable to read directory entry").path())
+        .map(|p| (PathBuf::from(p.file_name().unwrap()), read(&p).expect("Unable to read output")))
+        .collect();
+    files.sort();
+    files
+}
+
+#[test]
+fn conversion_is_repeatable() {
+    assert_eq!(convert(), convert());
+}
+
+#[test]
+fn written_output_is_repeatable() {
+    let map = manifest_dir().join("raw_assets/map.tmx");
+    let dirs: Vec<_> = (0..2)
+        .map(|i| std::env::temp_dir().join(format!("pietra_dura_golden_{}_{}", std::process::id(), i)))
+        .collect();
+    for dir in &dirs {
+        LevelPrefab::from_map(&map, Path::new("map")).write(dir).expect("Unable to write map");
+    }
+    let first = written_files(&dirs[0]);
+    let second = written_files(&dirs[1]);
+    for dir in &dirs {
+        remove_dir_all(dir).expect("Unable to clean up output");
+    }
+    assert_eq!(first, second);
+}
+
+#[test]
+fn conversion_matches_checked_in_assets() {
+    let golden = manifest_dir().join("assets/map");
+    for (path, buffer) in convert() {
+        let expected = read(golden.join(&path))
+            .unwrap_or_else(|_| panic!("{} isn't in assets/map", path.display()));
+        if path.extension().map(|e| e == "ron").unwrap_or(false) {
+            assert_eq!(
+                String::from_utf8(buffer).expect("Output isn't utf8"),
+                String::from_utf8(expected).expect("Golden file isn't utf8"),
+                "{} differs from assets/map", path.display(),
+            );
+        } else {
+            assert!(buffer == expected, "{} differs from assets/map", path.display());
+        }
+    }
+}
+
+// json_map.tmj is map.tmx saved in Tiled's JSON format, with the objects tileset
+// saved separately as objects.tsj
+#[test]
+fn json_map_converts_the_same_as_tmx() {
+    assert_eq!(convert_map("raw_assets/json_map.tmj"), convert());
+}
+
+// tsj_map.tmx is map.tmx using objects.tsj for its objects tileset
+#[test]
+fn tmx_map_with_json_tileset_converts_the_same_as_tmx() {
+    assert_eq!(convert_map("raw_assets/tsj_map.tmx"), convert());
+}
@@ -0,0 +1,69 @@
+#![cfg(feature = "asset-prep")]
+
+use std::path::PathBuf;
+
+use pietra_dura_tiled::{ldtk_levels, parse_ldtk_level, parse_map, Map, ObjectShape, PropertyValue};
+
+// levels.ldtk has a 4x3 level with a tile layer, an IntGrid collision layer and a
+// chest entity, and a second level with a single tile
+fn project() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("raw_assets/levels.ldtk")
+}
+
+fn level(name: &str) -> Map {
+    parse_ldtk_level(&project(), Some(name)).expect("Unable to parse level")
+}
+
+#[test]
+fn levels_are_listed_in_order() {
+    assert_eq!(ldtk_levels(&project()).unwrap(), vec!["Level_0", "Level_1"]);
+}
+
+#[test]
+fn tile_layers_keep_their_tiles() {
+    let map = level("Level_0");
+    assert_eq!((map.width, map.height, map.tile_width, map.tile_height), (4, 3, 32, 32));
+    let layers: Vec<_> = map.layers.iter().map(|l| l.name.as_str()).collect();
+    assert_eq!(layers, vec!["Ground"]);
+    assert_eq!(map.layers[0].tiles, vec![vec![1, 2, 0, 0], vec![0, 0, 0, 0], vec![3, 0, 0, 0]]);
+
+    let map = level("Level_1");
+    assert_eq!(map.layers[0].tiles[2], vec![0, 0, 0, 1]);
+    assert!(map.object_groups.is_empty());
+}
+
+#[test]
+fn int_grid_values_become_collision_objects() {
+    let map = level("Level_0");
+    let groups: Vec<_> = map.object_groups.iter().map(|g| g.name.as_str()).collect();
+    assert_eq!(groups, vec!["Collision", "Entities"]);
+    let objects: Vec<_> = map.object_groups[0].objects.iter()
+        .map(|o| (o.obj_type.as_str(), o.x, o.y, o.shape.clone(), o.properties.get("value").cloned()))
+        .collect();
+    assert_eq!(objects, vec![
+        ("collision", 0.0, 0.0, ObjectShape::Rect { width: 128.0, height: 32.0 }, Some(PropertyValue::Int(1))),
+        ("collision", 96.0, 32.0, ObjectShape::Rect { width: 32.0, height: 32.0 }, Some(PropertyValue::Int(1))),
+    ]);
+}
+
+#[test]
+fn entities_keep_their_fields() {
+    let map = level("Level_0");
+    let chest = &map.object_groups[1].objects[0];
+    assert_eq!((chest.name.as_str(), chest.obj_type.as_str()), ("Chest", "Chest"));
+    // Placed by its top left corner rather than its bottom center pivot
+    assert_eq!((chest.x, chest.y), (32.0, 48.0));
+    let properties: Vec<_> = chest.properties.iter().map(|(k, v)| (k.as_str(), v.clone())).collect();
+    // Array fields have no Tiled property type so are left out
+    assert_eq!(properties, vec![
+        ("gold", PropertyValue::Int(5)),
+        ("iid", PropertyValue::String("chest-1".to_string())),
+        ("label", PropertyValue::String("loot".to_string())),
+    ]);
+}
+
+#[test]
+fn projects_need_a_level_name() {
+    let error = parse_map(&project()).unwrap_err();
+    assert!(error.contains("Level_0") && error.contains("Level_1"), "{}", error);
+}
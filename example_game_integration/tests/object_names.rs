@@ -0,0 +1,32 @@
+#![cfg(feature = "asset-prep")]
+
+// Objects keep their names and types through conversion so they can be looked up
+// in the `ObjectIndex` once the level is loaded.
+
+mod common;
+
+use pietra_dura_tiled::Object;
+
+use common::{builder, convert, decode, entities};
+
+#[test]
+fn object_names_and_types_are_kept() {
+    let (mut builder, tiles) = builder(4, 4);
+    builder.object_group("objects")
+        .add(Object::tile("static", tiles, 0.0, 32.0, 32.0, 32.0).named("player_spawn"))
+        .add(Object::tile("static", tiles, 32.0, 32.0, 32.0, 32.0))
+        .add(Object::rect("collision", 0.0, 96.0, 128.0, 32.0).named("floor"));
+    let prefab = decode(&convert(builder.build(), "names"));
+    let objects: Vec<_> = entities(&prefab).iter()
+        .map(|e| (
+            e.name.as_ref().map(|n| n.name.to_string()),
+            e.object_type.as_ref().map(|t| t.0.as_str()),
+        ))
+        .collect();
+    // Unnamed objects don't get a name
+    assert_eq!(objects, vec![
+        (Some("player_spawn".to_string()), Some("static")),
+        (None, Some("static")),
+        (Some("floor".to_string()), Some("collision")),
+    ]);
+}
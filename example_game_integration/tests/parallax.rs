@@ -0,0 +1,90 @@
+#![cfg(feature = "asset-prep")]
+
+// Parallax factors have to make it from Tiled to the tiles and objects of their
+// layers, whether they were set as attributes or, in older maps, as properties.
+
+mod common;
+
+use std::fs::{remove_file, write};
+
+use pietra_dura_tiled::{parse_map, Map, MapBuilder, Object, PropertyValue};
+
+use common::{builder, convert, decode, entities, read_back};
+
+fn parallax_map() -> Map {
+    let (mut builder, tiles) = builder(4, 3);
+    builder.layer("background").fill(tiles).parallax(0.5, 0.25);
+    builder.layer("ground").fill_rect(0, 2, 4, 1, tiles);
+    builder.object_group("objects")
+        .parallax(0.5, 1.0)
+        .add(Object::tile("static", tiles + 1, 64.0, 64.0, 32.0, 32.0));
+    builder.build()
+}
+
+#[test]
+fn parallax_is_read_from_tmx() {
+    let map = read_back(&parallax_map(), "parallax");
+    assert_eq!(map.layers[0].parallax, (0.5, 0.25));
+    assert_eq!(map.layers[1].parallax, (1.0, 1.0));
+    assert_eq!(map.object_groups[0].parallax, (0.5, 1.0));
+}
+
+#[test]
+fn parallax_properties_take_precedence() {
+    let mut builder = MapBuilder::new(1, 1, 32, 32);
+    builder.layer("background").property("parallaxx", 0.5f32);
+    let map = read_back(&builder.build(), "parallax_properties");
+    assert_eq!(map.layers[0].parallax, (0.5, 1.0));
+}
+
+#[test]
+fn layers_in_groups_are_read_by_name() {
+    let tmx = r#"<?xml version="1.0" encoding="UTF-8"?>
+<map version="1.5" orientation="orthogonal" renderorder="right-down" width="1" height="1" tilewidth="32" tileheight="32">
+ <group name="far">
+  <layer name="sky" width="1" height="1" parallaxx="0.5">
+   <data encoding="csv">0</data>
+  </layer>
+  <objectgroup name="clouds" parallaxy="0.5">
+   <properties>
+    <property name="notes">drifting
+slowly</property>
+   </properties>
+   <object id="1" x="0" y="0" width="32" height="32">
+    <properties>
+     <property name="speed" type="int" value="2"/>
+    </properties>
+   </object>
+  </objectgroup>
+ </group>
+ <layer name="sky" width="1" height="1" parallaxy="0.75">
+  <data encoding="csv">0</data>
+ </layer>
+</map>
+"#;
+    let path = std::env::temp_dir().join(format!("pietra_dura_groups_{}.tmx", std::process::id()));
+    write(&path, tmx).expect("Unable to write map");
+    let map = parse_map(&path);
+    remove_file(&path).expect("Unable to clean up output");
+    let map = map.expect("Unable to parse map");
+
+    let layers: Vec<_> = map.layers.iter().map(|l| (l.name.as_str(), l.parallax)).collect();
+    assert_eq!(layers, vec![("sky", (0.5, 1.0)), ("sky", (1.0, 0.75))]);
+    let group = &map.object_groups[0];
+    assert_eq!(group.parallax, (1.0, 0.5));
+    assert_eq!(group.properties.len(), 1);
+    assert_eq!(group.properties["notes"], PropertyValue::String("drifting\nslowly".to_string()));
+}
+
+#[test]
+fn parallax_goes_on_its_layers_entities() {
+    let prefab = decode(&convert(parallax_map(), "parallax"));
+    let factors: Vec<_> = entities(&prefab).iter()
+        .map(|e| e.parallax.as_ref().map(|p| (p.x, p.y)))
+        .collect();
+    // The 12 background tiles, then the 4 ground tiles without any, then the static sprite
+    let mut expected = vec![Some((0.5, 0.25)); 12];
+    expected.extend(vec![None; 4]);
+    expected.push(Some((0.5, 1.0)));
+    assert_eq!(factors, expected);
+}
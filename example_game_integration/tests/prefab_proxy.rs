@@ -0,0 +1,106 @@
+#![cfg(feature = "asset-prep")]
+
+use std::path::PathBuf;
+
+use amethyst::{
+    assets::Prefab,
+    core::{Named, Transform},
+};
+use example_game_integration::{Detail, LevelPrefab, LevelPrefabProxy};
+use pietra_dura_tiled::{
+    BincodeFormat, ConversionSettings, MapFile, OutputFormat, ObjectType, Parallax, Prefab as ProxyPrefab, PrefabEntity,
+    TileCell, TiledConverter,
+};
+
+fn round_trip(proxy: &ProxyPrefab<LevelPrefabProxy>) -> Prefab<LevelPrefab> {
+    let buffer = ron::ser::to_string_pretty(proxy, ron::ser::PrettyConfig::default())
+        .expect("Failed to encode proxy");
+    ron::de::from_str(&buffer)
+        .unwrap_or_else(|e| panic!("Proxy output didn't deserialize as LevelPrefab: {}\n{}", e, buffer))
+}
+
+#[test]
+fn bare_proxy_deserializes_as_prefab() {
+    let proxy = ProxyPrefab { entities: vec![tile_proxy(None)] };
+    let prefab = round_trip(&proxy);
+    assert_eq!(prefab.len(), 1);
+}
+
+fn tile_proxy(parallax: Option<Parallax>) -> PrefabEntity<LevelPrefabProxy> {
+    PrefabEntity {
+        parent: None,
+        data: Some(LevelPrefabProxy {
+            sheet: None,
+            render: None,
+            transform: Some(Transform::default()),
+            parallax,
+            y_sort: None,
+            name: None,
+            object_type: None,
+            collider_cells: None,
+            detail: Detail::Tile(TileCell::new(0, 1, 2)),
+        }),
+    }
+}
+
+// Bincode can't leave out fields, so the optional ones are only skipped in RON and JSON
+#[test]
+fn optional_fields_are_only_skipped_when_human_readable() {
+    let mut sorted = tile_proxy(Some(Parallax::new(0.5, 1.0)));
+    sorted.data.as_mut().unwrap().y_sort = Some(
+        ron::de::from_str("(base: 0, spacing: 1, top: 0, height: 96, anchor: 0)").expect("Invalid YSort"),
+    );
+    sorted.data.as_mut().unwrap().name = Some(Named::new("player"));
+    sorted.data.as_mut().unwrap().object_type = Some(ObjectType("dynamic".to_string()));
+    let proxy = ProxyPrefab { entities: vec![tile_proxy(None), sorted] };
+    let ron = String::from_utf8(OutputFormat::PrettyRon.encode(&proxy)).expect("RON isn't utf8");
+    assert_eq!(ron.matches("parallax:").count(), 1);
+    assert_eq!(ron.matches("y_sort:").count(), 1);
+    assert_eq!(ron.matches("name:").count(), 1);
+    assert_eq!(ron.matches("object_type:").count(), 1);
+    for format in &[OutputFormat::PrettyRon, OutputFormat::Json, OutputFormat::Bincode] {
+        let data = format.encode(&proxy);
+        let prefab: Prefab<LevelPrefab> = match format {
+            OutputFormat::Bincode => amethyst::assets::Format::import_simple(&BincodeFormat, data)
+                .unwrap_or_else(|e| panic!("Bincode proxy didn't load: {}", e)),
+            _ => format.decode(&data)
+                .unwrap_or_else(|e| panic!("{:?} proxy didn't deserialize as LevelPrefab: {}", format, e)),
+        };
+        assert_eq!(prefab.len(), 2);
+    }
+}
+
+#[test]
+fn converted_map_deserializes_as_prefab() {
+    let map_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("raw_assets/map.tmx");
+    let map = LevelPrefab::from_map(&map_path, &PathBuf::from("map"));
+    let buffer = map.files().iter()
+        .filter_map(|f| match f {
+            MapFile::Data(path, buffer) if path == &PathBuf::from("map.ron") => Some(buffer),
+            _ => None,
+        })
+        .next()
+        .expect("No map.ron produced");
+    let buffer = String::from_utf8(buffer.clone()).expect("map.ron isn't utf8");
+    let prefab: Prefab<LevelPrefab> = ron::de::from_str(&buffer)
+        .unwrap_or_else(|e| panic!("Converted map didn't deserialize as LevelPrefab: {}", e));
+    // The level root, 100 tiles, 2 sprite objects and 4 colliders
+    assert_eq!(prefab.len(), 107);
+}
+
+#[test]
+fn every_output_format_loads_as_prefab() {
+    let map_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("raw_assets/map.tmx");
+    for format in &[OutputFormat::PrettyRon, OutputFormat::CompactRon, OutputFormat::Json, OutputFormat::Bincode] {
+        let settings = ConversionSettings::default().with_output_format(*format);
+        let map = LevelPrefab::from_map_with_settings(&map_path, &PathBuf::from("map"), &settings);
+        let data = map.prefab_data().expect("No prefab produced").to_vec();
+        let prefab: Prefab<LevelPrefab> = match format {
+            OutputFormat::Bincode => amethyst::assets::Format::import_simple(&BincodeFormat, data)
+                .unwrap_or_else(|e| panic!("Bincode map didn't load: {}", e)),
+            _ => format.decode(&data)
+                .unwrap_or_else(|e| panic!("{:?} map didn't deserialize as LevelPrefab: {}", format, e)),
+        };
+        assert_eq!(prefab.len(), 107);
+    }
+}
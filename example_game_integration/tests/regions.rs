@@ -0,0 +1,41 @@
+#![cfg(feature = "asset-prep")]
+
+use std::path::{Path, PathBuf};
+
+use example_game_integration::{LevelPrefab, LevelPrefabProxy};
+use pietra_dura_tiled::{ConversionSettings, MapFile, MapPrefab, RegionManifest, TiledConverter};
+
+fn convert(settings: &ConversionSettings) -> MapPrefab<LevelPrefab, LevelPrefabProxy> {
+    let map = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("raw_assets/map.tmx");
+    LevelPrefab::from_map_with_settings(&map, Path::new("map"), settings)
+}
+
+fn file_data<'a>(map: &'a MapPrefab<LevelPrefab, LevelPrefabProxy>, path: &Path) -> Option<&'a [u8]> {
+    map.files().iter().find_map(|f| match f {
+        MapFile::Data(dest, data) if dest == path => Some(data.as_slice()),
+        _ => None,
+    })
+}
+
+#[test]
+fn regions_are_listed_in_the_manifest() {
+    let map = convert(&ConversionSettings::default().with_regions(5, 5));
+    let manifest: RegionManifest = ron::de::from_bytes(
+        file_data(&map, Path::new("regions.ron")).expect("No region manifest"),
+    ).expect("Unable to parse region manifest");
+    assert!(!manifest.regions.is_empty());
+    for region in &manifest.regions {
+        let dest = format!("regions/{}_{}.ron", region.x, region.y);
+        assert_eq!(region.prefab, format!("map/{}", dest));
+        assert!(file_data(&map, Path::new(&dest)).is_some());
+    }
+}
+
+#[test]
+fn regions_hold_every_entity() {
+    let whole = convert(&ConversionSettings::default());
+    let split = convert(&ConversionSettings::default().with_regions(5, 5));
+    assert_eq!(split.stats().entities, whole.stats().entities);
+    // Tiles and objects go into regions, leaving the map's own prefab with little but the root
+    assert!(split.prefab_data().unwrap().len() < whole.prefab_data().unwrap().len());
+}
@@ -0,0 +1,67 @@
+#![cfg(feature = "asset-prep")]
+
+mod common;
+
+use std::path::{Path, PathBuf};
+
+use example_game_integration::{Detail, LevelPrefab, LevelPrefabProxy};
+use pietra_dura_tiled::{ConversionSettings, MapFile, TiledConverter, WorldPrefab};
+
+use common::{decode, entities};
+
+// example.world has map.tmx with json_map.tmj, the same map, to the right of it
+fn convert_world() -> WorldPrefab<LevelPrefab, LevelPrefabProxy> {
+    let world = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("raw_assets/example.world");
+    LevelPrefab::from_world(&world, Path::new("example"), &ConversionSettings::default())
+}
+
+#[test]
+fn world_maps_are_placed_in_the_manifest() {
+    let world = convert_world();
+    let maps: Vec<_> = world.manifest().maps.iter()
+        .map(|m| (m.name.as_str(), m.prefab.as_str(), m.x, m.y))
+        .collect();
+    assert_eq!(maps, vec![
+        ("map", "example/map/map.ron", 0.0, 0.0),
+        ("json_map", "example/json_map/map.ron", 320.0, 0.0),
+    ]);
+}
+
+#[test]
+fn world_maps_share_textures() {
+    let world = convert_world();
+    // One texture for each of the tilesets, however many maps use them
+    assert_eq!(world.shared_files().count(), 2);
+    for (_, map) in world.maps() {
+        assert!(map.files().iter().all(|f| match f {
+            MapFile::Path(_, dest) | MapFile::Data(dest, _) => dest.extension().map(|e| e != "png").unwrap_or(true),
+        }));
+    }
+}
+
+#[test]
+fn world_maps_are_offset() {
+    let world = convert_world();
+    let prefabs: Vec<_> = world.maps().map(|(_, map)| decode(map)).collect();
+    let (left, right) = (entities(&prefabs[0]), entities(&prefabs[1]));
+    assert_eq!(left.len(), right.len());
+
+    // Tiles and sprites are placed by their transforms, colliders by their bodies
+    let position = |entity: &LevelPrefab| match (&entity.transform, &entity.detail) {
+        (Some(transform), _) => Some((transform.translation().x, transform.translation().y)),
+        (None, Detail::Physics(physics)) => physics.location,
+        _ => None,
+    };
+    let mut tiles = 0;
+    let mut objects = 0;
+    for (left, right) in left.iter().zip(&right) {
+        let (x, y) = position(left).expect("Entity has no position");
+        assert_eq!(position(right), Some((x + 320.0, y)));
+        match left.detail {
+            Detail::Tile(_) => tiles += 1,
+            _ => objects += 1,
+        }
+    }
+    assert_eq!(tiles, 100);
+    assert!(objects > 0);
+}
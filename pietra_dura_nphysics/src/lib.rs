@@ -9,6 +9,7 @@ use amethyst::{
 };
 use serde::{Deserialize, Serialize};
 use specs_derive::Component;
+use std::collections::HashMap;
 
 use nalgebra::Vector2;
 use ncollide2d::{
@@ -17,7 +18,7 @@ use ncollide2d::{
 };
 use nphysics2d::{
     material::{BasicMaterial, MaterialHandle},
-    object::{BodyHandle, ColliderDesc, RigidBodyDesc},
+    object::{BodyHandle, ColliderDesc, ColliderHandle, RigidBodyDesc},
     world::World as PhysicsWorld,
 };
 
@@ -120,6 +121,7 @@ impl<'s, CollisionTypeEnum> PrefabData<'s> for PhysicsEntityPrefab<CollisionType
         WriteExpect<'s, PhysicsWorld<f32>>,
         ReadStorage<'s, InitialPosition>,
         WriteStorage<'s, NoRotate>,
+        Write<'s, PhysicsHandles>,
         );
     type Result = ();
 
@@ -134,6 +136,9 @@ impl<'s, CollisionTypeEnum> PrefabData<'s> for PhysicsEntityPrefab<CollisionType
         let physics_world = &mut data.1;
         let starting_locations = &mut data.2;
         let no_rotates = &mut data.3;
+        let handles = &mut data.4;
+        // Applying the prefab again regenerates the entity's physics rather than doubling it
+        handles.remove(entity, physics_world);
         let mut collider_descs = Vec::with_capacity(self.colliders.len());
         for collider in &self.colliders {
             let shape = match &collider.shape {
@@ -190,7 +195,8 @@ impl<'s, CollisionTypeEnum> PrefabData<'s> for PhysicsEntityPrefab<CollisionType
             }
             collider_desc.set_user_data(Some(Box::new(entity)));
             if self.collider_only {
-                collider_desc.build(physics_world);
+                let handle = collider_desc.build(physics_world).handle();
+                handles.colliders.entry(entity).or_insert_with(Vec::new).push(handle);
             } else {
                 collider_descs.push(collider_desc);
             }
@@ -210,6 +216,7 @@ impl<'s, CollisionTypeEnum> PrefabData<'s> for PhysicsEntityPrefab<CollisionType
                 .gravity_enabled(self.gravity_enabled)
                 .set_translation(Vector2::new(x*PHYSICS_SCALE, y*PHYSICS_SCALE))
                 .build(physics_world);
+            handles.bodies.insert(entity, body.handle());
             physics_entities
                 .insert(
                     entity,
@@ -240,6 +247,45 @@ pub struct PhysicsEntity {
     pub handle: BodyHandle,
 }
 
+/// The bodies and colliders which were created for each entity, so they can be
+/// removed from the physics world when the entity goes away.
+#[derive(Default)]
+pub struct PhysicsHandles {
+    bodies: HashMap<Entity, BodyHandle>,
+    colliders: HashMap<Entity, Vec<ColliderHandle>>,
+}
+
+impl PhysicsHandles {
+    pub fn remove(&mut self, entity: Entity, physics_world: &mut PhysicsWorld<f32>) {
+        if let Some(body) = self.bodies.remove(&entity) {
+            physics_world.remove_bodies(&[body]);
+        }
+        if let Some(colliders) = self.colliders.remove(&entity) {
+            physics_world.remove_colliders(&colliders);
+        }
+    }
+}
+
+struct PhysicalCleanupSystem;
+impl<'s> System<'s> for PhysicalCleanupSystem {
+    type SystemData = (
+        Entities<'s>,
+        Write<'s, PhysicsHandles>,
+        WriteExpect<'s, PhysicsWorld<f32>>,
+    );
+
+    fn run(&mut self, (entities, mut handles, mut physics_world): Self::SystemData) {
+        let dead: Vec<Entity> = handles.bodies.keys()
+            .chain(handles.colliders.keys())
+            .filter(|e| !entities.is_alive(**e))
+            .cloned()
+            .collect();
+        for entity in dead {
+            handles.remove(entity, &mut physics_world);
+        }
+    }
+}
+
 struct PhysicalSimulationSystem;
 impl<'s> System<'s> for PhysicalSimulationSystem {
     type SystemData = WriteExpect<'s, PhysicsWorld<f32>>;
@@ -290,6 +336,7 @@ impl PhysicsBundle {
 
 impl<'a, 'b> SystemBundle<'a, 'b> for PhysicsBundle {
     fn build(self, builder: &mut DispatcherBuilder<'a, 'b>) -> Result<(), Error> {
+        builder.add(PhysicalCleanupSystem, "pietra_dura_physical_cleanup_system", &[]);
         builder.add(PhysicalPlacementSystem, "pietra_dura_physical_placement_system", &[]);
         builder.add(PhysicalSimulationSystem, "pietra_dura_physical_simulation_system", &[]);
         Ok(())
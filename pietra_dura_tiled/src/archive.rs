@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::fs::{metadata, File};
+use std::io::{self, prelude::*, Result};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use amethyst::{
+    assets::Source,
+    error::Error,
+};
+use zip::{write::FileOptions, DateTime, ZipArchive, ZipWriter};
+
+use crate::{path_key, MapFile};
+
+fn zip_error(e: zip::result::ZipError) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+/// Writes map files into a zip archive, each under `map_prefix` so they have the
+/// same asset paths they would have in the assets directory. Entries are given a
+/// fixed modification time so converting the same map gives the same archive.
+pub(crate) fn write_archive(files: &[MapFile], map_prefix: &Path, path: &Path) -> Result<()> {
+    let options = FileOptions::default().last_modified_time(DateTime::default());
+    let mut zip = ZipWriter::new(File::create(path)?);
+    for file in files {
+        let (dest, buffer) = match file {
+            MapFile::Path(src, dest) => (dest, std::fs::read(src)?),
+            MapFile::Data(dest, buffer) => (dest, buffer.clone()),
+        };
+        zip.start_file(path_key(&map_prefix.join(dest)), options)
+            .map_err(zip_error)?;
+        zip.write_all(&buffer)?;
+    }
+    zip.finish().map_err(zip_error)?;
+    Ok(())
+}
+
+struct MountedArchive {
+    path: PathBuf,
+    modified: u64,
+    entries: HashMap<String, Vec<u8>>,
+}
+
+/// An asset source which reads from level archives written by
+/// `MapPrefab::write_archive`, falling back to another source for anything which
+/// isn't in them.
+///
+/// Amethyst loads the textures referenced by a prefab from the loader's default
+/// source rather than the one the prefab came from, so for archived levels to find
+/// their sprite sheets this has to replace the default source, wrapping the assets
+/// directory:
+///
+/// ```ignore
+/// let source = ArchiveSource::new(Directory::new(assets_dir)).mount("mods/level_1.zip")?;
+/// loader.add_source("", source);
+/// ```
+pub struct ArchiveSource {
+    archives: Vec<MountedArchive>,
+    fallback: Box<dyn Source>,
+}
+
+impl ArchiveSource {
+    pub fn new<S: Source>(fallback: S) -> Self {
+        ArchiveSource {
+            archives: Vec::new(),
+            fallback: Box::new(fallback),
+        }
+    }
+
+    /// Adds an archive. Archives mounted later take precedence over earlier ones.
+    pub fn mount<P: Into<PathBuf>>(mut self, path: P) -> Result<Self> {
+        let path = path.into();
+        let modified = metadata(&path)?.modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let mut archive = ZipArchive::new(File::open(&path)?).map_err(zip_error)?;
+        let mut entries = HashMap::new();
+        for i in 0..archive.len() {
+            let mut entry = archive.by_index(i).map_err(zip_error)?;
+            let mut buffer = Vec::with_capacity(entry.size() as usize);
+            entry.read_to_end(&mut buffer)?;
+            entries.insert(entry.name().to_string(), buffer);
+        }
+        self.archives.push(MountedArchive { path, modified, entries });
+        Ok(self)
+    }
+
+    pub fn archives(&self) -> impl Iterator<Item = &Path> {
+        self.archives.iter().map(|a| a.path.as_path())
+    }
+
+    fn find(&self, path: &str) -> Option<&MountedArchive> {
+        self.archives.iter().rev().find(|a| a.entries.contains_key(path))
+    }
+}
+
+impl Source for ArchiveSource {
+    fn modified(&self, path: &str) -> std::result::Result<u64, Error> {
+        match self.find(path) {
+            Some(archive) => Ok(archive.modified),
+            None => self.fallback.modified(path),
+        }
+    }
+
+    fn load(&self, path: &str) -> std::result::Result<Vec<u8>, Error> {
+        match self.find(path) {
+            Some(archive) => Ok(archive.entries[path].clone()),
+            None => self.fallback.load(path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, read, remove_dir_all, write};
+
+    use amethyst::assets::Directory;
+
+    use super::*;
+
+    fn files() -> Vec<MapFile> {
+        vec![
+            MapFile::Data(PathBuf::from("map.ron"), b"(entities: [])".to_vec()),
+            MapFile::Data(PathBuf::from("regions/0_0.ron"), b"(entities: [()])".to_vec()),
+        ]
+    }
+
+    #[test]
+    fn archived_files_load_by_asset_path() {
+        let dir = std::env::temp_dir().join(format!("pietra_dura_archive_{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        write(dir.join("outside.ron"), b"()").unwrap();
+        let (first, second) = (dir.join("first.zip"), dir.join("second.zip"));
+        write_archive(&files(), Path::new("levels/one"), &first).unwrap();
+        write_archive(&files(), Path::new("levels/one"), &second).unwrap();
+        let same = read(&first).unwrap() == read(&second).unwrap();
+
+        let source = ArchiveSource::new(Directory::new(&dir)).mount(&first).unwrap();
+        let map = source.load("levels/one/map.ron");
+        let region = source.load("levels/one/regions/0_0.ron");
+        let outside = source.load("outside.ron");
+        remove_dir_all(&dir).unwrap();
+
+        assert!(same, "archives of the same files differ");
+        assert_eq!(map.unwrap(), b"(entities: [])".to_vec());
+        assert_eq!(region.unwrap(), b"(entities: [()])".to_vec());
+        // Anything else comes from the fallback
+        assert_eq!(outside.unwrap(), b"()".to_vec());
+    }
+}
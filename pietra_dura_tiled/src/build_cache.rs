@@ -0,0 +1,76 @@
+use std::collections::BTreeMap;
+use std::fs::{read, File};
+use std::io::prelude::*;
+use std::io::Result;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+pub const MANIFEST_FILE: &str = "manifest.ron";
+
+// FNV-1a, which unlike the std hashers is guaranteed to be stable between builds
+pub fn content_hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+pub fn file_hash(path: &Path) -> Option<u64> {
+    read(path).ok().map(|bytes| content_hash(&bytes))
+}
+
+pub(crate) fn path_key(path: &Path) -> String {
+    path.to_string_lossy().replace('\\', "/")
+}
+
+/// Written next to a converted map to record which inputs it was produced from and
+/// what was produced, so unchanged maps can be skipped.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BuildManifest {
+    // `TiledConverter::conversion_key`, naming the converter and its settings
+    #[serde(default)]
+    pub conversion: String,
+    pub inputs: BTreeMap<String, u64>,
+    pub outputs: BTreeMap<String, u64>,
+    // Converted entities by kind, so later conversions can report what changed
+    #[serde(default)]
+    pub entity_kinds: BTreeMap<String, usize>,
+}
+
+impl BuildManifest {
+    // Inputs which can't be read are left out
+    pub fn hash_inputs(inputs: &[PathBuf]) -> BTreeMap<String, u64> {
+        inputs.iter()
+            .filter_map(|p| file_hash(p).map(|hash| (path_key(p), hash)))
+            .collect()
+    }
+
+    pub fn load(dir: &Path) -> Option<Self> {
+        let f = File::open(dir.join(MANIFEST_FILE)).ok()?;
+        ron::de::from_reader(f).ok()
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        let buffer = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())
+            .expect("Failed to encode build manifest");
+        let mut f = File::create(dir.join(MANIFEST_FILE))?;
+        f.write_all(buffer.as_bytes())
+    }
+
+    /// True if `dir` holds the output of converting exactly these inputs with the
+    /// same `conversion` key and none of that output has been changed since. Missing
+    /// inputs are never current, so the map is converted again to report them.
+    pub fn is_current(dir: &Path, inputs: &[PathBuf], conversion: &str) -> bool {
+        let manifest = match Self::load(dir) {
+            Some(manifest) => manifest,
+            None => return false,
+        };
+        inputs.iter().all(|p| p.is_file())
+            && manifest.conversion == conversion
+            && manifest.inputs == Self::hash_inputs(inputs)
+            && manifest.outputs.iter().all(|(path, hash)| file_hash(&dir.join(path)) == Some(*hash))
+    }
+}
@@ -0,0 +1,306 @@
+// Building maps in code, for procedurally generated levels and for testing converters
+// without fixture files. Convert the result with `TiledConverter::from_map_data`.
+
+use crate::{Image, Layer, Map, Object, ObjectGroup, ObjectShape, Properties, PropertyValue, Tile, Tileset};
+
+/// Assembles a `Map` in memory.
+///
+/// ```ignore
+/// let mut builder = MapBuilder::new(20, 15, 32, 32);
+/// let floor = builder.image_tileset("floor", "floor.png", 32, 32, 128, 64);
+/// builder.layer("ground").fill(floor);
+/// builder.object_group("things").add(Object::rect("Wall", 0.0, 0.0, 640.0, 32.0));
+/// let map = builder.build();
+/// ```
+#[derive(Debug, Clone)]
+pub struct MapBuilder {
+    map: Map,
+    next_gid: u32,
+    next_object_id: u32,
+}
+
+impl MapBuilder {
+    /// A map `width` by `height` tiles with no tilesets, layers or objects
+    pub fn new(width: u32, height: u32, tile_width: u32, tile_height: u32) -> Self {
+        MapBuilder {
+            map: Map {
+                width,
+                height,
+                tile_width,
+                tile_height,
+                tilesets: Vec::new(),
+                layers: Vec::new(),
+                object_groups: Vec::new(),
+                properties: Properties::new(),
+            },
+            next_gid: 1,
+            next_object_id: 1,
+        }
+    }
+
+    /// Adds a tileset cut from a single image and returns the gid of its first tile.
+    /// Tiles are numbered left to right then top to bottom from that gid.
+    pub fn image_tileset(&mut self, name: &str, source: &str, tile_width: u32, tile_height: u32, image_width: u32, image_height: u32) -> u32 {
+        let first_gid = self.next_gid;
+        self.next_gid += (image_width / tile_width.max(1)) * (image_height / tile_height.max(1));
+        self.map.tilesets.push(Tileset {
+            first_gid,
+            name: name.to_string(),
+            tile_width,
+            tile_height,
+            spacing: 0,
+            margin: 0,
+            images: vec![Image { source: source.to_string(), width: image_width as i32, height: image_height as i32 }],
+            tiles: Vec::new(),
+            source: None,
+        });
+        first_gid
+    }
+
+    /// Adds a tileset with an image per tile and returns the gid of its first tile.
+    /// The tiles' gids follow on from it in the order the images are given.
+    pub fn collection_tileset(&mut self, name: &str, images: Vec<Image>) -> u32 {
+        let first_gid = self.next_gid;
+        self.next_gid += images.len() as u32;
+        let tile_width = images.iter().map(|i| i.width as u32).max().unwrap_or(0);
+        let tile_height = images.iter().map(|i| i.height as u32).max().unwrap_or(0);
+        self.map.tilesets.push(Tileset {
+            first_gid,
+            name: name.to_string(),
+            tile_width,
+            tile_height,
+            spacing: 0,
+            margin: 0,
+            images: Vec::new(),
+            tiles: images.into_iter()
+                .enumerate()
+                .map(|(id, image)| Tile { id: id as u32, images: vec![image], properties: Properties::new() })
+                .collect(),
+            source: None,
+        });
+        first_gid
+    }
+
+    /// Adds an empty tile layer above the existing ones
+    pub fn layer(&mut self, name: &str) -> LayerBuilder<'_> {
+        self.map.layers.push(Layer {
+            name: name.to_string(),
+            opacity: 1.0,
+            visible: true,
+            parallax: (1.0, 1.0),
+            tiles: vec![vec![0; self.map.width as usize]; self.map.height as usize],
+            properties: Properties::new(),
+        });
+        LayerBuilder { layer: self.map.layers.last_mut().unwrap() }
+    }
+
+    /// Adds an empty object group
+    pub fn object_group(&mut self, name: &str) -> ObjectGroupBuilder<'_> {
+        self.map.object_groups.push(ObjectGroup {
+            name: name.to_string(),
+            opacity: 1.0,
+            visible: true,
+            parallax: (1.0, 1.0),
+            objects: Vec::new(),
+            properties: Properties::new(),
+        });
+        ObjectGroupBuilder {
+            group: self.map.object_groups.last_mut().unwrap(),
+            next_object_id: &mut self.next_object_id,
+        }
+    }
+
+    pub fn property<V: Into<PropertyValue>>(&mut self, name: &str, value: V) -> &mut Self {
+        self.map.properties.insert(name.to_string(), value.into());
+        self
+    }
+
+    pub fn build(self) -> Map {
+        self.map
+    }
+}
+
+pub struct LayerBuilder<'a> {
+    layer: &'a mut Layer,
+}
+
+impl<'a> LayerBuilder<'a> {
+    /// Sets the tile at column `x`, row `y`. Cells outside the map are ignored.
+    pub fn set(&mut self, x: usize, y: usize, gid: u32) -> &mut Self {
+        if let Some(cell) = self.layer.tiles.get_mut(y).and_then(|row| row.get_mut(x)) {
+            *cell = gid;
+        }
+        self
+    }
+
+    pub fn fill(&mut self, gid: u32) -> &mut Self {
+        for row in &mut self.layer.tiles {
+            for cell in row.iter_mut() {
+                *cell = gid;
+            }
+        }
+        self
+    }
+
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, gid: u32) -> &mut Self {
+        for row in y..y + height {
+            for column in x..x + width {
+                self.set(column, row, gid);
+            }
+        }
+        self
+    }
+
+    pub fn opacity(&mut self, opacity: f32) -> &mut Self {
+        self.layer.opacity = opacity;
+        self
+    }
+
+    pub fn visible(&mut self, visible: bool) -> &mut Self {
+        self.layer.visible = visible;
+        self
+    }
+
+    pub fn parallax(&mut self, x: f32, y: f32) -> &mut Self {
+        self.layer.parallax = (x, y);
+        self
+    }
+
+    pub fn property<V: Into<PropertyValue>>(&mut self, name: &str, value: V) -> &mut Self {
+        self.layer.properties.insert(name.to_string(), value.into());
+        self
+    }
+}
+
+pub struct ObjectGroupBuilder<'a> {
+    group: &'a mut ObjectGroup,
+    next_object_id: &'a mut u32,
+}
+
+impl<'a> ObjectGroupBuilder<'a> {
+    /// Adds an object, giving it the next free id
+    pub fn add(&mut self, mut object: Object) -> &mut Self {
+        object.id = *self.next_object_id;
+        *self.next_object_id += 1;
+        self.group.objects.push(object);
+        self
+    }
+
+    pub fn opacity(&mut self, opacity: f32) -> &mut Self {
+        self.group.opacity = opacity;
+        self
+    }
+
+    pub fn visible(&mut self, visible: bool) -> &mut Self {
+        self.group.visible = visible;
+        self
+    }
+
+    pub fn parallax(&mut self, x: f32, y: f32) -> &mut Self {
+        self.group.parallax = (x, y);
+        self
+    }
+
+    pub fn property<V: Into<PropertyValue>>(&mut self, name: &str, value: V) -> &mut Self {
+        self.group.properties.insert(name.to_string(), value.into());
+        self
+    }
+}
+
+impl Object {
+    fn with_shape(obj_type: &str, x: f32, y: f32, width: f32, height: f32, shape: ObjectShape) -> Self {
+        Object {
+            id: 0,
+            gid: 0,
+            name: String::new(),
+            obj_type: obj_type.to_string(),
+            x,
+            y,
+            width,
+            height,
+            rotation: 0.0,
+            visible: true,
+            shape,
+            properties: Properties::new(),
+        }
+    }
+
+    pub fn rect(obj_type: &str, x: f32, y: f32, width: f32, height: f32) -> Self {
+        Object::with_shape(obj_type, x, y, width, height, ObjectShape::Rect { width, height })
+    }
+
+    pub fn ellipse(obj_type: &str, x: f32, y: f32, width: f32, height: f32) -> Self {
+        Object::with_shape(obj_type, x, y, width, height, ObjectShape::Ellipse { width, height })
+    }
+
+    // Points are relative to `x` and `y`
+    pub fn polygon(obj_type: &str, x: f32, y: f32, points: Vec<(f32, f32)>) -> Self {
+        Object::with_shape(obj_type, x, y, 0.0, 0.0, ObjectShape::Polygon { points })
+    }
+
+    pub fn polyline(obj_type: &str, x: f32, y: f32, points: Vec<(f32, f32)>) -> Self {
+        Object::with_shape(obj_type, x, y, 0.0, 0.0, ObjectShape::Polyline { points })
+    }
+
+    pub fn point(obj_type: &str, x: f32, y: f32) -> Self {
+        Object::with_shape(obj_type, x, y, 0.0, 0.0, ObjectShape::Point(x, y))
+    }
+
+    /// A tile object. As in Tiled, `x` and `y` are its bottom left corner.
+    pub fn tile(obj_type: &str, gid: u32, x: f32, y: f32, width: f32, height: f32) -> Self {
+        let mut object = Object::rect(obj_type, x, y, width, height);
+        object.gid = gid;
+        object
+    }
+
+    pub fn named(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    pub fn rotated(mut self, rotation: f32) -> Self {
+        self.rotation = rotation;
+        self
+    }
+
+    pub fn with_property<V: Into<PropertyValue>>(mut self, name: &str, value: V) -> Self {
+        self.properties.insert(name.to_string(), value.into());
+        self
+    }
+}
+
+impl Image {
+    pub fn new(source: &str, width: u32, height: u32) -> Self {
+        Image { source: source.to_string(), width: width as i32, height: height as i32 }
+    }
+}
+
+impl From<bool> for PropertyValue {
+    fn from(value: bool) -> Self {
+        PropertyValue::Bool(value)
+    }
+}
+
+impl From<f32> for PropertyValue {
+    fn from(value: f32) -> Self {
+        PropertyValue::Float(value)
+    }
+}
+
+impl From<i32> for PropertyValue {
+    fn from(value: i32) -> Self {
+        PropertyValue::Int(value)
+    }
+}
+
+impl From<&str> for PropertyValue {
+    fn from(value: &str) -> Self {
+        PropertyValue::String(value.to_string())
+    }
+}
+
+impl From<String> for PropertyValue {
+    fn from(value: String) -> Self {
+        PropertyValue::String(value)
+    }
+}
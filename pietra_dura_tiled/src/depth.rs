@@ -0,0 +1,181 @@
+// The z tiles and objects are given, for layering and top down depth sorting
+
+use amethyst::{
+    assets::PrefabData,
+    core::Transform,
+    derive::PrefabData,
+    ecs::prelude::*,
+    error::Error,
+};
+use serde::{Deserialize, Serialize};
+use specs_derive::Component;
+
+use crate::{Map, Object, ObjectShape, Properties, PropertyValue};
+
+/// A layer or object group with this property is put at its value instead of
+/// where `DepthSettings` would put it
+pub const Z_PROPERTY: &str = "z";
+/// A layer or object group with this property set to true is y sorted
+pub const Y_SORT_PROPERTY: &str = "ysort";
+
+/// How tiles and objects are given their z. Each tile layer is `spacing` above the
+/// one before it starting from `layer_base`, and each object group likewise from
+/// `group_base`. The default puts everything at its layer or group's index.
+///
+/// Layers and groups with the `ysort` property spread their contents across the
+/// `spacing` above them by how far down the map they are, so things lower down
+/// are drawn in front.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DepthSettings {
+    pub layer_base: f32,
+    pub group_base: f32,
+    pub spacing: f32,
+}
+
+impl Default for DepthSettings {
+    fn default() -> Self {
+        DepthSettings {
+            layer_base: 0.0,
+            group_base: 0.0,
+            spacing: 1.0,
+        }
+    }
+}
+
+impl DepthSettings {
+    pub fn with_layer_base(mut self, layer_base: f32) -> Self {
+        self.layer_base = layer_base;
+        self
+    }
+
+    pub fn with_group_base(mut self, group_base: f32) -> Self {
+        self.group_base = group_base;
+        self
+    }
+
+    pub fn with_spacing(mut self, spacing: f32) -> Self {
+        self.spacing = spacing;
+        self
+    }
+
+    // `offset_y` is how far down the map has been moved, as in `ConversionSettings::offset`
+    pub fn layer_depth(&self, map: &Map, layer: usize, offset_y: f32) -> Depth {
+        let base = self.layer_base + layer as f32 * self.spacing;
+        self.depth(base, &map.layers[layer].properties, map, offset_y)
+    }
+
+    pub fn group_depth(&self, map: &Map, group: usize, offset_y: f32) -> Depth {
+        let base = self.group_base + group as f32 * self.spacing;
+        self.depth(base, &map.object_groups[group].properties, map, offset_y)
+    }
+
+    fn depth(&self, base: f32, properties: &Properties, map: &Map, offset_y: f32) -> Depth {
+        let z = match properties.get(Z_PROPERTY) {
+            Some(PropertyValue::Float(z)) => *z,
+            Some(PropertyValue::Int(z)) => *z as f32,
+            _ => base,
+        };
+        let y_sort = match properties.get(Y_SORT_PROPERTY) {
+            Some(PropertyValue::Bool(true)) => Some(YSort {
+                base: z,
+                spacing: self.spacing,
+                top: -offset_y,
+                height: (map.height * map.tile_height) as f32,
+                anchor: 0.0,
+                last_y: None,
+            }),
+            _ => None,
+        };
+        Depth { z, y_sort }
+    }
+}
+
+/// Where the contents of a layer or object group go
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Depth {
+    pub z: f32,
+    pub y_sort: Option<YSort>,
+}
+
+impl Depth {
+    /// The z of something whose bottom edge is at `bottom`, in world pixels with y
+    /// going up
+    pub fn z(&self, bottom: f32) -> f32 {
+        match &self.y_sort {
+            Some(y_sort) => y_sort.z(bottom),
+            None => self.z,
+        }
+    }
+}
+
+/// The world y of an object's bottom edge. Tile objects are placed by their bottom
+/// edge and everything else by its top.
+pub fn object_bottom(object: &Object) -> f32 {
+    let height = match object.shape {
+        ObjectShape::Rect { height, .. } | ObjectShape::Ellipse { height, .. } if object.gid == 0 => height,
+        _ => 0.0,
+    };
+    -(object.y + height)
+}
+
+/// Keeps an entity y sorted as it moves. `anchor` is how far above its bottom edge
+/// its transform is, and `top` and `height` are the world y of the top of its map
+/// and the map's height, which its z is spread over.
+#[derive(Debug, Copy, Clone, PartialEq, Component, Serialize, Deserialize, PrefabData)]
+#[prefab(Component)]
+pub struct YSort {
+    pub base: f32,
+    pub spacing: f32,
+    pub top: f32,
+    pub height: f32,
+    pub anchor: f32,
+    #[serde(skip)]
+    last_y: Option<f32>,
+}
+
+impl YSort {
+    pub fn with_anchor(mut self, anchor: f32) -> Self {
+        self.anchor = anchor;
+        self
+    }
+
+    // Stays below `base + spacing` so it never reaches the next layer
+    pub fn z(&self, bottom: f32) -> f32 {
+        let down = (self.top - bottom).max(0.0).min(self.height);
+        self.base + self.spacing * down / (self.height + 1.0)
+    }
+}
+
+/// Updates the z of y sorted entities which have moved since the last frame. Their
+/// z when they are created comes from the converter.
+#[derive(Default)]
+pub struct YSortSystem;
+
+impl<'s> System<'s> for YSortSystem {
+    type SystemData = (
+        Entities<'s>,
+        WriteStorage<'s, YSort>,
+        WriteStorage<'s, Transform>,
+    );
+
+    fn run(&mut self, (entities, mut y_sorts, mut transforms): Self::SystemData) {
+        // Transforms are only borrowed mutably for entities which have moved, so
+        // the rest aren't flagged as modified
+        let mut moved = Vec::new();
+        for (entity, y_sort, transform) in (&entities, &mut y_sorts, &transforms).join() {
+            let y = transform.translation().y;
+            if y_sort.last_y == Some(y) {
+                continue;
+            }
+            if y_sort.last_y.is_some() {
+                moved.push((entity, y_sort.z(y - y_sort.anchor)));
+            }
+            y_sort.last_y = Some(y);
+        }
+        for (entity, z) in moved {
+            if let Some(transform) = transforms.get_mut(entity) {
+                transform.set_translation_z(z);
+            }
+        }
+    }
+}
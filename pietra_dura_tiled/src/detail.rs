@@ -0,0 +1,122 @@
+use amethyst::{
+    core::Transform,
+    renderer::sprite::prefab::SpriteSheetReference,
+};
+
+use crate::{Object, ObjectShape, PropertyValue, SpriteContext, SpriteRenderPrefab, TileCell};
+
+/// Builds a prefab's detail from tiles and objects. Normally derived with
+/// `#[derive(TiledDetail)]`, which lets a `#[derive(TiledConverter)]` prefab
+/// take care of the sprite and transform.
+pub trait TiledDetail: Sized {
+    /// Object types `from_object` handles, used when validating maps
+    const OBJECT_TYPES: &'static [&'static str] = &[];
+    /// Object types which become colliders
+    const COLLIDER_TYPES: &'static [&'static str] = &[];
+
+    fn from_tile(ctx: &SpriteContext, cell: TileCell) -> Option<Self>;
+    fn from_object(ctx: &Option<SpriteContext>, layer: usize, object: &Object) -> Option<Self>;
+
+    /// A name for the kind of detail, used to count entities by kind. Derived
+    /// implementations use the variant name.
+    fn kind(&self) -> &'static str {
+        "detail"
+    }
+}
+
+/// Reads a value out of an object's custom properties. Normally derived with
+/// `#[derive(FromTiledObject)]`.
+pub trait FromTiledObject: Sized {
+    fn from_tiled_object(object: &Object) -> Option<Self>;
+}
+
+pub trait FromProperty: Sized {
+    fn from_property(value: &PropertyValue) -> Option<Self>;
+}
+
+impl FromProperty for bool {
+    fn from_property(value: &PropertyValue) -> Option<Self> {
+        match value {
+            PropertyValue::Bool(v) => Some(*v),
+            _ => None,
+        }
+    }
+}
+
+impl FromProperty for f32 {
+    fn from_property(value: &PropertyValue) -> Option<Self> {
+        match value {
+            PropertyValue::Float(v) => Some(*v),
+            PropertyValue::Int(v) => Some(*v as f32),
+            _ => None,
+        }
+    }
+}
+
+impl FromProperty for i32 {
+    fn from_property(value: &PropertyValue) -> Option<Self> {
+        match value {
+            PropertyValue::Int(v) => Some(*v),
+            _ => None,
+        }
+    }
+}
+
+impl FromProperty for u32 {
+    fn from_property(value: &PropertyValue) -> Option<Self> {
+        match value {
+            PropertyValue::Int(v) if *v >= 0 => Some(*v as u32),
+            PropertyValue::Color(v) => Some(*v),
+            _ => None,
+        }
+    }
+}
+
+impl FromProperty for usize {
+    fn from_property(value: &PropertyValue) -> Option<Self> {
+        match value {
+            PropertyValue::Int(v) if *v >= 0 => Some(*v as usize),
+            _ => None,
+        }
+    }
+}
+
+impl FromProperty for String {
+    fn from_property(value: &PropertyValue) -> Option<Self> {
+        match value {
+            PropertyValue::String(v) => Some(v.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl SpriteContext {
+    pub fn render(&self) -> SpriteRenderPrefab {
+        SpriteRenderPrefab {
+            sheet: Some(SpriteSheetReference::Name(self.name.clone())),
+            sprite_number: self.sprite_id as usize,
+        }
+    }
+}
+
+pub fn tile_transform(x: f32, y: f32, layer: usize) -> Transform {
+    let mut transform = Transform::default();
+    // In tiled's coordinate system down is positive, so flip it.
+    transform.set_translation_xyz(x, -y, layer as f32);
+    transform
+}
+
+// Tile objects are anchored at their bottom left corner
+pub fn object_transform(object: &Object, layer: usize) -> Transform {
+    let (width, height) = match object.shape {
+        ObjectShape::Rect { width, height } | ObjectShape::Ellipse { width, height } => (width, height),
+        _ => (0.0, 0.0),
+    };
+    let mut transform = Transform::default();
+    transform.set_translation_xyz(
+        object.x + width / 2.0,
+        -object.y + height / 2.0,
+        layer as f32,
+    );
+    transform
+}
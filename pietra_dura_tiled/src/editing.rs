@@ -0,0 +1,434 @@
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
+use amethyst::{
+    assets::PrefabData,
+    core::transform::Parent,
+    derive::PrefabData,
+    ecs::{prelude::*, world::Index},
+    error::Error,
+};
+use serde::{Deserialize, Serialize};
+
+/// The tile layer and grid coordinates a tile entity was created from.
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TileCell {
+    pub layer: usize,
+    pub x: usize,
+    pub y: usize,
+}
+
+// Flagged so `TileIndexSystem` only has to look at tiles which were added or removed
+impl Component for TileCell {
+    type Storage = FlaggedStorage<Self, DenseVecStorage<Self>>;
+}
+
+impl TileCell {
+    pub fn new(layer: usize, x: usize, y: usize) -> Self {
+        TileCell { layer, x, y }
+    }
+
+    // The same position `TiledConverter::from_map` hands to `convert_tile`
+    pub fn position(&self, tile_width: u32, tile_height: u32) -> (f32, f32) {
+        (
+            self.x as f32 * tile_width as f32 + tile_width as f32 / 2.0,
+            self.y as f32 * -(tile_height as f32) - tile_height as f32 / 2.0,
+        )
+    }
+}
+
+/// The tile cells under a collider made from one of the map's collision rectangles,
+/// so `TileEditSystem` can rebuild it when one of them is edited. Along with the
+/// cells it keeps what is needed to place the rebuilt colliders: the rectangle
+/// itself, the map's tile size, the tile layer the rectangle stands on and the
+/// `ConversionSettings::offset` the map was converted with.
+#[derive(Default, Debug, Copy, Clone, PartialEq, Serialize, Deserialize, PrefabData)]
+#[prefab(Component)]
+pub struct ColliderCells {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+    // The rectangle in map pixels with y going down, which needn't line up with the cells
+    pub bounds: (f32, f32, f32, f32),
+    pub tile_size: (u32, u32),
+    pub layer: usize,
+    pub offset: (f32, f32),
+}
+
+impl Component for ColliderCells {
+    type Storage = FlaggedStorage<Self, DenseVecStorage<Self>>;
+}
+
+impl ColliderCells {
+    /// The cells a rectangle in map pixels overlaps, on layer 0 with no offset
+    pub fn from_rect(x: f32, y: f32, width: f32, height: f32, tile_width: u32, tile_height: u32) -> Self {
+        let (cell_width, cell_height) = (tile_width.max(1) as f32, tile_height.max(1) as f32);
+        let left = (x / cell_width).floor().max(0.0);
+        let top = (y / cell_height).floor().max(0.0);
+        let right = ((x + width) / cell_width).ceil().max(left);
+        let bottom = ((y + height) / cell_height).ceil().max(top);
+        ColliderCells {
+            x: left as usize,
+            y: top as usize,
+            width: (right - left) as usize,
+            height: (bottom - top) as usize,
+            bounds: (x, y, width, height),
+            tile_size: (tile_width, tile_height),
+            layer: 0,
+            offset: (0.0, 0.0),
+        }
+    }
+
+    pub fn with_layer(mut self, layer: usize) -> Self {
+        self.layer = layer;
+        self
+    }
+
+    pub fn with_offset(mut self, offset: (f32, f32)) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        x >= self.x && x < self.x + self.width && y >= self.y && y < self.y + self.height
+    }
+
+    /// Every cell, row by row
+    pub fn positions(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        (self.y..self.y + self.height).flat_map(move |y| (self.x..self.x + self.width).map(move |x| (x, y)))
+    }
+
+    /// The center and size of the part of the rectangle over the cells, offset and
+    /// with y going up like the collider converted from it
+    pub fn rect(&self) -> (f32, f32, f32, f32) {
+        let (tile_width, tile_height) = (self.tile_size.0 as f32, self.tile_size.1 as f32);
+        let (x, y, width, height) = self.bounds;
+        let left = (self.x as f32 * tile_width).max(x);
+        let top = (self.y as f32 * tile_height).max(y);
+        let right = ((self.x + self.width) as f32 * tile_width).min(x + width).max(left);
+        let bottom = ((self.y + self.height) as f32 * tile_height).min(y + height).max(top);
+        (
+            (left + right) / 2.0 + self.offset.0,
+            -(top + bottom) / 2.0 - self.offset.1,
+            right - left,
+            bottom - top,
+        )
+    }
+
+    /// Splits the cells into runs of filled cells along their longer side, for
+    /// rebuilding a collider once some of the tiles under it are gone. If every
+    /// cell is filled they are returned as they are.
+    pub fn runs<F: Fn(usize, usize) -> bool>(&self, filled: F) -> Vec<ColliderCells> {
+        let horizontal = self.width >= self.height;
+        let (lanes, length) = if horizontal { (self.height, self.width) } else { (self.width, self.height) };
+        let cell = |lane: usize, i: usize| if horizontal { (self.x + i, self.y + lane) } else { (self.x + lane, self.y + i) };
+        let mut runs = Vec::new();
+        let mut all_filled = true;
+        for lane in 0..lanes {
+            let mut start = None;
+            for i in 0..=length {
+                let is_filled = i < length && {
+                    let (x, y) = cell(lane, i);
+                    filled(x, y)
+                };
+                if is_filled {
+                    start = start.or(Some(i));
+                    continue;
+                }
+                all_filled &= i == length;
+                if let Some(start) = start.take() {
+                    let (x, y) = cell(lane, start);
+                    let (width, height) = if horizontal { (i - start, 1) } else { (1, i - start) };
+                    runs.push(ColliderCells { x, y, width, height, ..*self });
+                }
+            }
+        }
+        if all_filled {
+            return vec![*self];
+        }
+        runs
+    }
+}
+
+/// Tiles and colliders by the root entity of the level or region they belong to,
+/// which is their `Parent`, and their cell, so maps loaded at the same time don't
+/// get in each other's way. Entities without a parent aren't indexed.
+#[derive(Default)]
+pub struct TileIndex {
+    cells: HashMap<(Entity, TileCell), Entity>,
+    // What each entity was indexed under, so it can be taken out again once removed
+    tiles: HashMap<Index, (Entity, TileCell)>,
+    colliders: HashMap<Index, (Entity, Entity, ColliderCells)>,
+}
+
+impl TileIndex {
+    pub fn get(&self, root: Entity, cell: &TileCell) -> Option<Entity> {
+        self.cells.get(&(root, *cell)).cloned()
+    }
+
+    /// The colliders under `root` whose `ColliderCells` include a cell
+    pub fn colliders_at(&self, root: Entity, cell: &TileCell) -> Vec<(Entity, ColliderCells)> {
+        let mut colliders: Vec<_> = self.colliders.values()
+            .filter(|(_, collider_root, cells)| {
+                *collider_root == root && cells.layer == cell.layer && cells.contains(cell.x, cell.y)
+            })
+            .map(|(entity, _, cells)| (*entity, *cells))
+            .collect();
+        colliders.sort_by_key(|(entity, _)| entity.id());
+        colliders
+    }
+
+    fn insert_tile(&mut self, entity: Entity, root: Entity, cell: TileCell) {
+        self.remove_tile(entity.id());
+        self.cells.insert((root, cell), entity);
+        self.tiles.insert(entity.id(), (root, cell));
+    }
+
+    fn remove_tile(&mut self, id: Index) {
+        if let Some(key) = self.tiles.remove(&id) {
+            // The cell may already hold the tile which replaced this one
+            if self.cells.get(&key).map(|entity| entity.id()) == Some(id) {
+                self.cells.remove(&key);
+            }
+        }
+    }
+}
+
+pub enum TileEdit<P> {
+    Set(Entity, TileCell, P),
+    Clear(Entity, TileCell),
+}
+
+type RebuildColliders<P> = Box<dyn Fn(&ColliderCells, Entity, &TileIndex) -> Vec<P> + Send + Sync>;
+
+/// Queue of runtime changes to the level's tiles. Edits are applied by `TileEditSystem`.
+///
+/// Every edit names the root entity of the level or region the tile belongs to, the
+/// entity holding its prefab handle. The prefab data given to `set_tile` is expected
+/// to attach the `TileCell` component (for example as part of the tile's detail) so
+/// the cell stays indexed.
+pub struct TileEdits<P> {
+    edits: Vec<TileEdit<P>>,
+    rebuild_colliders: Option<RebuildColliders<P>>,
+}
+
+impl<P> Default for TileEdits<P> {
+    fn default() -> Self {
+        TileEdits { edits: Vec::new(), rebuild_colliders: None }
+    }
+}
+
+impl<P> TileEdits<P> {
+    /// How colliders with `ColliderCells` are rebuilt when a tile they cover is
+    /// edited. `rebuild` gets their cells, the root they belong to and the index
+    /// with the edits applied, and returns the prefab data for the colliders
+    /// replacing them, which should carry `ColliderCells` of their own. Without it
+    /// they are left as they are.
+    pub fn with_collider_rebuild<F>(mut self, rebuild: F) -> Self
+        where F: Fn(&ColliderCells, Entity, &TileIndex) -> Vec<P> + Send + Sync + 'static {
+        self.rebuild_colliders = Some(Box::new(rebuild));
+        self
+    }
+
+    pub fn set_tile(&mut self, root: Entity, cell: TileCell, data: P) {
+        self.edits.push(TileEdit::Set(root, cell, data));
+    }
+
+    pub fn clear_tile(&mut self, root: Entity, cell: TileCell) {
+        self.edits.push(TileEdit::Clear(root, cell));
+    }
+}
+
+/// Keeps the `TileIndex` up to date as `TileCell` and `ColliderCells` components are
+/// added and removed, which is when levels and regions are spawned and removed.
+#[derive(Default)]
+pub struct TileIndexSystem {
+    tile_events: Option<ReaderId<ComponentEvent>>,
+    collider_events: Option<ReaderId<ComponentEvent>>,
+}
+
+impl<'s> System<'s> for TileIndexSystem {
+    type SystemData = (
+        Entities<'s>,
+        ReadStorage<'s, TileCell>,
+        ReadStorage<'s, ColliderCells>,
+        ReadStorage<'s, Parent>,
+        Write<'s, TileIndex>,
+    );
+
+    fn run(&mut self, (entities, tiles, colliders, parents, mut index): Self::SystemData) {
+        let tile_events = self.tile_events.as_mut().expect("TileIndexSystem wasn't set up");
+        for event in tiles.channel().read(tile_events) {
+            match event {
+                ComponentEvent::Inserted(id) | ComponentEvent::Modified(id) => {
+                    let entity = entities.entity(*id);
+                    match (tiles.get(entity), parents.get(entity)) {
+                        (Some(cell), Some(parent)) => index.insert_tile(entity, parent.entity, *cell),
+                        _ => index.remove_tile(*id),
+                    }
+                },
+                ComponentEvent::Removed(id) => index.remove_tile(*id),
+            }
+        }
+        let collider_events = self.collider_events.as_mut().expect("TileIndexSystem wasn't set up");
+        for event in colliders.channel().read(collider_events) {
+            match event {
+                ComponentEvent::Inserted(id) | ComponentEvent::Modified(id) => {
+                    let entity = entities.entity(*id);
+                    match (colliders.get(entity), parents.get(entity)) {
+                        (Some(cells), Some(parent)) => {
+                            index.colliders.insert(*id, (entity, parent.entity, *cells));
+                        },
+                        _ => {
+                            index.colliders.remove(id);
+                        },
+                    }
+                },
+                ComponentEvent::Removed(id) => {
+                    index.colliders.remove(id);
+                },
+            }
+        }
+    }
+
+    fn setup(&mut self, res: &mut Resources) {
+        Self::SystemData::setup(res);
+        self.tile_events = Some(WriteStorage::<TileCell>::fetch(res).register_reader());
+        self.collider_events = Some(WriteStorage::<ColliderCells>::fetch(res).register_reader());
+    }
+}
+
+/// Applies queued `TileEdits`. A tile which is replaced or cleared has its entity
+/// deleted, so anything attached to it (sprite, physics bodies, colliders) goes with
+/// it, and the new tile is built from scratch using its prefab data and parented to
+/// the edit's root, so it is removed along with its level or region.
+///
+/// Colliders under the same root covering an edited cell of their layer are rebuilt
+/// with the function given to `TileEdits::with_collider_rebuild`. Colliders crossing
+/// into another region only see the tiles of their own.
+///
+/// Every tile is an entity with a sprite of its own, so there are no baked chunk
+/// meshes to rebuild. A game which bakes tiles into meshes would rebuild them from
+/// the `TileIndex` after the edits are applied.
+pub struct TileEditSystem<P> {
+    phantom: PhantomData<P>,
+}
+
+impl<P> Default for TileEditSystem<P> {
+    fn default() -> Self {
+        TileEditSystem { phantom: PhantomData }
+    }
+}
+
+impl<'s, P> System<'s> for TileEditSystem<P>
+    where P: PrefabData<'s> + Send + Sync + 'static {
+    type SystemData = (
+        Entities<'s>,
+        Write<'s, TileEdits<P>>,
+        Write<'s, TileIndex>,
+        WriteStorage<'s, Parent>,
+        P::SystemData,
+    );
+
+    fn run(&mut self, (entities, mut edits, mut index, mut parents, mut prefab_data): Self::SystemData) {
+        let edits = &mut *edits;
+        let mut edited = Vec::new();
+        for edit in edits.edits.drain(..) {
+            let (root, cell, data) = match edit {
+                TileEdit::Clear(root, cell) => (root, cell, None),
+                TileEdit::Set(root, cell, data) => (root, cell, Some(data)),
+            };
+            if let Some(old) = index.cells.remove(&(root, cell)) {
+                entities.delete(old).expect("Unable to delete tile");
+            }
+            if let Some(data) = data {
+                let entity = entities.create();
+                data.add_to_entity(entity, &mut prefab_data, &[], &[])
+                    .expect("Unable to build tile");
+                parents.insert(entity, Parent { entity: root }).expect("Unable to parent tile");
+                index.insert_tile(entity, root, cell);
+            }
+            edited.push((root, cell));
+        }
+
+        let rebuild = match &edits.rebuild_colliders {
+            Some(rebuild) => rebuild,
+            None => return,
+        };
+        let mut colliders = Vec::new();
+        for (root, cell) in &edited {
+            for (collider, cells) in index.colliders_at(*root, cell) {
+                if !colliders.contains(&(collider, *root, cells)) {
+                    colliders.push((collider, *root, cells));
+                }
+            }
+        }
+        for (old, root, cells) in colliders {
+            entities.delete(old).expect("Unable to delete collider");
+            index.colliders.remove(&old.id());
+            for data in rebuild(&cells, root, &*index) {
+                let entity = entities.create();
+                data.add_to_entity(entity, &mut prefab_data, &[], &[])
+                    .expect("Unable to build collider");
+                parents.insert(entity, Parent { entity: root }).expect("Unable to parent collider");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cells(cells: &ColliderCells) -> (usize, usize, usize, usize) {
+        (cells.x, cells.y, cells.width, cells.height)
+    }
+
+    #[test]
+    fn rects_cover_the_cells_they_overlap() {
+        assert_eq!(cells(&ColliderCells::from_rect(0.0, 0.0, 320.0, 32.0, 32, 32)), (0, 0, 10, 1));
+        assert_eq!(cells(&ColliderCells::from_rect(40.0, 40.0, 32.0, 8.0, 32, 32)), (1, 1, 2, 1));
+        assert_eq!(cells(&ColliderCells::from_rect(64.0, 0.0, 0.0, 0.0, 32, 32)), (2, 0, 0, 0));
+    }
+
+    #[test]
+    fn rect_matches_tile_positions() {
+        let (x, y) = TileCell::new(0, 3, 2).position(32, 32);
+        assert_eq!(ColliderCells::from_rect(96.0, 64.0, 32.0, 32.0, 32, 32).rect(), (x, y, 32.0, 32.0));
+        assert_eq!(ColliderCells::from_rect(0.0, 0.0, 320.0, 32.0, 32, 32).rect(), (160.0, -16.0, 320.0, 32.0));
+    }
+
+    #[test]
+    fn rect_keeps_rectangles_off_the_grid() {
+        assert_eq!(ColliderCells::from_rect(40.0, 40.0, 32.0, 8.0, 32, 32).rect(), (56.0, -44.0, 32.0, 8.0));
+        let offset = ColliderCells::from_rect(40.0, 40.0, 32.0, 8.0, 32, 32).with_offset((320.0, 160.0));
+        assert_eq!(offset.rect(), (376.0, -204.0, 32.0, 8.0));
+    }
+
+    #[test]
+    fn filled_cells_are_kept_whole() {
+        let cells = ColliderCells::from_rect(0.0, 0.0, 320.0, 64.0, 32, 32);
+        assert_eq!(cells.runs(|_, _| true), vec![cells]);
+    }
+
+    #[test]
+    fn gaps_split_along_the_longer_side() {
+        let wall = ColliderCells::from_rect(0.0, 0.0, 160.0, 32.0, 32, 32);
+        let runs: Vec<_> = wall.runs(|x, _| x != 2).iter().map(cells).collect();
+        assert_eq!(runs, vec![(0, 0, 2, 1), (3, 0, 2, 1)]);
+        let column = ColliderCells::from_rect(128.0, 32.0, 32.0, 128.0, 32, 32);
+        let runs: Vec<_> = column.runs(|_, y| y != 1).iter().map(cells).collect();
+        assert_eq!(runs, vec![(4, 2, 1, 3)]);
+        assert!(column.runs(|_, _| false).is_empty());
+    }
+
+    #[test]
+    fn split_runs_stay_inside_the_rectangle() {
+        let wall = ColliderCells::from_rect(8.0, 4.0, 144.0, 24.0, 32, 32).with_layer(2).with_offset((320.0, 0.0));
+        let runs = wall.runs(|x, _| x != 2);
+        let rects: Vec<_> = runs.iter().map(ColliderCells::rect).collect();
+        assert_eq!(rects, vec![(356.0, -16.0, 56.0, 24.0), (444.0, -16.0, 56.0, 24.0)]);
+        assert!(runs.iter().all(|run| run.layer == 2));
+    }
+}
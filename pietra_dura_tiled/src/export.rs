@@ -0,0 +1,361 @@
+// Going back from a level in the world to a Tiled map, so levels edited in game can
+// be carried on with in Tiled.
+
+use std::collections::HashSet;
+use std::fs::write;
+use std::io::Result;
+use std::path::Path;
+
+use amethyst::{
+    core::Transform,
+    ecs::prelude::*,
+};
+
+use crate::json::escape;
+use crate::{Image, Layer, Map, Object, ObjectGroup, ObjectShape, Properties, PropertyValue, TileCell};
+
+/// Reads a level out of the world into a `MapExport`. The reverse of
+/// `TiledConverter`, implemented by hand since only the game knows which of its
+/// components came from which tiles and objects.
+pub trait TiledExporter<'a> {
+    type SystemData: SystemData<'a>;
+
+    fn export(data: Self::SystemData, export: &mut MapExport);
+
+    /// Exports the level in `world`. `original` is the map it was converted from,
+    /// whose tilesets the exported tiles and objects refer to.
+    fn export_world(world: &'a mut World, original: &Map) -> MapExport {
+        Self::export_world_with_offset(world, original, (0.0, 0.0))
+    }
+
+    /// Exports a level which was converted with `ConversionSettings::offset`
+    fn export_world_with_offset(world: &'a mut World, original: &Map, offset: (f32, f32)) -> MapExport {
+        let mut export = MapExport::new(original).with_offset(offset);
+        world.exec(|data: Self::SystemData| Self::export(data, &mut export));
+        export
+    }
+}
+
+/// A map being rebuilt from a level. It starts as the original map with its tile
+/// layers cleared and its object groups emptied, keeping their names and
+/// properties along with the map's size, tilesets and properties.
+pub struct MapExport {
+    original: Map,
+    map: Map,
+    next_object_id: u32,
+    // `ConversionSettings::offset`, taken back off exported objects
+    offset: (f32, f32),
+    // The original objects exported objects have been matched with, by group and index
+    matched: HashSet<(usize, usize)>,
+}
+
+impl MapExport {
+    pub fn new(original: &Map) -> Self {
+        let mut map = original.clone();
+        for layer in &mut map.layers {
+            for row in &mut layer.tiles {
+                for cell in row.iter_mut() {
+                    *cell = 0;
+                }
+            }
+        }
+        for group in &mut map.object_groups {
+            group.objects.clear();
+        }
+        MapExport {
+            original: original.clone(),
+            map,
+            next_object_id: 1,
+            offset: (0.0, 0.0),
+            matched: HashSet::new(),
+        }
+    }
+
+    pub fn with_offset(mut self, offset: (f32, f32)) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// The gid of a sprite, given the index of the tileset its sprite sheet was made
+    /// from. This is the reverse of the mapping `sprite_sheets_from_tilesets` makes.
+    pub fn gid(&self, tileset: usize, sprite_number: usize) -> Option<u32> {
+        let tileset = self.map.tilesets.get(tileset)?;
+        if !tileset.images.is_empty() {
+            Some(tileset.first_gid + sprite_number as u32)
+        } else {
+            // Collections are packed in tile id order
+            let mut ids: Vec<_> = tileset.tiles.iter().map(|t| t.id).collect();
+            ids.sort();
+            ids.get(sprite_number).map(|id| tileset.first_gid + id)
+        }
+    }
+
+    /// The size of the tile a gid refers to
+    pub fn tile_size(&self, gid: u32) -> Option<(u32, u32)> {
+        let tileset = self.map.tilesets.iter().rev().find(|t| t.first_gid <= gid)?;
+        if !tileset.images.is_empty() {
+            return Some((tileset.tile_width, tileset.tile_height));
+        }
+        tileset.tiles.iter()
+            .find(|t| t.id == gid - tileset.first_gid)
+            .and_then(|t| t.images.first())
+            .map(|i| (i.width as u32, i.height as u32))
+    }
+
+    /// Sets a tile, adding layers if the cell's layer is past the original ones.
+    /// Cells outside the map are ignored.
+    pub fn set_tile(&mut self, cell: TileCell, gid: u32) {
+        let (width, height) = (self.map.width as usize, self.map.height as usize);
+        while self.map.layers.len() <= cell.layer {
+            let name = format!("layer_{}", self.map.layers.len());
+            self.map.layers.push(Layer {
+                name,
+                opacity: 1.0,
+                visible: true,
+                parallax: (1.0, 1.0),
+                tiles: vec![vec![0; width]; height],
+                properties: Properties::new(),
+            });
+        }
+        if let Some(tile) = self.map.layers[cell.layer].tiles.get_mut(cell.y).and_then(|row| row.get_mut(cell.x)) {
+            *tile = gid;
+        }
+    }
+
+    /// Adds an object to a group, adding groups if `group` is past the original
+    /// ones, and gives it the next free id.
+    pub fn add_object(&mut self, group: usize, mut object: Object) {
+        while self.map.object_groups.len() <= group {
+            let name = format!("objects_{}", self.map.object_groups.len());
+            self.map.object_groups.push(ObjectGroup {
+                name,
+                opacity: 1.0,
+                visible: true,
+                parallax: (1.0, 1.0),
+                objects: Vec::new(),
+                properties: Properties::new(),
+            });
+        }
+        object.id = self.next_object_id;
+        self.next_object_id += 1;
+        self.map.object_groups[group].objects.push(object);
+    }
+
+    /// The first of the original map's object groups with an object of this type, or
+    /// the first group if none has, for putting exported objects back where they were.
+    pub fn group_of_type(&self, obj_type: &str) -> usize {
+        self.original.object_groups.iter()
+            .position(|group| group.objects.iter().any(|o| o.obj_type == obj_type))
+            .unwrap_or(0)
+    }
+
+    /// A tile object placed where `TiledConverter` would have put `transform` for
+    /// it. The transform's z comes from `DepthSettings` so can't be turned back into
+    /// a group, which has to be given.
+    ///
+    /// Properties don't make it into the world, so they are copied from the original
+    /// object of the same type with the same name or, for objects without one, at the
+    /// same place. Each original is only used once.
+    pub fn add_tile_object(&mut self, group: usize, obj_type: &str, name: Option<&str>, gid: u32, transform: &Transform) {
+        let (width, height) = self.tile_size(gid).unwrap_or((0, 0));
+        let (width, height) = (width as f32, height as f32);
+        let translation = transform.translation();
+        let mut object = Object::tile(
+            obj_type,
+            gid,
+            translation.x - self.offset.0 - width / 2.0,
+            height / 2.0 - translation.y - self.offset.1,
+            width,
+            height,
+        );
+        if let Some(name) = name {
+            object.name = name.to_string();
+        }
+        if let Some((original_group, index)) = self.original_of(&object) {
+            self.matched.insert((original_group, index));
+            let original = &self.original.object_groups[original_group].objects[index];
+            object.name = original.name.clone();
+            object.properties = original.properties.clone();
+        }
+        self.add_object(group, object);
+    }
+
+    fn original_of(&self, object: &Object) -> Option<(usize, usize)> {
+        let matches = |original: &Object| {
+            if !object.name.is_empty() {
+                original.name == object.name
+            } else {
+                (original.x - object.x).abs() < 0.5 && (original.y - object.y).abs() < 0.5
+            }
+        };
+        self.original.object_groups.iter()
+            .enumerate()
+            .flat_map(|(i, group)| group.objects.iter().enumerate().map(move |(j, o)| ((i, j), o)))
+            .find(|(key, original)| {
+                !self.matched.contains(key) && original.obj_type == object.obj_type && matches(original)
+            })
+            .map(|(key, _)| key)
+    }
+
+    /// Copies the original map's objects for which `keep` returns true, for things
+    /// which can't be read back out of the world.
+    pub fn keep_objects<F: Fn(&Object) -> bool>(&mut self, keep: F) {
+        let kept: Vec<_> = self.original.object_groups.iter()
+            .enumerate()
+            .flat_map(|(i, group)| group.objects.iter().filter(|o| keep(o)).map(move |o| (i, o.clone())))
+            .collect();
+        for (group, object) in kept {
+            self.add_object(group, object);
+        }
+    }
+
+    pub fn map(&self) -> &Map {
+        &self.map
+    }
+
+    pub fn into_map(self) -> Map {
+        self.map
+    }
+
+    pub fn to_tmx(&self) -> String {
+        write_tmx(&self.map)
+    }
+
+    /// Writes the map as TMX. Tileset images keep the paths they had in the original
+    /// map, so this is usually written next to it.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        write(path, self.to_tmx())
+    }
+}
+
+/// The index of the tileset a sprite sheet was made from, from the name
+/// `TiledConverter` gave the sheet.
+pub fn sprite_sheet_tileset(name: &str) -> Option<usize> {
+    let start = name.rfind("_sprite_sheet_")? + "_sprite_sheet_".len();
+    name[start..].parse().ok()
+}
+
+/// Writes a map as TMX with its tiles as CSV. Tilesets read from their own files
+/// refer to them again, relative to where the map was read from, and the rest are
+/// embedded.
+pub fn write_tmx(map: &Map) -> String {
+    let next_object_id = map.object_groups.iter()
+        .flat_map(|g| g.objects.iter().map(|o| o.id))
+        .max()
+        .unwrap_or(0) + 1;
+    let mut tmx = String::new();
+    tmx.push_str(&format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<map version=\"1.2\" orientation=\"orthogonal\" renderorder=\"right-down\" width=\"{}\" height=\"{}\" tilewidth=\"{}\" tileheight=\"{}\" nextobjectid=\"{}\">\n",
+        map.width, map.height, map.tile_width, map.tile_height, next_object_id,
+    ));
+    write_properties(&mut tmx, &map.properties);
+
+    for tileset in &map.tilesets {
+        if let Some(source) = &tileset.source {
+            tmx.push_str(&format!("<tileset firstgid=\"{}\" source=\"{}\"/>\n", tileset.first_gid, escape(source)));
+            continue;
+        }
+        let (tile_count, columns) = match tileset.images.first() {
+            Some(image) => {
+                let columns = image.width as u32 / tileset.tile_width.max(1);
+                (columns * (image.height as u32 / tileset.tile_height.max(1)), columns)
+            },
+            None => (tileset.tiles.len() as u32, 0),
+        };
+        tmx.push_str(&format!(
+            "<tileset firstgid=\"{}\" name=\"{}\" tilewidth=\"{}\" tileheight=\"{}\" spacing=\"{}\" margin=\"{}\" tilecount=\"{}\" columns=\"{}\">\n",
+            tileset.first_gid, escape(&tileset.name), tileset.tile_width, tileset.tile_height,
+            tileset.spacing, tileset.margin, tile_count, columns,
+        ));
+        for image in &tileset.images {
+            write_image(&mut tmx, image);
+        }
+        for tile in &tileset.tiles {
+            tmx.push_str(&format!("<tile id=\"{}\">\n", tile.id));
+            write_properties(&mut tmx, &tile.properties);
+            for image in &tile.images {
+                write_image(&mut tmx, image);
+            }
+            tmx.push_str("</tile>\n");
+        }
+        tmx.push_str("</tileset>\n");
+    }
+
+    for layer in &map.layers {
+        tmx.push_str(&format!(
+            "<layer name=\"{}\" width=\"{}\" height=\"{}\" opacity=\"{}\" visible=\"{}\" parallaxx=\"{}\" parallaxy=\"{}\">\n",
+            escape(&layer.name), map.width, map.height, layer.opacity, if layer.visible { 1 } else { 0 },
+            layer.parallax.0, layer.parallax.1,
+        ));
+        write_properties(&mut tmx, &layer.properties);
+        let rows: Vec<String> = layer.tiles.iter()
+            .map(|row| row.iter().map(|gid| gid.to_string()).collect::<Vec<_>>().join(","))
+            .collect();
+        tmx.push_str(&format!("<data encoding=\"csv\">\n{}\n</data>\n", rows.join(",\n")));
+        tmx.push_str("</layer>\n");
+    }
+
+    for group in &map.object_groups {
+        tmx.push_str(&format!(
+            "<objectgroup name=\"{}\" opacity=\"{}\" visible=\"{}\" parallaxx=\"{}\" parallaxy=\"{}\">\n",
+            escape(&group.name), group.opacity, if group.visible { 1 } else { 0 },
+            group.parallax.0, group.parallax.1,
+        ));
+        write_properties(&mut tmx, &group.properties);
+        for object in &group.objects {
+            write_object(&mut tmx, object);
+        }
+        tmx.push_str("</objectgroup>\n");
+    }
+    tmx.push_str("</map>\n");
+    tmx
+}
+
+fn write_image(tmx: &mut String, image: &Image) {
+    tmx.push_str(&format!(
+        "<image source=\"{}\" width=\"{}\" height=\"{}\"/>\n",
+        escape(&image.source), image.width, image.height,
+    ));
+}
+
+fn write_properties(tmx: &mut String, properties: &Properties) {
+    if properties.is_empty() {
+        return;
+    }
+    tmx.push_str("<properties>\n");
+    for (name, value) in properties {
+        let (kind, value) = match value {
+            PropertyValue::Bool(v) => ("bool", v.to_string()),
+            PropertyValue::Float(v) => ("float", v.to_string()),
+            PropertyValue::Int(v) => ("int", v.to_string()),
+            PropertyValue::Color(v) => ("color", format!("#{:08x}", v)),
+            PropertyValue::String(v) => ("string", escape(v)),
+        };
+        tmx.push_str(&format!("<property name=\"{}\" type=\"{}\" value=\"{}\"/>\n", escape(name), kind, value));
+    }
+    tmx.push_str("</properties>\n");
+}
+
+fn write_object(tmx: &mut String, object: &Object) {
+    tmx.push_str(&format!(
+        "<object id=\"{}\" name=\"{}\" type=\"{}\" x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" rotation=\"{}\" visible=\"{}\"",
+        object.id, escape(&object.name), escape(&object.obj_type), object.x, object.y,
+        object.width, object.height, object.rotation, if object.visible { 1 } else { 0 },
+    ));
+    if object.gid != 0 {
+        tmx.push_str(&format!(" gid=\"{}\"", object.gid));
+    }
+    tmx.push_str(">\n");
+    write_properties(tmx, &object.properties);
+    let points = |points: &[(f32, f32)]| {
+        points.iter().map(|(x, y)| format!("{},{}", x, y)).collect::<Vec<_>>().join(" ")
+    };
+    match &object.shape {
+        ObjectShape::Rect { .. } => (),
+        ObjectShape::Ellipse { .. } => tmx.push_str("<ellipse/>\n"),
+        ObjectShape::Point(..) => tmx.push_str("<point/>\n"),
+        ObjectShape::Polygon { points: p } => tmx.push_str(&format!("<polygon points=\"{}\"/>\n", points(p))),
+        ObjectShape::Polyline { points: p } => tmx.push_str(&format!("<polyline points=\"{}\"/>\n", points(p))),
+    }
+    tmx.push_str("</object>\n");
+}
@@ -0,0 +1,107 @@
+use std::any::Any;
+use std::fmt;
+use std::marker::PhantomData;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use amethyst::{
+    assets::{Format, FormatValue, Prefab, PrefabData, Source},
+    error::Error,
+};
+use serde::de::DeserializeOwned;
+
+use crate::{parse_map, ConversionSettings, TiledConverter};
+
+/// Loads `.tmx` maps directly as prefabs by running the converter inside the asset
+/// loader, so maps can be used without running them through an asset_prep step first.
+///
+/// Converted sprite sheet images are written under `assets_dir/map_prefix` where the
+/// prefab expects to find them. The map's tilesets and images are read from the
+/// filesystem relative to the map, so the asset source passed to the loader is
+/// ignored and the map's name is resolved against `assets_dir`.
+///
+/// A map which can't be read or converted fails to load with an error rather than
+/// taking down the loader.
+pub struct TmxFormat<C> {
+    assets_dir: PathBuf,
+    map_prefix: PathBuf,
+    settings: ConversionSettings,
+    phantom: PhantomData<fn() -> C>,
+}
+
+impl<C> TmxFormat<C> {
+    pub fn new<A: Into<PathBuf>, M: Into<PathBuf>>(assets_dir: A, map_prefix: M) -> Self {
+        TmxFormat {
+            assets_dir: assets_dir.into(),
+            map_prefix: map_prefix.into(),
+            settings: ConversionSettings::default(),
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn with_settings(mut self, settings: ConversionSettings) -> Self {
+        self.settings = settings;
+        self
+    }
+}
+
+impl<C> Clone for TmxFormat<C> {
+    fn clone(&self) -> Self {
+        TmxFormat {
+            assets_dir: self.assets_dir.clone(),
+            map_prefix: self.map_prefix.clone(),
+            settings: self.settings.clone(),
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<C> fmt::Debug for TmxFormat<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TmxFormat")
+            .field("assets_dir", &self.assets_dir)
+            .field("map_prefix", &self.map_prefix)
+            .finish()
+    }
+}
+
+impl<C, P> Format<Prefab<P>> for TmxFormat<C>
+    where C: for<'s> TiledConverter<'s, P> + 'static,
+          P: for<'s> PrefabData<'s> + DeserializeOwned + Send + Sync + 'static {
+    fn name(&self) -> &'static str {
+        "TMX"
+    }
+
+    fn import(
+        &self,
+        name: String,
+        _source: Arc<dyn Source>,
+        _create_reload: Option<Box<dyn Format<Prefab<P>>>>,
+    ) -> Result<FormatValue<Prefab<P>>, Error> {
+        let input = self.assets_dir.join(&name);
+        if !input.is_file() {
+            return Err(Error::from_string(format!("Map {:?} does not exist", input)));
+        }
+        let map = parse_map(&input)
+            .map_err(|e| Error::from_string(format!("Unable to parse {:?}: {}", input, e)))?;
+        // Converters index into the map's tilesets and expect its images to be
+        // readable, so a malformed map can still panic partway through
+        let map = catch_unwind(AssertUnwindSafe(|| {
+            let map = C::from_parsed_map(map, &input, &self.map_prefix, &self.settings);
+            map.write(&self.assets_dir.join(&self.map_prefix)).map(|_| map)
+        }))
+            .map_err(|panic| Error::from_string(format!("Unable to convert {:?}: {}", input, panic_message(&*panic))))??;
+        let data = map.prefab_data()
+            .ok_or_else(|| Error::from_string(format!("No prefab was produced for {:?}", input)))?;
+        let prefab = map.output_format().decode(data)
+            .map_err(|e| Error::from_string(format!("Failed to load converted map {:?}: {}", input, e)))?;
+        Ok(FormatValue::data(prefab))
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    panic.downcast_ref::<String>().cloned()
+        .or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()))
+        .unwrap_or_else(|| "conversion panicked".to_string())
+}
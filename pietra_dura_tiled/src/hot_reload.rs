@@ -0,0 +1,229 @@
+use std::fs::{metadata, read_to_string};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use amethyst::{
+    assets::{AssetStorage, Handle, Prefab, PrefabData, PrefabLoader},
+    core::{transform::Parent, Named},
+    ecs::prelude::*,
+};
+use specs_derive::Component;
+
+use crate::json::{is_json, json_references};
+use crate::loading::spawned_descendants;
+use crate::NavGrid;
+
+/// Tracks the modification times of a map and everything it references (external
+/// tilesets and images) so changes made in Tiled can be picked up. The files are
+/// looked at no more than once every poll interval, half a second by default.
+pub struct MapWatcher {
+    path: PathBuf,
+    modified: Vec<(PathBuf, Option<SystemTime>)>,
+    poll_interval: Duration,
+    last_poll: Instant,
+}
+
+impl MapWatcher {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        let path = path.into();
+        let modified = snapshot(&path);
+        MapWatcher {
+            path,
+            modified,
+            poll_interval: Duration::from_millis(500),
+            last_poll: Instant::now(),
+        }
+    }
+
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn dependencies(&self) -> impl Iterator<Item = &Path> {
+        self.modified.iter().map(|(p, _)| p.as_path())
+    }
+
+    /// True if anything has changed since the last call (or since the watcher was
+    /// created). Always false until the poll interval has passed since the last look.
+    pub fn changed(&mut self) -> bool {
+        if self.last_poll.elapsed() < self.poll_interval {
+            return false;
+        }
+        self.last_poll = Instant::now();
+        let modified = snapshot(&self.path);
+        if modified != self.modified {
+            self.modified = modified;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// The map itself followed by every tileset and image it references
+pub fn map_dependencies(path: &Path) -> Vec<PathBuf> {
+    let mut dependencies = Vec::new();
+    collect_dependencies(path, &mut dependencies);
+    dependencies
+}
+
+fn snapshot(path: &Path) -> Vec<(PathBuf, Option<SystemTime>)> {
+    map_dependencies(path).into_iter()
+        .map(|p| {
+            let modified = metadata(&p).and_then(|m| m.modified()).ok();
+            (p, modified)
+        })
+        .collect()
+}
+
+fn collect_dependencies(path: &Path, dependencies: &mut Vec<PathBuf>) {
+    if dependencies.iter().any(|p| p == path) {
+        return;
+    }
+    dependencies.push(path.to_path_buf());
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    if is_json(path) {
+        for source in json_references(path) {
+            collect_dependencies(&dir.join(source), dependencies);
+        }
+        return;
+    }
+    let is_tiled_file = match path.extension().and_then(|e| e.to_str()) {
+        Some("tmx") | Some("tsx") => true,
+        _ => false,
+    };
+    if !is_tiled_file {
+        return;
+    }
+    // Both tilesets and images are referenced through source attributes
+    let text = match read_to_string(path) {
+        Ok(text) => text,
+        Err(_) => return,
+    };
+    for source in text.split("source=\"").skip(1) {
+        if let Some(end) = source.find('"') {
+            collect_dependencies(&dir.join(&source[..end]), dependencies);
+        }
+    }
+}
+
+/// Entities with this component are left alone when their level is reloaded. Any
+/// entity the reloaded level spawns with the same `Named` as one of them, such as
+/// the player it was placed as, is deleted so it isn't there twice.
+#[derive(Default, Debug, Copy, Clone, Component)]
+#[storage(NullStorage)]
+pub struct PreserveOnReload;
+
+/// Attach to a level's root entity (the one holding the prefab handle) to reload
+/// the level whenever the watched map changes. `load` is used to load the new prefab.
+///
+/// With `with_nav_grid` the `NavGrid` resource is read again from the given
+/// `nav.ron` once the reloaded level has spawned.
+pub struct HotReloadLevel<P> {
+    watcher: MapWatcher,
+    load: Box<dyn Fn(&PrefabLoader<'_, P>) -> Handle<Prefab<P>> + Send + Sync>,
+    nav_grid: Option<PathBuf>,
+    // The names of the preserved entities while waiting for the reloaded level to spawn
+    respawning: Option<Vec<String>>,
+}
+
+impl<P> HotReloadLevel<P> {
+    pub fn new<F>(watcher: MapWatcher, load: F) -> Self
+        where F: Fn(&PrefabLoader<'_, P>) -> Handle<Prefab<P>> + Send + Sync + 'static {
+        HotReloadLevel {
+            watcher,
+            load: Box::new(load),
+            nav_grid: None,
+            respawning: None,
+        }
+    }
+
+    pub fn with_nav_grid<T: Into<PathBuf>>(mut self, path: T) -> Self {
+        self.nav_grid = Some(path.into());
+        self
+    }
+}
+
+impl<P: Send + Sync + 'static> Component for HotReloadLevel<P> {
+    type Storage = HashMapStorage<Self>;
+}
+
+/// Despawns the entities of a level whose map has changed and respawns it from a
+/// freshly loaded prefab. Anything which isn't part of the level, like the camera,
+/// is untouched. A navigation grid which fails to load keeps the old one.
+pub struct LevelReloadSystem<P> {
+    phantom: PhantomData<P>,
+}
+
+impl<P> Default for LevelReloadSystem<P> {
+    fn default() -> Self {
+        LevelReloadSystem { phantom: PhantomData }
+    }
+}
+
+impl<'s, P> System<'s> for LevelReloadSystem<P>
+    where P: PrefabData<'s> + Send + Sync + 'static {
+    type SystemData = (
+        Entities<'s>,
+        ReadStorage<'s, Parent>,
+        ReadStorage<'s, PreserveOnReload>,
+        ReadStorage<'s, Named>,
+        WriteStorage<'s, HotReloadLevel<P>>,
+        WriteStorage<'s, Handle<Prefab<P>>>,
+        Read<'s, AssetStorage<Prefab<P>>>,
+        Option<Write<'s, NavGrid>>,
+        PrefabLoader<'s, P>,
+    );
+
+    fn run(&mut self, (entities, parents, preserved, names, mut levels, mut handles, prefabs, mut nav_grid, loader): Self::SystemData) {
+        let mut descendants = None;
+        for (root, level) in (&entities, &mut levels).join() {
+            if let Some(preserved_names) = &level.respawning {
+                let prefab = match handles.get(root).and_then(|handle| prefabs.get(handle)) {
+                    Some(prefab) => prefab,
+                    None => continue,
+                };
+                let descendants = descendants.get_or_insert_with(|| spawned_descendants(&parents, &preserved));
+                if descendants.get(&root).cloned().unwrap_or(0) < prefab.len().saturating_sub(1) {
+                    continue;
+                }
+                for (entity, parent, name, _) in (&entities, &parents, &names, !&preserved).join() {
+                    if parent.entity == root && preserved_names.iter().any(|n| *n == name.name) {
+                        entities.delete(entity).expect("Unable to delete respawned entity");
+                    }
+                }
+                if let (Some(path), Some(nav_grid)) = (&level.nav_grid, nav_grid.as_mut()) {
+                    if let Ok(grid) = NavGrid::load(path) {
+                        **nav_grid = grid;
+                    }
+                }
+                level.respawning = None;
+                continue;
+            }
+            if !level.watcher.changed() {
+                continue;
+            }
+            let mut preserved_names = Vec::new();
+            for (entity, parent, kept) in (&entities, &parents, preserved.maybe()).join() {
+                if parent.entity != root {
+                    continue;
+                }
+                if kept.is_some() {
+                    preserved_names.extend(names.get(entity).map(|n| n.name.to_string()));
+                } else {
+                    entities.delete(entity).expect("Unable to delete level entity");
+                }
+            }
+            level.respawning = Some(preserved_names);
+            // Removing first makes sure the prefab loader sees a fresh insert
+            handles.remove(root);
+            handles.insert(root, (level.load)(&loader)).expect("Unable to reload level");
+        }
+    }
+}
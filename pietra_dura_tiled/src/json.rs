@@ -0,0 +1,398 @@
+use std::fs::{read_to_string, File};
+use std::path::{Path, PathBuf};
+
+use serde_json::{Map as JsonObject, Value};
+use tiled::{parse, parse_with_path};
+
+use crate::ldtk::{is_ldtk, ldtk_levels};
+use crate::model::read_layer_attributes;
+use crate::Map;
+
+/// Parses a map in either of Tiled's formats, TMX or JSON (`.tmj` or `.json`). LDtk
+/// projects (`.ldtk`) hold several levels, so are an error naming them which says to
+/// use `parse_ldtk_level` instead.
+///
+/// JSON maps are translated into TMX, with any external JSON tilesets inlined, and
+/// parsed by the `tiled` crate so they produce exactly the same `Map` as the
+/// equivalent TMX file would. TMX maps can use JSON tilesets (`.tsj` or `.json`)
+/// too, they are inlined the same way.
+pub fn parse_map(path: &Path) -> Result<Map, String> {
+    if is_ldtk(path) {
+        let levels = ldtk_levels(path)?;
+        Err(format!(
+            "{:?} is an LDtk project, parse one of its levels ({}) with parse_ldtk_level",
+            path, levels.join(", "),
+        ))
+    } else if is_json(path) {
+        let tmx = json_to_tmx(path)?;
+        parse_tmx(&tmx)
+    } else {
+        let tmx = read_to_string(path).map_err(|e| format!("Unable to read {:?}: {}", path, e))?;
+        let inlined = inline_json_tilesets(&tmx, path.parent().unwrap_or_else(|| Path::new("")))
+            .map_err(|e| format!("{:?}: {}", path, e))?;
+        // Parsed with the map's path so external TMX tilesets are found relative to it
+        let mut map = parse_with_path(inlined.as_bytes(), path).map(Map::from).map_err(|e| format!("{:?}", e))?;
+        read_layer_attributes(&mut map, &tmx);
+        Ok(map)
+    }
+}
+
+pub(crate) fn parse_tmx(tmx: &str) -> Result<Map, String> {
+    let mut map = parse(tmx.as_bytes()).map(Map::from).map_err(|e| format!("{:?}", e))?;
+    read_layer_attributes(&mut map, tmx);
+    Ok(map)
+}
+
+pub(crate) fn is_json(path: &Path) -> bool {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("json") | Some("tmj") | Some("tsj") | Some("ldtk") | Some("ldtkl") | Some("world") => true,
+        _ => false,
+    }
+}
+
+pub(crate) fn read_json(path: &Path) -> Result<Value, String> {
+    let f = File::open(path).map_err(|e| format!("Unable to open {:?}: {}", path, e))?;
+    serde_json::from_reader(f).map_err(|e| format!("Unable to parse {:?}: {}", path, e))
+}
+
+// Every file a JSON map, tileset, world or LDtk project references
+pub(crate) fn json_references(path: &Path) -> Vec<String> {
+    fn collect(value: &Value, references: &mut Vec<String>) {
+        match value {
+            Value::Object(object) => {
+                for (key, value) in object {
+                    match (key.as_str(), value) {
+                        ("source", Value::String(s))
+                        | ("image", Value::String(s))
+                        | ("relPath", Value::String(s))
+                        | ("externalRelPath", Value::String(s))
+                        | ("fileName", Value::String(s)) => references.push(s.clone()),
+                        _ => collect(value, references),
+                    }
+                }
+            },
+            Value::Array(values) => values.iter().for_each(|v| collect(v, references)),
+            _ => (),
+        }
+    }
+    let mut references = Vec::new();
+    if let Ok(value) = read_json(path) {
+        collect(&value, &mut references);
+    }
+    references
+}
+
+pub(crate) fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn field<'a>(object: &'a JsonObject<String, Value>, name: &str) -> Option<&'a Value> {
+    object.get(name).filter(|v| !v.is_null())
+}
+
+fn number(object: &JsonObject<String, Value>, name: &str, default: &str) -> String {
+    field(object, name).map(|v| v.to_string()).unwrap_or_else(|| default.to_string())
+}
+
+fn string(object: &JsonObject<String, Value>, name: &str) -> String {
+    field(object, name).and_then(Value::as_str).map(escape).unwrap_or_default()
+}
+
+fn visible(object: &JsonObject<String, Value>) -> &'static str {
+    if field(object, "visible").and_then(Value::as_bool).unwrap_or(true) { "1" } else { "0" }
+}
+
+fn as_object<'a>(value: &'a Value, what: &str) -> Result<&'a JsonObject<String, Value>, String> {
+    value.as_object().ok_or_else(|| format!("Expected {} to be an object", what))
+}
+
+fn json_to_tmx(path: &Path) -> Result<String, String> {
+    let json = read_json(path)?;
+    tiled_json_to_tmx(&json, path.parent().unwrap_or_else(|| Path::new("")))
+        .map_err(|e| format!("{:?}: {}", path, e))
+}
+
+// `map_dir` is the directory external tilesets are relative to
+pub(crate) fn tiled_json_to_tmx(json: &Value, map_dir: &Path) -> Result<String, String> {
+    let map = as_object(json, "map")?;
+    if field(map, "infinite").and_then(Value::as_bool).unwrap_or(false) {
+        return Err("infinite maps aren't supported".to_string());
+    }
+
+    let mut tmx = String::new();
+    tmx.push_str(&format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<map version=\"1.0\" orientation=\"{}\" width=\"{}\" height=\"{}\" tilewidth=\"{}\" tileheight=\"{}\"",
+        string(map, "orientation"),
+        number(map, "width", "0"),
+        number(map, "height", "0"),
+        number(map, "tilewidth", "0"),
+        number(map, "tileheight", "0"),
+    ));
+    if let Some(colour) = field(map, "backgroundcolor").and_then(Value::as_str) {
+        tmx.push_str(&format!(" backgroundcolor=\"{}\"", escape(colour)));
+    }
+    tmx.push_str(">\n");
+    write_properties(&mut tmx, map);
+
+    for tileset in field(map, "tilesets").and_then(Value::as_array).into_iter().flatten() {
+        let tileset = as_object(tileset, "tileset")?;
+        let first_gid = number(tileset, "firstgid", "1");
+        match field(tileset, "source").and_then(Value::as_str) {
+            Some(source) => {
+                let source_path = map_dir.join(source);
+                if !is_json(&source_path) {
+                    return Err(format!("Tileset {:?} isn't JSON, JSON maps can only use JSON tilesets", source_path));
+                }
+                let external = read_json(&source_path)?;
+                let external = as_object(&external, "tileset")?;
+                // Images are relative to the tileset but the inlined tileset is read
+                // relative to the map
+                let image_dir = Path::new(source).parent().map(Path::to_path_buf).unwrap_or_default();
+                write_tileset(&mut tmx, external, &first_gid, &image_dir)?;
+            },
+            None => write_tileset(&mut tmx, tileset, &first_gid, &PathBuf::new())?,
+        }
+    }
+
+    let mut layers = Vec::new();
+    flatten_layers(field(map, "layers"), &mut layers);
+    for layer in layers {
+        write_layer(&mut tmx, layer)?;
+    }
+
+    tmx.push_str("</map>\n");
+    Ok(tmx)
+}
+
+// The tiled crate only reads TMX tilesets, so JSON tilesets a TMX map refers to
+// are inlined into it
+fn inline_json_tilesets(tmx: &str, map_dir: &Path) -> Result<String, String> {
+    let mut inlined = String::with_capacity(tmx.len());
+    let mut rest = tmx;
+    while let Some(start) = rest.find("<tileset ") {
+        let end = match rest[start..].find('>') {
+            Some(end) => start + end + 1,
+            None => break,
+        };
+        let tag = &rest[start..end];
+        inlined.push_str(&rest[..start]);
+        match attribute(tag, "source") {
+            Some(source) if tag.ends_with("/>") && is_json(Path::new(source)) => {
+                let external = read_json(&map_dir.join(source))?;
+                let external = as_object(&external, "tileset")?;
+                let image_dir = Path::new(source).parent().map(Path::to_path_buf).unwrap_or_default();
+                write_tileset(&mut inlined, external, attribute(tag, "firstgid").unwrap_or("1"), &image_dir)?;
+            },
+            _ => inlined.push_str(tag),
+        }
+        rest = &rest[end..];
+    }
+    inlined.push_str(rest);
+    Ok(inlined)
+}
+
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let start = tag.find(&format!(" {}=\"", name))? + name.len() + 3;
+    let end = tag[start..].find('"')? + start;
+    Some(&tag[start..end])
+}
+
+// Group layers are flattened into their children
+fn flatten_layers<'a>(layers: Option<&'a Value>, flattened: &mut Vec<&'a JsonObject<String, Value>>) {
+    for layer in layers.and_then(Value::as_array).into_iter().flatten().filter_map(Value::as_object) {
+        if layer.get("type").and_then(Value::as_str) == Some("group") {
+            flatten_layers(layer.get("layers"), flattened);
+        } else {
+            flattened.push(layer);
+        }
+    }
+}
+
+fn write_properties(tmx: &mut String, object: &JsonObject<String, Value>) {
+    let properties = match field(object, "properties").and_then(Value::as_array) {
+        Some(properties) if !properties.is_empty() => properties,
+        _ => return,
+    };
+    tmx.push_str("<properties>\n");
+    for property in properties.iter().filter_map(Value::as_object) {
+        let kind = match property.get("type").and_then(Value::as_str) {
+            Some("bool") => "bool",
+            Some("float") => "float",
+            Some("int") => "int",
+            Some("color") => "color",
+            _ => "string",
+        };
+        let value = match property.get("value") {
+            Some(Value::String(s)) => escape(s),
+            Some(value) => value.to_string(),
+            None => String::new(),
+        };
+        tmx.push_str(&format!(
+            "<property name=\"{}\" type=\"{}\" value=\"{}\"/>\n",
+            string(property, "name"), kind, value,
+        ));
+    }
+    tmx.push_str("</properties>\n");
+}
+
+fn write_image(tmx: &mut String, object: &JsonObject<String, Value>, image_dir: &Path) {
+    if let Some(image) = field(object, "image").and_then(Value::as_str) {
+        let source = image_dir.join(image).to_string_lossy().replace('\\', "/");
+        tmx.push_str(&format!(
+            "<image source=\"{}\" width=\"{}\" height=\"{}\"/>\n",
+            escape(&source), number(object, "imagewidth", "0"), number(object, "imageheight", "0"),
+        ));
+    }
+}
+
+fn write_tileset(tmx: &mut String, tileset: &JsonObject<String, Value>, first_gid: &str, image_dir: &Path) -> Result<(), String> {
+    tmx.push_str(&format!(
+        "<tileset firstgid=\"{}\" name=\"{}\" tilewidth=\"{}\" tileheight=\"{}\" spacing=\"{}\" margin=\"{}\">\n",
+        first_gid,
+        string(tileset, "name"),
+        number(tileset, "tilewidth", "0"),
+        number(tileset, "tileheight", "0"),
+        number(tileset, "spacing", "0"),
+        number(tileset, "margin", "0"),
+    ));
+    write_properties(tmx, tileset);
+    write_image(tmx, tileset, image_dir);
+    for tile in field(tileset, "tiles").and_then(Value::as_array).into_iter().flatten() {
+        let tile = as_object(tile, "tile")?;
+        tmx.push_str(&format!("<tile id=\"{}\">\n", number(tile, "id", "0")));
+        write_properties(tmx, tile);
+        write_image(tmx, tile, image_dir);
+        if let Some(group) = field(tile, "objectgroup").and_then(Value::as_object) {
+            write_layer(tmx, group)?;
+        }
+        if let Some(frames) = field(tile, "animation").and_then(Value::as_array) {
+            tmx.push_str("<animation>\n");
+            for frame in frames.iter().filter_map(Value::as_object) {
+                tmx.push_str(&format!(
+                    "<frame tileid=\"{}\" duration=\"{}\"/>\n",
+                    number(frame, "tileid", "0"), number(frame, "duration", "0"),
+                ));
+            }
+            tmx.push_str("</animation>\n");
+        }
+        tmx.push_str("</tile>\n");
+    }
+    tmx.push_str("</tileset>\n");
+    Ok(())
+}
+
+fn write_layer(tmx: &mut String, layer: &JsonObject<String, Value>) -> Result<(), String> {
+    match layer.get("type").and_then(Value::as_str) {
+        Some("tilelayer") => {
+            tmx.push_str(&format!(
+                "<layer name=\"{}\" width=\"{}\" height=\"{}\" opacity=\"{}\" visible=\"{}\" parallaxx=\"{}\" parallaxy=\"{}\">\n",
+                string(layer, "name"),
+                number(layer, "width", "0"),
+                number(layer, "height", "0"),
+                number(layer, "opacity", "1"),
+                visible(layer),
+                number(layer, "parallaxx", "1"),
+                number(layer, "parallaxy", "1"),
+            ));
+            write_properties(tmx, layer);
+            match field(layer, "data") {
+                Some(Value::Array(gids)) => {
+                    let width = field(layer, "width").and_then(Value::as_u64).unwrap_or(0).max(1) as usize;
+                    let rows: Vec<String> = gids.chunks(width)
+                        .map(|row| row.iter().map(|gid| gid.to_string()).collect::<Vec<_>>().join(","))
+                        .collect();
+                    tmx.push_str(&format!("<data encoding=\"csv\">\n{}\n</data>\n", rows.join(",\n")));
+                },
+                Some(Value::String(data)) => {
+                    tmx.push_str("<data encoding=\"base64\"");
+                    if let Some(compression) = field(layer, "compression").and_then(Value::as_str) {
+                        if !compression.is_empty() {
+                            tmx.push_str(&format!(" compression=\"{}\"", escape(compression)));
+                        }
+                    }
+                    tmx.push_str(&format!(">{}</data>\n", data.trim()));
+                },
+                _ => return Err(format!("Layer {:?} has no tile data", layer.get("name"))),
+            }
+            tmx.push_str("</layer>\n");
+        },
+        Some("objectgroup") => {
+            tmx.push_str(&format!(
+                "<objectgroup name=\"{}\" opacity=\"{}\" visible=\"{}\" parallaxx=\"{}\" parallaxy=\"{}\"",
+                string(layer, "name"),
+                number(layer, "opacity", "1"),
+                visible(layer),
+                number(layer, "parallaxx", "1"),
+                number(layer, "parallaxy", "1"),
+            ));
+            if let Some(colour) = field(layer, "color").and_then(Value::as_str) {
+                tmx.push_str(&format!(" color=\"{}\"", escape(colour)));
+            }
+            tmx.push_str(">\n");
+            write_properties(tmx, layer);
+            for object in field(layer, "objects").and_then(Value::as_array).into_iter().flatten() {
+                write_object(tmx, as_object(object, "object")?);
+            }
+            tmx.push_str("</objectgroup>\n");
+        },
+        Some("imagelayer") => {
+            tmx.push_str(&format!(
+                "<imagelayer name=\"{}\" opacity=\"{}\" visible=\"{}\">\n",
+                string(layer, "name"),
+                number(layer, "opacity", "1"),
+                visible(layer),
+            ));
+            write_properties(tmx, layer);
+            write_image(tmx, layer, Path::new(""));
+            tmx.push_str("</imagelayer>\n");
+        },
+        // Tile collision shapes are object groups without a type
+        None => {
+            let mut group = layer.clone();
+            group.insert("type".to_string(), Value::String("objectgroup".to_string()));
+            write_layer(tmx, &group)?;
+        },
+        Some(other) => return Err(format!("Unknown layer type {:?}", other)),
+    }
+    Ok(())
+}
+
+fn write_object(tmx: &mut String, object: &JsonObject<String, Value>) {
+    tmx.push_str(&format!(
+        "<object id=\"{}\" name=\"{}\" type=\"{}\" x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" rotation=\"{}\" visible=\"{}\"",
+        number(object, "id", "0"),
+        string(object, "name"),
+        string(object, "type"),
+        number(object, "x", "0"),
+        number(object, "y", "0"),
+        number(object, "width", "0"),
+        number(object, "height", "0"),
+        number(object, "rotation", "0"),
+        visible(object),
+    ));
+    if let Some(gid) = field(object, "gid") {
+        tmx.push_str(&format!(" gid=\"{}\"", gid));
+    }
+    tmx.push_str(">\n");
+    write_properties(tmx, object);
+    let points = |name| field(object, name).and_then(Value::as_array).map(|points| {
+        points.iter()
+            .filter_map(Value::as_object)
+            .map(|p| format!("{},{}", number(p, "x", "0"), number(p, "y", "0")))
+            .collect::<Vec<_>>()
+            .join(" ")
+    });
+    if field(object, "ellipse").and_then(Value::as_bool).unwrap_or(false) {
+        tmx.push_str("<ellipse/>\n");
+    } else if field(object, "point").and_then(Value::as_bool).unwrap_or(false) {
+        tmx.push_str("<point/>\n");
+    } else if let Some(points) = points("polygon") {
+        tmx.push_str(&format!("<polygon points=\"{}\"/>\n", points));
+    } else if let Some(points) = points("polyline") {
+        tmx.push_str(&format!("<polyline points=\"{}\"/>\n", points));
+    }
+    tmx.push_str("</object>\n");
+}
@@ -0,0 +1,311 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use serde_json::{json, Map as JsonObject, Value};
+
+use crate::json::{parse_tmx, read_json, tiled_json_to_tmx};
+use crate::Map;
+
+// LDtk has no properties for its layers or objects, these are the names of the
+// properties its data is put into instead
+const INT_GRID_VALUE_PROPERTY: &str = "value";
+const IID_PROPERTY: &str = "iid";
+
+pub(crate) fn is_ldtk(path: &Path) -> bool {
+    path.extension().map(|e| e == "ldtk").unwrap_or(false)
+}
+
+/// The identifiers of every level in an LDtk project
+pub fn ldtk_levels(path: &Path) -> Result<Vec<String>, String> {
+    let project = read_json(path)?;
+    Ok(levels(&project).iter()
+        .filter_map(|l| l.get("identifier").and_then(Value::as_str))
+        .map(str::to_string)
+        .collect())
+}
+
+/// Parses a level of an LDtk project as though it were a Tiled map, so it can go
+/// through any `TiledConverter`. `level` is the level's identifier, or None for the
+/// first level.
+///
+/// * Tile, auto and IntGrid layers with tiles become tile layers.
+/// * IntGrid values become an object group named after the layer. Each run of a
+///   value along a row becomes a rectangle whose type is the value's identifier
+///   (or the value itself if it has none) with the value in its `value` property.
+/// * Entity layers become object groups. Each entity becomes an object whose name
+///   and type are the entity's identifier, with its fields as properties and its
+///   `iid` in the `iid` property. Entities with a tile become tile objects.
+/// * Level fields become map properties.
+///
+/// Layers are put in Tiled's bottom to top order. Flipped tiles aren't supported and
+/// are placed unflipped.
+pub fn parse_ldtk_level(path: &Path, level: Option<&str>) -> Result<Map, String> {
+    let project = read_json(path)?;
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let map = ldtk_to_tiled_json(&project, level, dir)
+        .map_err(|e| format!("{:?}: {}", path, e))?;
+    let tmx = tiled_json_to_tmx(&map, dir)
+        .map_err(|e| format!("{:?}: {}", path, e))?;
+    parse_tmx(&tmx)
+}
+
+fn levels(project: &Value) -> &[Value] {
+    project.get("levels").and_then(Value::as_array).map(Vec::as_slice).unwrap_or(&[])
+}
+
+fn array<'a>(value: &'a Value, name: &str) -> &'a [Value] {
+    value.get(name).and_then(Value::as_array).map(Vec::as_slice).unwrap_or(&[])
+}
+
+fn int(value: &Value, name: &str) -> i64 {
+    value.get(name).and_then(Value::as_f64).unwrap_or(0.0) as i64
+}
+
+fn pair(value: &Value, name: &str) -> (f64, f64) {
+    let values = array(value, name);
+    let get = |i: usize| values.get(i).and_then(Value::as_f64).unwrap_or(0.0);
+    (get(0), get(1))
+}
+
+struct TilesetInfo {
+    first_gid: i64,
+    columns: i64,
+    grid: i64,
+}
+
+impl TilesetInfo {
+    fn gid(&self, tile: &Value, src_name: &str) -> i64 {
+        let id = match tile.get("t").and_then(Value::as_i64) {
+            Some(id) => id,
+            None => {
+                let (x, y) = pair(tile, src_name);
+                (y as i64 / self.grid) * self.columns + x as i64 / self.grid
+            },
+        };
+        self.first_gid + id
+    }
+
+    // Entity tiles are rectangles in the tileset's image
+    fn gid_of_rect(&self, rect: &Value) -> i64 {
+        self.first_gid + (int(rect, "y") / self.grid) * self.columns + int(rect, "x") / self.grid
+    }
+}
+
+fn ldtk_to_tiled_json(project: &Value, level: Option<&str>, dir: &Path) -> Result<Value, String> {
+    let level = match level {
+        Some(identifier) => levels(project).iter()
+            .find(|l| l.get("identifier").and_then(Value::as_str) == Some(identifier))
+            .ok_or_else(|| format!("no level named {:?}", identifier))?,
+        None => levels(project).first().ok_or_else(|| "project has no levels".to_string())?,
+    };
+
+    // Levels can be saved in their own files
+    let external;
+    let layer_instances = match level.get("layerInstances").and_then(Value::as_array) {
+        Some(layers) => layers.as_slice(),
+        None => {
+            let relative = level.get("externalRelPath").and_then(Value::as_str)
+                .ok_or_else(|| "level has no layers".to_string())?;
+            external = read_json(&dir.join(relative))?;
+            array(&external, "layerInstances")
+        },
+    };
+
+    let defs = project.get("defs").cloned().unwrap_or(Value::Null);
+    let mut tileset_infos = HashMap::new();
+    let mut tilesets = Vec::new();
+    let mut next_gid = 1;
+    for tileset in array(&defs, "tilesets") {
+        let image = match tileset.get("relPath").and_then(Value::as_str) {
+            Some(image) => image,
+            None => continue,
+        };
+        let grid = int(tileset, "tileGridSize").max(1);
+        let columns = int(tileset, "pxWid") / grid;
+        let rows = int(tileset, "pxHei") / grid;
+        tilesets.push(json!({
+            "firstgid": next_gid,
+            "name": tileset.get("identifier").cloned().unwrap_or(Value::Null),
+            "tilewidth": grid,
+            "tileheight": grid,
+            "spacing": int(tileset, "spacing"),
+            "margin": int(tileset, "padding"),
+            "image": image,
+            "imagewidth": int(tileset, "pxWid"),
+            "imageheight": int(tileset, "pxHei"),
+        }));
+        tileset_infos.insert(int(tileset, "uid"), TilesetInfo { first_gid: next_gid, columns, grid });
+        next_gid += columns * rows;
+    }
+
+    let int_grid_names: HashMap<(i64, i64), String> = array(&defs, "layers").iter()
+        .flat_map(|layer| {
+            let uid = int(layer, "uid");
+            array(layer, "intGridValues").iter().filter_map(move |v| {
+                let name = v.get("identifier").and_then(Value::as_str)?;
+                Some(((uid, int(v, "value")), name.to_string()))
+            })
+        })
+        .collect();
+
+    let grid = layer_instances.iter()
+        .find(|l| l.get("__type").and_then(Value::as_str) != Some("Entities"))
+        .map(|l| int(l, "__gridSize"))
+        .unwrap_or_else(|| int(project, "defaultGridSize"))
+        .max(1);
+    let width = int(level, "pxWid") / grid;
+    let height = int(level, "pxHei") / grid;
+
+    let mut layers = Vec::new();
+    let mut next_object_id = 1;
+    // LDtk lists layers top first
+    for layer in layer_instances.iter().rev() {
+        let name = layer.get("__identifier").cloned().unwrap_or(Value::Null);
+        let opacity = layer.get("__opacity").cloned().unwrap_or_else(|| json!(1));
+        let visible = layer.get("visible").cloned().unwrap_or(Value::Bool(true));
+        let kind = layer.get("__type").and_then(Value::as_str).unwrap_or("");
+
+        if kind != "Entities" && int(layer, "__gridSize") != grid {
+            return Err(format!("layer {} has a different grid size to the rest of the level", name));
+        }
+
+        let tiles = match kind {
+            "Tiles" => array(layer, "gridTiles"),
+            _ => array(layer, "autoLayerTiles"),
+        };
+        if !tiles.is_empty() {
+            let tileset = layer.get("__tilesetDefUid").and_then(Value::as_i64)
+                .and_then(|uid| tileset_infos.get(&uid))
+                .ok_or_else(|| format!("layer {} has tiles but no tileset", name))?;
+            let mut data = vec![0; (width * height) as usize];
+            for tile in tiles {
+                let (px, py) = pair(tile, "px");
+                let (x, y) = (px as i64 / grid, py as i64 / grid);
+                if x >= 0 && y >= 0 && x < width && y < height {
+                    data[(y * width + x) as usize] = tileset.gid(tile, "src");
+                }
+            }
+            layers.push(json!({
+                "type": "tilelayer",
+                "name": name,
+                "width": width,
+                "height": height,
+                "opacity": opacity,
+                "visible": visible,
+                "data": data,
+            }));
+        }
+
+        let mut objects = Vec::new();
+        if kind == "IntGrid" {
+            let layer_uid = int(layer, "layerDefUid");
+            let values = array(layer, "intGridCsv");
+            let columns = int(layer, "__cWid").max(1) as usize;
+            for (y, row) in values.chunks(columns).enumerate() {
+                let mut x = 0;
+                while x < row.len() {
+                    let value = row[x].as_i64().unwrap_or(0);
+                    let run = row[x..].iter().take_while(|v| v.as_i64() == Some(value)).count();
+                    if value != 0 {
+                        let object_type = int_grid_names.get(&(layer_uid, value))
+                            .cloned()
+                            .unwrap_or_else(|| value.to_string());
+                        objects.push(json!({
+                            "id": next_object_id,
+                            "name": "",
+                            "type": object_type,
+                            "x": x as i64 * grid,
+                            "y": y as i64 * grid,
+                            "width": run as i64 * grid,
+                            "height": grid,
+                            "properties": [{ "name": INT_GRID_VALUE_PROPERTY, "type": "int", "value": value }],
+                        }));
+                        next_object_id += 1;
+                    }
+                    x += run;
+                }
+            }
+        }
+
+        for entity in array(layer, "entityInstances") {
+            let (px, py) = pair(entity, "px");
+            let (pivot_x, pivot_y) = pair(entity, "__pivot");
+            let (entity_width, entity_height) = (int(entity, "width") as f64, int(entity, "height") as f64);
+            let x = px - pivot_x * entity_width;
+            let mut y = py - pivot_y * entity_height;
+            let identifier = entity.get("__identifier").cloned().unwrap_or(Value::Null);
+            let mut properties = field_properties(array(entity, "fieldInstances"));
+            if let Some(iid) = entity.get("iid") {
+                properties.push(json!({ "name": IID_PROPERTY, "type": "string", "value": iid }));
+            }
+            let mut object = json!({
+                "id": next_object_id,
+                "name": identifier,
+                "type": identifier,
+                "x": x,
+                "width": entity_width,
+                "height": entity_height,
+                "properties": properties,
+            });
+            let tile = entity.get("__tile").filter(|t| !t.is_null());
+            let tileset = tile
+                .and_then(|t| t.get("tilesetUid"))
+                .and_then(Value::as_i64)
+                .and_then(|uid| tileset_infos.get(&uid));
+            if let (Some(tile), Some(tileset)) = (tile, tileset) {
+                object["gid"] = json!(tileset.gid_of_rect(tile));
+                // Tile objects are positioned by their bottom left corner
+                y += entity_height;
+            }
+            object["y"] = json!(y);
+            objects.push(object);
+            next_object_id += 1;
+        }
+
+        if !objects.is_empty() || kind == "Entities" {
+            layers.push(json!({
+                "type": "objectgroup",
+                "name": name,
+                "opacity": opacity,
+                "visible": visible,
+                "objects": objects,
+            }));
+        }
+    }
+
+    let mut map = JsonObject::new();
+    map.insert("orientation".to_string(), json!("orthogonal"));
+    map.insert("width".to_string(), json!(width));
+    map.insert("height".to_string(), json!(height));
+    map.insert("tilewidth".to_string(), json!(grid));
+    map.insert("tileheight".to_string(), json!(grid));
+    if let Some(colour) = level.get("__bgColor").and_then(Value::as_str) {
+        map.insert("backgroundcolor".to_string(), json!(colour));
+    }
+    map.insert("properties".to_string(), Value::Array(field_properties(array(level, "fieldInstances"))));
+    map.insert("tilesets".to_string(), Value::Array(tilesets));
+    map.insert("layers".to_string(), Value::Array(layers));
+    Ok(Value::Object(map))
+}
+
+// Fields with types Tiled properties can't hold, like arrays and points, are left out
+fn field_properties(fields: &[Value]) -> Vec<Value> {
+    fields.iter()
+        .filter_map(|field| {
+            let name = field.get("__identifier")?;
+            let value = field.get("__value").filter(|v| !v.is_null())?;
+            let field_type = field.get("__type").and_then(Value::as_str).unwrap_or("");
+            let (property_type, value) = match field_type {
+                "Int" => ("int", value.clone()),
+                "Float" => ("float", value.clone()),
+                "Bool" => ("bool", value.clone()),
+                // LDtk colours have no alpha
+                "Color" => ("color", json!(format!("#ff{}", value.as_str()?.trim_start_matches('#')))),
+                "String" | "Multilines" | "FilePath" => ("string", value.clone()),
+                t if t.starts_with("LocalEnum.") || t.starts_with("ExternEnum.") => ("string", value.clone()),
+                _ => return None,
+            };
+            Some(json!({ "name": name, "type": property_type, "value": value }))
+        })
+        .collect()
+}
@@ -1,6 +1,28 @@
 mod prefab_proxies;
-
-use std::collections::{HashSet, HashMap};
+mod model;
+mod editing;
+mod navigation;
+mod mapping;
+mod detail;
+mod format;
+mod hot_reload;
+mod build_cache;
+mod validate;
+mod output;
+mod archive;
+mod plan;
+mod json;
+mod ldtk;
+mod builder;
+mod export;
+mod world;
+mod streaming;
+mod parallax;
+mod depth;
+mod objects;
+mod loading;
+
+use std::collections::{BTreeMap, HashSet};
 use image::{
     png::PNGEncoder,
     ColorType,
@@ -11,7 +33,7 @@ use std::fs::File;
 use std::io::Result;
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
-use std::fs::{copy, create_dir_all};
+use std::fs::{copy, create_dir_all, read, read_to_string, rename};
 
 use amethyst::{
     renderer::{
@@ -32,8 +54,33 @@ use sheep::{AmethystFormat, InputSprite, SimplePacker};
 
 
 pub use prefab_proxies::*;
+pub use model::*;
+pub use editing::*;
+pub use navigation::*;
+pub use mapping::*;
+pub use detail::*;
+pub use format::*;
+pub use hot_reload::*;
+pub use build_cache::*;
+pub use validate::*;
+pub use output::*;
+pub use archive::ArchiveSource;
+pub use plan::*;
+pub use json::parse_map;
+pub use ldtk::{ldtk_levels, parse_ldtk_level};
+pub use builder::*;
+pub use export::*;
+pub use world::*;
+pub use streaming::*;
+pub use parallax::*;
+pub use depth::*;
+pub use objects::*;
+pub use loading::*;
+
+pub mod derive {
+    pub use pietra_dura_tiled_derive::{FromTiledObject, PrefabProxy, TiledConverter, TiledDetail};
+}
 
-use tiled::{Object, Map, parse_file, ObjectGroup};
 
 pub struct SpriteContext {
     pub sprite_sheet: Option<SpriteSheetPrefab>,
@@ -45,10 +92,8 @@ pub struct SpriteContext {
 }
 
 impl SpriteContext {
-    pub fn from_gid(gid: u32, map: &Map, gid_map: &HashMap<usize, (usize, usize)>, sprite_sheets: &Vec<SpriteSheetPrefab>, used_sprite_sheets: &HashSet<u32>) -> Option<Self> {
+    pub fn from_gid(gid: u32, map: &Map, gid_map: &BTreeMap<usize, (usize, usize)>, sprite_sheets: &Vec<SpriteSheetPrefab>, used_sprite_sheets: &HashSet<u32>) -> Option<Self> {
         if gid != 0 {
-            let mut keys: Vec<_> = gid_map.keys().collect();
-            keys.sort();
             let (sprite_sheet_id, sprite_id) = &gid_map[&(gid as usize)];
             let map_tileset = &map.tilesets[*sprite_sheet_id];
             let sprite_sheet = &sprite_sheets[*sprite_sheet_id as usize];
@@ -79,8 +124,131 @@ pub struct MapContext<P> {
     pub map: Map,
     pub sprite_sheets: Vec<SpriteSheetPrefab>,
     pub used_sprite_sheets: HashSet<u32>,
-    pub gid_map: HashMap<usize, (usize, usize)>,
+    pub gid_map: BTreeMap<usize, (usize, usize)>,
     pub entities: Vec<PrefabEntity<P>>,
+    pub object_mapping: Option<ObjectMapping>,
+    pub warnings: Vec<String>,
+    // Size of a region in pixels when the map is being split into regions
+    pub region_size: Option<(f32, f32)>,
+    pub region_sprite_sheets: BTreeMap<(i32, i32), HashSet<u32>>,
+    // The region of each entity, by index. Entities without one, including any
+    // pushed straight onto `entities`, stay in the map's own prefab.
+    pub entity_regions: Vec<Option<(i32, i32)>>,
+    pub depth: DepthSettings,
+    // `ConversionSettings::offset`, which objects have already been moved by
+    pub offset: (f32, f32),
+}
+
+impl<P> MapContext<P> {
+    /// The region a point falls in, in pixels with y going down as in Tiled, or None
+    /// if the map isn't being split into regions.
+    pub fn region_at(&self, x: f32, y: f32) -> Option<(i32, i32)> {
+        let (width, height) = self.region_size?;
+        Some(((x / width).floor() as i32, (y / height).floor() as i32))
+    }
+
+    // Sprite sheets are included with the first entity to use them in each prefab,
+    // so which have been used is tracked for every region
+    pub fn sprite_context(&self, gid: u32, region: Option<(i32, i32)>) -> Option<SpriteContext> {
+        let unused = HashSet::new();
+        let used = match region {
+            Some(region) => self.region_sprite_sheets.get(&region).unwrap_or(&unused),
+            None => &self.used_sprite_sheets,
+        };
+        SpriteContext::from_gid(gid, &self.map, &self.gid_map, &self.sprite_sheets, used)
+    }
+
+    pub fn push_entity(&mut self, entity: P, ctx: &Option<SpriteContext>, region: Option<(i32, i32)>) {
+        if let Some(ctx) = ctx {
+            match region {
+                Some(region) => self.region_sprite_sheets.entry(region).or_default().insert(ctx.sprite_sheet_id),
+                None => self.used_sprite_sheets.insert(ctx.sprite_sheet_id),
+            };
+        }
+        self.entity_regions.resize(self.entities.len(), None);
+        self.entities.push(PrefabEntity::new(entity));
+        self.entity_regions.push(region);
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MapStats {
+    pub entities: usize,
+    // Entities by `TiledConverter::entity_kind`
+    pub entity_kinds: BTreeMap<String, usize>,
+    pub sprite_sheets: usize,
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ConversionSettings {
+    // Object types found in the mapping are converted through
+    // `TiledConverter::convert_mapped_object` instead of `convert_object`
+    pub object_mapping: Option<ObjectMapping>,
+    pub output_format: OutputFormat,
+    // Added to the position of every tile and object, in map pixels, to place the
+    // map within a world
+    pub offset: (f32, f32),
+    // Where sprite sheet textures go, relative to the assets directory, when they
+    // are shared between maps. None keeps them with the map.
+    pub texture_prefix: Option<PathBuf>,
+    // Splits the map into regions this many tiles across, each written as its own
+    // prefab so they can be streamed in and out by `RegionStreamingSystem`
+    pub region_size: Option<(u32, u32)>,
+    pub depth: DepthSettings,
+    // Where packed sheets of image collection tilesets are kept between conversions.
+    // They are named after a hash of their images, so can be shared by every map.
+    pub sheet_cache: Option<PathBuf>,
+}
+
+impl ConversionSettings {
+    pub fn with_object_mapping(mut self, object_mapping: ObjectMapping) -> Self {
+        self.object_mapping = Some(object_mapping);
+        self
+    }
+
+    pub fn with_output_format(mut self, output_format: OutputFormat) -> Self {
+        self.output_format = output_format;
+        self
+    }
+
+    pub fn with_offset(mut self, offset: (f32, f32)) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    pub fn with_texture_prefix<P: Into<PathBuf>>(mut self, texture_prefix: P) -> Self {
+        self.texture_prefix = Some(texture_prefix.into());
+        self
+    }
+
+    pub fn with_regions(mut self, width: u32, height: u32) -> Self {
+        self.region_size = Some((width, height));
+        self
+    }
+
+    pub fn with_depth(mut self, depth: DepthSettings) -> Self {
+        self.depth = depth;
+        self
+    }
+
+    pub fn with_sheet_cache<P: Into<PathBuf>>(mut self, sheet_cache: P) -> Self {
+        self.sheet_cache = Some(sheet_cache.into());
+        self
+    }
+
+    // Everything which changes the output, written the same way every time. The
+    // sheet cache only changes how long conversion takes so is left out.
+    pub fn cache_key(&self) -> String {
+        let mapping: BTreeMap<_, _> = self.object_mapping.iter()
+            .flat_map(|mapping| mapping.objects.iter())
+            .map(|(object_type, rule)| (object_type, (rule.requires_sprite, &rule.detail)))
+            .collect();
+        format!(
+            "{:?} {:?} {:?} {:?} {:?} {:?}",
+            self.output_format, self.offset, self.texture_prefix, self.region_size, self.depth, mapping,
+        )
+    }
 }
 
 pub enum MapFile {
@@ -94,17 +262,126 @@ pub trait TiledConverter<'s, P>
         fn convert_tile(ctx: &Option<SpriteContext>, x: f32, y: f32, layer: usize) -> Option<Self::PrefabProxy>;
         fn convert_object(ctx: &Option<SpriteContext>, layer: usize, object: &Object) -> Option<Self::PrefabProxy>;
 
+        // Only called when an `ObjectMapping` is in use. The default ignores mapped
+        // objects. Errors, such as a detail which doesn't match the prefab's, become
+        // conversion warnings.
+        fn convert_mapped_object(_ctx: &Option<SpriteContext>, _layer: usize, _object: &Object, _mapped: MappedObject) -> std::result::Result<Option<Self::PrefabProxy>, String> {
+            Ok(None)
+        }
+
+        fn convert_tile_at_cell(ctx: &Option<SpriteContext>, cell: TileCell, x: f32, y: f32) -> Option<Self::PrefabProxy> {
+            Self::convert_tile(ctx, x, y, cell.layer)
+        }
+
+        // Called on whatever was converted from a layer or object group with parallax
+        // factors other than 1. The default leaves them out.
+        fn with_parallax(proxy: Self::PrefabProxy, _parallax: Parallax) -> Self::PrefabProxy {
+            proxy
+        }
+
+        // Called on everything converted with the z `ConversionSettings::depth` gives
+        // it. The default keeps the z it was converted with.
+        fn with_z(proxy: Self::PrefabProxy, _z: f32) -> Self::PrefabProxy {
+            proxy
+        }
+
+        // Called on objects from y sorted object groups, which keep being sorted as
+        // they move. The default leaves it out.
+        fn with_y_sort(proxy: Self::PrefabProxy, _y_sort: YSort) -> Self::PrefabProxy {
+            proxy
+        }
+
+        // Called on everything converted from an object, to keep its name and type so
+        // it can be found through the `ObjectIndex`. The default drops them.
+        fn with_object_names(proxy: Self::PrefabProxy, _object: &Object) -> Self::PrefabProxy {
+            proxy
+        }
+
+        // Called on objects drawn as rectangles, rather than tile objects which can
+        // move, with the tile cells under them, so colliders made from them can be
+        // rebuilt by `TileEditSystem` when those tiles are edited. The default leaves
+        // them out.
+        fn with_collider_cells(proxy: Self::PrefabProxy, _object: &Object, _cells: ColliderCells) -> Self::PrefabProxy {
+            proxy
+        }
+
         fn base_convert_object_group(map_context: &mut MapContext<Self::PrefabProxy>, group_id: usize) {
-            let group = &map_context.map.object_groups[group_id];
-            for object in &group.objects {
-                let ctx = SpriteContext::from_gid(object.gid, &map_context.map, &map_context.gid_map, &map_context.sprite_sheets, &map_context.used_sprite_sheets);
-                if let Some(object) = Self::convert_object(&ctx, group_id, object) {
-                    if let Some(ctx) = ctx {
-                        map_context.used_sprite_sheets.insert(ctx.sprite_sheet_id);
+            let depth = map_context.depth.group_depth(&map_context.map, group_id, map_context.offset.1);
+            for index in 0..map_context.map.object_groups[group_id].objects.len() {
+                let object = &map_context.map.object_groups[group_id].objects[index];
+                let region = map_context.region_at(object.x, object.y);
+                let ctx = map_context.sprite_context(object.gid, region);
+                let mapped = match &map_context.object_mapping {
+                    Some(mapping) => mapping.convert(&ctx, group_id, object),
+                    None => Ok(None),
+                };
+                let converted = mapped.and_then(|mapped| match mapped {
+                    Some(mapped) => Self::convert_mapped_object(&ctx, group_id, object, mapped),
+                    None => Ok(Self::convert_object(&ctx, group_id, object)),
+                });
+                let converted = match converted {
+                    Ok(converted) => converted,
+                    Err(e) => {
+                        let warning = format!(
+                            "Object {} of type {:?} in group {:?} was not converted: {}",
+                            object.id, object.obj_type, map_context.map.object_groups[group_id].name, e,
+                        );
+                        map_context.warnings.push(warning);
+                        continue;
+                    },
+                };
+                let (parallax_x, parallax_y) = map_context.map.object_groups[group_id].parallax;
+                let converted = match converted {
+                    Some(converted) if (parallax_x, parallax_y) != (1.0, 1.0) => {
+                        Some(Self::with_parallax(converted, Parallax::new(parallax_x, parallax_y)))
+                    },
+                    converted => converted,
+                };
+                let bottom = object_bottom(object);
+                let collider_cells = match object.shape {
+                    ObjectShape::Rect { width, height } if object.gid == 0 => {
+                        let cells = ColliderCells::from_rect(
+                            object.x - map_context.offset.0,
+                            object.y - map_context.offset.1,
+                            width,
+                            height,
+                            map_context.map.tile_width,
+                            map_context.map.tile_height,
+                        ).with_offset(map_context.offset);
+                        // The first tile layer with tiles under the rectangle is the one it stands on
+                        let layer = map_context.map.layers.iter().position(|layer| {
+                            cells.positions().any(|(x, y)| {
+                                layer.tiles.get(y).and_then(|row| row.get(x)).map_or(false, |gid| *gid != 0)
+                            })
+                        });
+                        Some(cells.with_layer(layer.unwrap_or(0)))
+                    },
+                    _ => None,
+                };
+                let converted = converted.map(|converted| {
+                    let converted = Self::with_object_names(converted, object);
+                    let converted = match collider_cells {
+                        Some(cells) => Self::with_collider_cells(converted, object, cells),
+                        None => converted,
+                    };
+                    let converted = Self::with_z(converted, depth.z(bottom));
+                    match depth.y_sort {
+                        Some(y_sort) => {
+                            let anchor = object_transform(object, group_id).translation().y - bottom;
+                            Self::with_y_sort(converted, y_sort.with_anchor(anchor))
+                        },
+                        None => converted,
                     }
-                    map_context.entities.push(
-                        PrefabEntity { data: Some(object) }
-                    );
+                });
+                match converted {
+                    Some(converted) => map_context.push_entity(converted, &ctx, region),
+                    None => {
+                        let warning = format!(
+                            "Object {} of type {:?} in group {:?} was not converted",
+                            object.id, object.obj_type, map_context.map.object_groups[group_id].name,
+                        );
+                        map_context.warnings.push(warning);
+                    },
                 }
             }
         }
@@ -113,12 +390,106 @@ pub trait TiledConverter<'s, P>
             Self::base_convert_object_group(map_context, group_id);
         }
 
+        // Used to count entities by kind in `MapStats` and `WritePlan`
+        fn entity_kind(_proxy: &Self::PrefabProxy) -> &'static str {
+            "entity"
+        }
+
+        // The navigation grid written next to the map prefab as `nav.ron`. Return None to skip it.
+        fn build_navigation(map: &Map) -> Option<NavGrid> {
+            Some(NavGrid::from_map(map, &NavSettings::default()))
+        }
+
+        // Recorded in the build manifest so changing the converter or its settings
+        // converts maps again
+        fn conversion_key(settings: &ConversionSettings) -> String {
+            format!("{} {}", std::any::type_name::<Self>(), settings.cache_key())
+        }
+
+        // What `validate` checks maps against. The default doesn't know which object
+        // types are handled so only checks tiles, images and property types.
+        fn validation_rules() -> ValidationRules {
+            ValidationRules::default()
+        }
+
+        fn validate(input: &Path, settings: &ConversionSettings) -> ValidationReport {
+            let map = match parse_map(input) {
+                Ok(map) => map,
+                Err(e) => return ValidationReport::parse_error(e),
+            };
+            let mut rules = Self::validation_rules();
+            if let Some(mapping) = &settings.object_mapping {
+                rules.add_mapping(mapping);
+            }
+            validate_map(&map, &rules)
+        }
+
         fn from_map(input: &Path, map_prefix: &Path) -> MapPrefab<P, Self::PrefabProxy> {
-            let input_dir = input.parent().unwrap();
-            let map = parse_file(input).unwrap();
+            Self::from_map_with_settings(input, map_prefix, &ConversionSettings::default())
+        }
+
+        fn from_map_with_settings(input: &Path, map_prefix: &Path, settings: &ConversionSettings) -> MapPrefab<P, Self::PrefabProxy> {
+            let map = parse_map(input).unwrap_or_else(|e| panic!("Unable to parse {:?}: {}", input, e));
+            Self::from_parsed_map(map, input, map_prefix, settings)
+        }
+
+        // `level` is the level's identifier, or None for the first level
+        fn from_ldtk_level(input: &Path, level: Option<&str>, map_prefix: &Path, settings: &ConversionSettings) -> MapPrefab<P, Self::PrefabProxy> {
+            let map = parse_ldtk_level(input, level).unwrap_or_else(|e| panic!("Unable to parse {:?}: {}", input, e));
+            Self::from_parsed_map(map, input, map_prefix, settings)
+        }
+
+        // Converts every map in a `.world` file, each into `world_prefix` under its own
+        // name and offset to its place in the world
+        fn from_world(input: &Path, world_prefix: &Path, settings: &ConversionSettings) -> WorldPrefab<P, Self::PrefabProxy> {
+            let world = parse_world(input).unwrap_or_else(|e| panic!("Unable to parse {:?}: {}", input, e));
+            let world_dir = input.parent().unwrap();
+            let mut world_prefab = WorldPrefab::new(world_prefix);
+            for world_map in &world.maps {
+                let settings = settings.clone()
+                    .with_offset((world_map.x as f32, world_map.y as f32))
+                    .with_texture_prefix(world_prefab.texture_prefix());
+                let mut map = Self::from_map_with_settings(
+                    &world_dir.join(&world_map.file_name),
+                    &world_prefix.join(world_map.name()),
+                    &settings,
+                );
+                map.add_input(input);
+                world_prefab.add_map(world_map, map);
+            }
+            world_prefab
+        }
 
-            let (mut sprite_sheets, gid_map) = sprite_sheets_from_tilesets(&map, &input_dir, map_prefix);
-            let (mut sprite_files, sprite_sheets): (Vec<MapFile>, Vec<SpriteSheetPrefab>) = sprite_sheets.drain(..).unzip();
+        // `input` is the file the map was read from, images are found relative to it
+        fn from_parsed_map(map: Map, input: &Path, map_prefix: &Path, settings: &ConversionSettings) -> MapPrefab<P, Self::PrefabProxy> {
+            let mut map_prefab = Self::from_map_data(map, input.parent().unwrap(), map_prefix, settings);
+            for dependency in map_dependencies(input) {
+                map_prefab.add_input(dependency);
+            }
+            map_prefab
+        }
+
+        // Converts a map which wasn't read from a file, such as one made with a
+        // `MapBuilder`. Image paths in its tilesets are relative to `images_dir`.
+        fn from_map_data(mut map: Map, images_dir: &Path, map_prefix: &Path, settings: &ConversionSettings) -> MapPrefab<P, Self::PrefabProxy> {
+            let texture_prefix = settings.texture_prefix.as_ref().map(PathBuf::as_path);
+            let sheet_cache = settings.sheet_cache.as_ref().map(PathBuf::as_path);
+            let (mut sprite_sheets, gid_map) = sprite_sheets_with_cache(&map, images_dir, map_prefix, texture_prefix, sheet_cache);
+            let (sprite_files, sprite_sheets): (Vec<MapFile>, Vec<SpriteSheetPrefab>) = sprite_sheets.drain(..).unzip();
+            let (mut files, shared_files) = match texture_prefix {
+                Some(_) => (Vec::new(), sprite_files),
+                None => (sprite_files, Vec::new()),
+            };
+
+            // The navigation grid stays in the map's own coordinates
+            let nav_grid = Self::build_navigation(&map);
+            let (offset_x, offset_y) = settings.offset;
+            for group in &mut map.object_groups {
+                for object in &mut group.objects {
+                    object.x += offset_x;
+                    object.y += offset_y;
+                }
+            }
 
             let mut map_context = MapContext {
                 map,
@@ -126,19 +497,37 @@ pub trait TiledConverter<'s, P>
                 used_sprite_sheets: HashSet::new(),
                 gid_map,
                 entities: Vec::new(),
+                object_mapping: settings.object_mapping.clone(),
+                warnings: Vec::new(),
+                region_size: None,
+                region_sprite_sheets: BTreeMap::new(),
+                entity_regions: Vec::new(),
+                depth: settings.depth.clone(),
+                offset: settings.offset,
             };
-
-            for (z, layer) in map_context.map.layers.iter().enumerate() {
-                for (y, row) in layer.tiles.iter().enumerate() {
-                    for (x, gid) in row.iter().enumerate() {
-                        let ctx = SpriteContext::from_gid(*gid, &map_context.map, &map_context.gid_map, &map_context.sprite_sheets, &map_context.used_sprite_sheets);
-                        if let Some(tile) = Self::convert_tile(&ctx, x as f32 * map_context.map.tile_width as f32 + map_context.map.tile_width as f32 / 2.0, y as f32 * -(map_context.map.tile_height as f32) - map_context.map.tile_height as f32 / 2.0, z) {
-                            if let Some(ctx) = ctx {
-                                map_context.used_sprite_sheets.insert(ctx.sprite_sheet_id);
+            let (tile_width, tile_height) = (map_context.map.tile_width, map_context.map.tile_height);
+            map_context.region_size = settings.region_size
+                .map(|(width, height)| ((width * tile_width) as f32, (height * tile_height) as f32));
+
+            for z in 0..map_context.map.layers.len() {
+                let (parallax_x, parallax_y) = map_context.map.layers[z].parallax;
+                let depth = settings.depth.layer_depth(&map_context.map, z, offset_y);
+                for y in 0..map_context.map.layers[z].tiles.len() {
+                    for x in 0..map_context.map.layers[z].tiles[y].len() {
+                        let gid = map_context.map.layers[z].tiles[y][x];
+                        let cell = TileCell::new(z, x, y);
+                        let (px, py) = cell.position(tile_width, tile_height);
+                        // Positions from `TileCell` have y going up
+                        let (px, py) = (px + offset_x, py - offset_y);
+                        let region = map_context.region_at(px, -py);
+                        let ctx = map_context.sprite_context(gid, region);
+                        if let Some(mut tile) = Self::convert_tile_at_cell(&ctx, cell, px, py) {
+                            if (parallax_x, parallax_y) != (1.0, 1.0) {
+                                tile = Self::with_parallax(tile, Parallax::new(parallax_x, parallax_y));
                             }
-                            map_context.entities.push(
-                                PrefabEntity { data: Some(tile) }
-                            );
+                            // Tiles don't move, so are only sorted here
+                            tile = Self::with_z(tile, depth.z(py - tile_height as f32 / 2.0));
+                            map_context.push_entity(tile, &ctx, region);
                         }
                     }
                 }
@@ -149,12 +538,65 @@ pub trait TiledConverter<'s, P>
             }
 
 
-            let map = Prefab { entities: map_context.entities }; 
-            let buffer = ron::ser::to_string_pretty(&map, ron::ser::PrettyConfig::default())
-                .expect("Failed to encode map prefab file");
-            sprite_files.push(MapFile::Data(PathBuf::from("map.ron"), buffer.into_bytes()));
+            if let Some(nav_grid) = nav_grid {
+                let buffer = ron::ser::to_string_pretty(&nav_grid, ron::ser::PrettyConfig::default())
+                    .expect("Failed to encode navigation grid");
+                files.push(MapFile::Data(PathBuf::from("nav.ron"), buffer.into_bytes()));
+            }
 
-            MapPrefab::new(sprite_files)
+            // Everything hangs off an empty root entity, which is the entity the prefab
+            // handle is attached to, so the whole level can be found and removed later.
+            let mut entity_kinds = BTreeMap::new();
+            for entity in &map_context.entities {
+                if let Some(data) = &entity.data {
+                    *entity_kinds.entry(Self::entity_kind(data).to_string()).or_insert(0) += 1;
+                }
+            }
+            let entity_count = map_context.entities.len();
+            let root = || PrefabEntity { parent: None, data: None };
+            let mut entities = Vec::with_capacity(entity_count + 1);
+            entities.push(root());
+            // Each region is a prefab of its own, with its own root
+            let mut regions = BTreeMap::new();
+            for (i, mut entity) in map_context.entities.drain(..).enumerate() {
+                entity.parent = Some(0);
+                match map_context.entity_regions.get(i).and_then(|r| *r) {
+                    Some(region) => regions.entry(region).or_insert_with(|| vec![root()]).push(entity),
+                    None => entities.push(entity),
+                }
+            }
+            if let Some((width, height)) = map_context.region_size {
+                let mut manifest = RegionManifest { region_width: width, region_height: height, regions: Vec::new() };
+                for ((x, y), entities) in regions {
+                    let dest = PathBuf::from(REGION_DIR).join(format!("{}_{}.{}", x, y, settings.output_format.extension()));
+                    manifest.regions.push(MapRegion {
+                        x,
+                        y,
+                        prefab: path_key(&map_prefix.join(&dest)),
+                    });
+                    files.push(MapFile::Data(dest, settings.output_format.encode(&Prefab { entities })));
+                }
+                let buffer = ron::ser::to_string_pretty(&manifest, ron::ser::PrettyConfig::default())
+                    .expect("Failed to encode region manifest");
+                files.push(MapFile::Data(PathBuf::from(REGION_MANIFEST_FILE), buffer.into_bytes()));
+            }
+            let map = Prefab { entities };
+            let buffer = settings.output_format.encode(&map);
+            files.push(MapFile::Data(PathBuf::from(settings.output_format.prefab_file()), buffer));
+
+            let stats = MapStats {
+                entities: entity_count,
+                entity_kinds,
+                sprite_sheets: map_context.sprite_sheets.len(),
+                warnings: map_context.warnings,
+            };
+            let mut map_prefab = MapPrefab::new(files);
+            map_prefab.shared_files = shared_files;
+            map_prefab.stats = stats;
+            map_prefab.output_format = settings.output_format;
+            map_prefab.map_prefix = map_prefix.to_path_buf();
+            map_prefab.conversion = Self::conversion_key(settings);
+            map_prefab
         }
 }
 
@@ -163,6 +605,12 @@ pub struct MapPrefab<Prefab, Proxy> {
     phantom_prefab: PhantomData<Prefab>,
     phantom_proxy: PhantomData<Proxy>,
     files: Vec<MapFile>,
+    shared_files: Vec<MapFile>,
+    inputs: Vec<PathBuf>,
+    conversion: String,
+    stats: MapStats,
+    output_format: OutputFormat,
+    map_prefix: PathBuf,
 }
 
 impl<Prefab, Proxy> MapPrefab<Prefab, Proxy> {
@@ -171,31 +619,110 @@ impl<Prefab, Proxy> MapPrefab<Prefab, Proxy> {
             phantom_prefab: PhantomData,
             phantom_proxy: PhantomData,
             files: files,
+            shared_files: Vec::new(),
+            inputs: Vec::new(),
+            conversion: String::new(),
+            stats: MapStats::default(),
+            output_format: OutputFormat::default(),
+            map_prefix: PathBuf::new(),
         }
     }
 
+    pub fn stats(&self) -> &MapStats {
+        &self.stats
+    }
+
+    // Files the map was produced from, recorded in the build manifest
+    pub fn add_input<P: Into<PathBuf>>(&mut self, path: P) {
+        self.inputs.push(path.into());
+    }
+
+    pub fn inputs(&self) -> &[PathBuf] {
+        &self.inputs
+    }
+
+    // Files whose contents haven't changed are left untouched
     pub fn write(&self, dir: &Path) -> Result<()> {
+        let mut manifest = BuildManifest {
+            conversion: self.conversion.clone(),
+            inputs: BuildManifest::hash_inputs(&self.inputs),
+            outputs: Default::default(),
+            entity_kinds: self.stats.entity_kinds.clone(),
+        };
         for file in self.files() {
-            match file {
-                MapFile::Path(src, dest) => {
-                    let dest = dir.join(dest);
-                    create_dir_all(dest.parent().expect("No path?")).expect("Unable to create output directories");
-                    copy(src, dest).expect("Unable to copy spritesheet image");
-                },
-                MapFile::Data(dest, buffer) => {
-                    let dest = dir.join(dest);
-                    create_dir_all(dest.parent().expect("No path?")).expect("Unable to create output directories");
-                    let mut f = File::create(dest).expect("Unable to create target sprite sheet image file");
-                    f.write(&buffer).expect("Unable to write sprite sheet image");
-                },
-            }
+            let (dest, hash) = write_map_file(file, dir);
+            manifest.outputs.insert(path_key(dest), hash);
         }
-        Ok(())
+        create_dir_all(dir)?;
+        manifest.save(dir)
+    }
+
+    /// Works out what `write` would change in `dir` without writing anything.
+    pub fn plan(&self, dir: &Path) -> Result<WritePlan> {
+        WritePlan::new(&self.files, &self.output_format.prefab_file(), &self.stats, dir)
+    }
+
+    /// Writes everything into a single zip archive instead of a directory. Files are
+    /// stored under the map prefix so they can be loaded through `ArchiveSource`
+    /// with the same asset paths as a written directory.
+    pub fn write_archive(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir)?;
+        }
+        archive::write_archive(&self.files, &self.map_prefix, path)
     }
 
     pub fn files(&self) -> &[MapFile] {
         &self.files
     }
+
+    // Sprite sheet textures shared with other maps, relative to
+    // `ConversionSettings::texture_prefix`. Neither `write` nor `write_archive`
+    // includes them, `WorldPrefab::write` writes them once for all of its maps.
+    pub fn shared_files(&self) -> &[MapFile] {
+        &self.shared_files
+    }
+
+    pub fn output_format(&self) -> OutputFormat {
+        self.output_format
+    }
+
+    // The encoded map prefab itself
+    pub fn prefab_data(&self) -> Option<&[u8]> {
+        let prefab_file = PathBuf::from(self.output_format.prefab_file());
+        self.files.iter()
+            .filter_map(|f| match f {
+                MapFile::Data(path, buffer) if *path == prefab_file => Some(&buffer[..]),
+                _ => None,
+            })
+            .next()
+    }
+}
+
+// Writes a file into `dir` unless it is already there, returning its destination
+// and content hash
+pub(crate) fn write_map_file<'a>(file: &'a MapFile, dir: &Path) -> (&'a Path, u64) {
+    match file {
+        MapFile::Path(src, dest) => {
+            let hash = file_hash(src).expect("Unable to read spritesheet image");
+            let path = dir.join(dest);
+            if file_hash(&path) != Some(hash) {
+                create_dir_all(path.parent().expect("No path?")).expect("Unable to create output directories");
+                copy(src, path).expect("Unable to copy spritesheet image");
+            }
+            (dest, hash)
+        },
+        MapFile::Data(dest, buffer) => {
+            let hash = content_hash(buffer);
+            let path = dir.join(dest);
+            if file_hash(&path) != Some(hash) {
+                create_dir_all(path.parent().expect("No path?")).expect("Unable to create output directories");
+                let mut f = File::create(path).expect("Unable to create target sprite sheet image file");
+                f.write(&buffer).expect("Unable to write sprite sheet image");
+            }
+            (dest, hash)
+        },
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, PrefabData)]
@@ -211,15 +738,44 @@ pub struct RealLevelPrefab {
 pub struct MapTile;
 
 
-pub fn sprite_sheets_from_tilesets(map: &Map, input_dir: &Path, map_prefix: &Path) -> (Vec<(MapFile, SpriteSheetPrefab)>, HashMap<usize, (usize, usize)>) {
+pub fn sprite_sheets_from_tilesets(map: &Map, input_dir: &Path, map_prefix: &Path) -> (Vec<(MapFile, SpriteSheetPrefab)>, BTreeMap<usize, (usize, usize)>) {
+    sprite_sheets_with_texture_prefix(map, input_dir, map_prefix, None)
+}
+
+/// Like `sprite_sheets_from_tilesets`, but with a `texture_prefix` the textures are
+/// put there instead of with the map. They are named after their tileset and their
+/// contents so maps using the same tilesets end up sharing them.
+pub fn sprite_sheets_with_texture_prefix(map: &Map, input_dir: &Path, map_prefix: &Path, texture_prefix: Option<&Path>) -> (Vec<(MapFile, SpriteSheetPrefab)>, BTreeMap<usize, (usize, usize)>) {
+    sprite_sheets_with_cache(map, input_dir, map_prefix, texture_prefix, None)
+}
+
+/// Like `sprite_sheets_with_texture_prefix`, keeping the packed sheets of image
+/// collection tilesets in `sheet_cache` as `ConversionSettings::sheet_cache` describes.
+pub fn sprite_sheets_with_cache(map: &Map, input_dir: &Path, map_prefix: &Path, texture_prefix: Option<&Path>, sheet_cache: Option<&Path>) -> (Vec<(MapFile, SpriteSheetPrefab)>, BTreeMap<usize, (usize, usize)>) {
     let mut spritesheets = Vec::new();
-    let mut gid_to_sprite = HashMap::new();
+    let mut gid_to_sprite = BTreeMap::new();
+    // Names and paths are built from the prefix's components so they don't depend on
+    // how the platform formats paths
+    let components = |path: &Path| -> Vec<String> { path.iter().map(|c| c.to_string_lossy().into_owned()).collect() };
+    let prefix = components(map_prefix);
+    let texture_dir = components(texture_prefix.unwrap_or(map_prefix));
+    let texture_file = |i: usize, tileset: &Tileset, hash: u64| match texture_prefix {
+        Some(_) => {
+            let name: String = tileset.name.chars()
+                .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' })
+                .collect();
+            PathBuf::from(format!("{}_{:016x}.png", name, hash))
+        },
+        None => PathBuf::from(format!("sprite_sheet_{}.png", i)),
+    };
     for (i, tileset) in map.tilesets.iter().enumerate() {
-        let base_path = PathBuf::from(format!("sprite_sheet_{}", i));
-
         if !tileset.images.is_empty() {
             let img = &tileset.images[0];
-            let texture_path = base_path.with_extension("png");
+            let hash = match texture_prefix {
+                Some(_) => file_hash(&input_dir.join(&img.source)).expect("Unable to read spritesheet image"),
+                None => 0,
+            };
+            let texture_path = texture_file(i, tileset, hash);
 
             let mut sprites = vec![];
             let tileset_sprite_columns = img.width / tileset.tile_width as i32;
@@ -242,7 +798,7 @@ pub fn sprite_sheets_from_tilesets(map: &Map, input_dir: &Path, map_prefix: &Pat
             }
             let sprite_sheet = SpriteSheetPrefab::Sheet {
                 texture: TexturePrefab::File(
-                    map_prefix.join(&texture_path).to_str().unwrap().to_string(),
+                    format!("{}/{}", texture_dir.join("/"), texture_path.display()),
                     (
                         "IMAGE".to_string(),
                         ImageFormat {
@@ -255,52 +811,22 @@ pub fn sprite_sheets_from_tilesets(map: &Map, input_dir: &Path, map_prefix: &Pat
                     texture_height: img.height as u32,
                     sprites: sprites,
                 })],
-                name: Some(format!("{:?}_sprite_sheet_{}", map_prefix, i)),
+                name: Some(format!("{}_sprite_sheet_{}", prefix.join("_"), i)),
             };
             spritesheets.push((MapFile::Path(input_dir.join(&img.source), texture_path), sprite_sheet));
         } else {
-            let mut images = Vec::with_capacity(tileset.tiles.len());
-            for tile in &tileset.tiles {
-                //TODO tiles can have multiple images because of animation probably. Ignoring that.
-                let img = image::open(input_dir.join(&tile.images[0].source))
-                    .expect("Failed to open image");
-                let img = img.as_rgba8().expect("Failed to convert image to rgba8");
-                let dimensions = img.dimensions();
-                let bytes = img
-                    .pixels()
-                    .flat_map(|it| it.data.iter().map(|it| *it))
-                    .collect::<Vec<u8>>();
-                gid_to_sprite.insert(tileset.first_gid as usize + tile.id as usize, (spritesheets.len(), images.len()));
-                images.push(InputSprite {
-                    dimensions,
-                    bytes: bytes.clone(),
-                });
+            // Packed in id order so the sheet comes out the same every time
+            let mut tiles: Vec<_> = tileset.tiles.iter().collect();
+            tiles.sort_by_key(|t| t.id);
+            for (sprite, tile) in tiles.iter().enumerate() {
+                gid_to_sprite.insert(tileset.first_gid as usize + tile.id as usize, (spritesheets.len(), sprite));
             }
-            let sprite_sheet = sheep::pack::<SimplePacker>(images, 4);
-            let meta = sheep::encode::<AmethystFormat>(&sprite_sheet, ());
-            let texture_path = base_path.with_extension("png");
-            let mut buffer = Vec::new();
-            let encoder = PNGEncoder::new(&mut buffer);
-            encoder.encode(&sprite_sheet.bytes, sprite_sheet.dimensions.0, sprite_sheet.dimensions.1, ColorType::RGBA(8)).expect("Could not encode spritesheet as png");
-
-
-            let sprites: Vec<_> = meta
-                .sprites
-                .iter()
-                .map(|s| SpritePosition {
-                    x: s.x as u32,
-                    y: s.y as u32,
-                    width: s.width as u32,
-                    height: s.height as u32,
-                    offsets: s.offsets,
-                    flip_horizontal: false,
-                    flip_vertical: false,
-                })
-                .collect();
+            let (buffer, sprite_list) = pack_tileset(&tiles, input_dir, sheet_cache);
+            let texture_path = texture_file(i, tileset, content_hash(&buffer));
 
             let sprite_sheet = SpriteSheetPrefab::Sheet {
                 texture: TexturePrefab::File(
-                    map_prefix.join(&texture_path).to_str().unwrap().to_string(),
+                    format!("{}/{}", texture_dir.join("/"), texture_path.display()),
                     (
                         "IMAGE".to_string(),
                         ImageFormat {
@@ -308,15 +834,99 @@ pub fn sprite_sheets_from_tilesets(map: &Map, input_dir: &Path, map_prefix: &Pat
                         },
                     ),
                 ),
-                sprites: vec![Sprites::List(SpriteList {
-                    texture_width: meta.texture_width as u32,
-                    texture_height: meta.texture_height as u32,
-                    sprites: sprites,
-                })],
-                name: Some(format!("{:?}_sprite_sheet_{}", map_prefix, i)),
+                sprites: vec![Sprites::List(sprite_list)],
+                name: Some(format!("{}_sprite_sheet_{}", prefix.join("_"), i)),
             };
             spritesheets.push((MapFile::Data(texture_path, buffer), sprite_sheet));
         }
     }
     (spritesheets, gid_to_sprite)
 }
+
+// Packs an image collection tileset's images, in id order, into one sheet. With a
+// `sheet_cache` the packed sheet is kept there, named after a hash of the images, so
+// an unchanged tileset isn't packed again.
+fn pack_tileset(tiles: &[&Tile], input_dir: &Path, sheet_cache: Option<&Path>) -> (Vec<u8>, SpriteList) {
+    //TODO tiles can have multiple images because of animation probably. Ignoring that.
+    let sources: Vec<PathBuf> = tiles.iter().map(|tile| input_dir.join(&tile.images[0].source)).collect();
+    let cached = sheet_cache.map(|dir| {
+        // The packer and its padding are part of the key in case they change
+        let mut key = b"simple 4".to_vec();
+        for (tile, source) in tiles.iter().zip(&sources) {
+            key.extend_from_slice(&tile.id.to_le_bytes());
+            key.extend_from_slice(&file_hash(source).expect("Failed to open image").to_le_bytes());
+        }
+        dir.join(format!("{:016x}", content_hash(&key)))
+    });
+    if let Some(sheet) = cached.as_ref().and_then(|path| read_cached_sheet(path)) {
+        return sheet;
+    }
+
+    let mut images = Vec::with_capacity(tiles.len());
+    for source in &sources {
+        let img = image::open(source)
+            .expect("Failed to open image");
+        let img = img.as_rgba8().expect("Failed to convert image to rgba8");
+        let dimensions = img.dimensions();
+        let bytes = img
+            .pixels()
+            .flat_map(|it| it.data.iter().map(|it| *it))
+            .collect::<Vec<u8>>();
+        images.push(InputSprite {
+            dimensions,
+            bytes: bytes.clone(),
+        });
+    }
+    let sprite_sheet = sheep::pack::<SimplePacker>(images, 4);
+    let meta = sheep::encode::<AmethystFormat>(&sprite_sheet, ());
+    let mut buffer = Vec::new();
+    let encoder = PNGEncoder::new(&mut buffer);
+    encoder.encode(&sprite_sheet.bytes, sprite_sheet.dimensions.0, sprite_sheet.dimensions.1, ColorType::RGBA(8)).expect("Could not encode spritesheet as png");
+
+    let sprites: Vec<_> = meta
+        .sprites
+        .iter()
+        .map(|s| SpritePosition {
+            x: s.x as u32,
+            y: s.y as u32,
+            width: s.width as u32,
+            height: s.height as u32,
+            offsets: s.offsets,
+            flip_horizontal: false,
+            flip_vertical: false,
+        })
+        .collect();
+    let sprite_list = SpriteList {
+        texture_width: meta.texture_width as u32,
+        texture_height: meta.texture_height as u32,
+        sprites: sprites,
+    };
+    if let Some(path) = &cached {
+        write_cached_sheet(path, &buffer, &sprite_list);
+    }
+    (buffer, sprite_list)
+}
+
+fn read_cached_sheet(path: &Path) -> Option<(Vec<u8>, SpriteList)> {
+    let sprites = ron::de::from_str(&read_to_string(path.with_extension("ron")).ok()?).ok()?;
+    let buffer = read(path.with_extension("png")).ok()?;
+    Some((buffer, sprites))
+}
+
+// A sheet which can't be cached is just packed again next time. Files are written
+// under a temporary name and moved into place so maps converted at the same time
+// never read a half written sheet.
+fn write_cached_sheet(path: &Path, buffer: &[u8], sprites: &SpriteList) {
+    let text = match ron::ser::to_string(sprites) {
+        Ok(text) => text,
+        Err(_) => return,
+    };
+    let write_file = |extension: &str, bytes: &[u8]| -> Result<()> {
+        let temp = path.with_extension(format!("{}.{}.{:?}.tmp", extension, std::process::id(), std::thread::current().id()));
+        std::fs::write(&temp, bytes)?;
+        rename(&temp, path.with_extension(extension))
+    };
+    let _ = create_dir_all(path.parent().unwrap_or_else(|| Path::new("")))
+        .and_then(|_| write_file("png", buffer))
+        .and_then(|_| write_file("ron", text.as_bytes()));
+}
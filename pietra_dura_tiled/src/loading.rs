@@ -0,0 +1,236 @@
+// Telling the game when a level has finished loading
+
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
+use amethyst::{
+    assets::{AssetStorage, Handle, Prefab, PrefabData, ProgressCounter},
+    core::transform::Parent,
+    ecs::prelude::*,
+    shrev::EventChannel,
+};
+
+use crate::PreserveOnReload;
+
+/// Published on the `EventChannel<LevelLoaded>` resource once a level's prefab and
+/// all of its sprite sheets have been processed and its entities, physics bodies
+/// included, have been spawned. `root` is the entity holding the prefab handle.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LevelLoaded {
+    pub map: String,
+    pub root: Entity,
+}
+
+/// Attach to a level's root entity, alongside its prefab handle, for a `LevelLoaded`
+/// to be published when it is spawned, and again whenever its handle is replaced,
+/// as `LevelReloadSystem` does. Pass `progress_mut` to the loader to follow this
+/// map's loading on its own.
+pub struct TrackLevel<P> {
+    map: String,
+    progress: ProgressCounter,
+    // The handle the last `LevelLoaded` was for
+    loaded: Option<Handle<Prefab<P>>>,
+}
+
+impl<P> TrackLevel<P> {
+    pub fn new(map: &str) -> Self {
+        TrackLevel {
+            map: map.to_string(),
+            progress: ProgressCounter::new(),
+            loaded: None,
+        }
+    }
+
+    pub fn map(&self) -> &str {
+        &self.map
+    }
+
+    pub fn progress(&self) -> &ProgressCounter {
+        &self.progress
+    }
+
+    pub fn progress_mut(&mut self) -> &mut ProgressCounter {
+        &mut self.progress
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.loaded.is_some()
+    }
+}
+
+impl<P: Send + Sync + 'static> Component for TrackLevel<P> {
+    type Storage = HashMapStorage<Self>;
+}
+
+/// Publishes `LevelLoaded` for levels with a `TrackLevel`. A level counts as loaded
+/// once its prefab's sub assets, which include its sprite sheets, are complete and
+/// all of its entities have been spawned under the root, directly or further down,
+/// which is when their physics bodies are made. Entities kept through a reload with
+/// `PreserveOnReload`, and anything under them, don't count towards the level's
+/// entities.
+///
+/// Regions spawned by `RegionStreamingSystem` have roots of their own, so a level
+/// split into regions is loaded once its own prefab is, without waiting for any of
+/// its regions.
+pub struct LevelLoadedSystem<P> {
+    phantom: PhantomData<P>,
+}
+
+impl<P> Default for LevelLoadedSystem<P> {
+    fn default() -> Self {
+        LevelLoadedSystem { phantom: PhantomData }
+    }
+}
+
+impl<'s, P> System<'s> for LevelLoadedSystem<P>
+    where P: PrefabData<'s> + Send + Sync + 'static {
+    type SystemData = (
+        Entities<'s>,
+        ReadStorage<'s, Handle<Prefab<P>>>,
+        Read<'s, AssetStorage<Prefab<P>>>,
+        ReadStorage<'s, Parent>,
+        ReadStorage<'s, PreserveOnReload>,
+        WriteStorage<'s, TrackLevel<P>>,
+        Write<'s, EventChannel<LevelLoaded>>,
+    );
+
+    fn run(&mut self, (entities, handles, prefabs, parents, preserved, mut levels, mut events): Self::SystemData) {
+        let mut descendants = None;
+        for (root, handle, level) in (&entities, &handles, &mut levels).join() {
+            if level.loaded.as_ref() == Some(handle) || !level.progress.is_complete() {
+                continue;
+            }
+            let prefab = match prefabs.get(handle) {
+                Some(prefab) => prefab,
+                None => continue,
+            };
+            if !prefab.progress().map(ProgressCounter::is_complete).unwrap_or(false) {
+                continue;
+            }
+            // Every entity but the root ends up somewhere under it when spawned
+            let descendants = descendants.get_or_insert_with(|| spawned_descendants(&parents, &preserved));
+            if descendants.get(&root).cloned().unwrap_or(0) < prefab.len().saturating_sub(1) {
+                continue;
+            }
+            level.loaded = Some(handle.clone());
+            events.single_write(LevelLoaded { map: level.map.clone(), root });
+        }
+    }
+}
+
+// How many entities are under each entity at any depth, leaving out ones kept
+// through a reload and anything under them
+pub(crate) fn spawned_descendants(
+    parents: &ReadStorage<'_, Parent>,
+    preserved: &ReadStorage<'_, PreserveOnReload>,
+) -> HashMap<Entity, usize> {
+    let mut descendants = HashMap::new();
+    for (parent, _) in (parents, !preserved).join() {
+        let mut ancestors = vec![parent.entity];
+        while let Some(parent) = parents.get(ancestors[ancestors.len() - 1]) {
+            // Parents should never loop, but a broken hierarchy mustn't hang the game
+            if ancestors.contains(&parent.entity) {
+                break;
+            }
+            ancestors.push(parent.entity);
+        }
+        if ancestors.iter().any(|ancestor| preserved.contains(*ancestor)) {
+            continue;
+        }
+        for ancestor in ancestors {
+            *descendants.entry(ancestor).or_insert(0) += 1;
+        }
+    }
+    descendants
+}
+
+#[cfg(test)]
+mod tests {
+    use amethyst::core::Transform;
+
+    use super::*;
+
+    fn world() -> World {
+        let mut world = World::new();
+        world.register::<Transform>();
+        world.register::<Parent>();
+        world.register::<PreserveOnReload>();
+        world.register::<Handle<Prefab<Transform>>>();
+        world.register::<TrackLevel<Transform>>();
+        world.add_resource(AssetStorage::<Prefab<Transform>>::new());
+        world.add_resource(EventChannel::<LevelLoaded>::new());
+        world
+    }
+
+    // A processed prefab with a root and `children` entities under it
+    fn prefab(world: &World, children: usize) -> Handle<Prefab<Transform>> {
+        let mut prefab = Prefab::new_main(Transform::default());
+        for _ in 0..children {
+            prefab.add(Some(0), Some(Transform::default()));
+        }
+        prefab.load_sub_assets(&mut world.write_storage::<Transform>()).expect("Unable to load sub assets");
+        world.write_resource::<AssetStorage<Prefab<Transform>>>().insert(prefab)
+    }
+
+    fn spawn_child(world: &mut World, root: Entity) -> Entity {
+        world.create_entity().with(Parent { entity: root }).build()
+    }
+
+    #[test]
+    fn loaded_is_published_once_per_handle() {
+        let mut world = world();
+        let mut reader = world.write_resource::<EventChannel<LevelLoaded>>().register_reader();
+        let mut system = LevelLoadedSystem::<Transform>::default();
+        let mut published = |world: &World, system: &mut LevelLoadedSystem<Transform>| {
+            system.run_now(&world.res);
+            world.read_resource::<EventChannel<LevelLoaded>>().read(&mut reader).count()
+        };
+
+        let handle = prefab(&world, 2);
+        let root = world.create_entity().with(handle).with(TrackLevel::<Transform>::new("map")).build();
+        assert_eq!(published(&world, &mut system), 0);
+        let child = spawn_child(&mut world, root);
+        assert_eq!(published(&world, &mut system), 0);
+        let replaced = spawn_child(&mut world, root);
+        assert_eq!(published(&world, &mut system), 1);
+        assert_eq!(published(&world, &mut system), 0);
+
+        // A reload keeps a preserved child, which doesn't count towards the new level
+        world.write_storage::<PreserveOnReload>().insert(child, PreserveOnReload).unwrap();
+        world.delete_entity(replaced).unwrap();
+        let handle = prefab(&world, 2);
+        world.write_storage::<Handle<Prefab<Transform>>>().insert(root, handle).unwrap();
+        assert_eq!(published(&world, &mut system), 0);
+        spawn_child(&mut world, root);
+        assert_eq!(published(&world, &mut system), 0);
+        spawn_child(&mut world, root);
+        assert_eq!(published(&world, &mut system), 1);
+        assert_eq!(published(&world, &mut system), 0);
+    }
+
+    #[test]
+    fn entities_below_the_root_count_towards_the_level() {
+        let mut world = world();
+        let mut reader = world.write_resource::<EventChannel<LevelLoaded>>().register_reader();
+        let mut system = LevelLoadedSystem::<Transform>::default();
+        let mut published = |world: &World, system: &mut LevelLoadedSystem<Transform>| {
+            system.run_now(&world.res);
+            world.read_resource::<EventChannel<LevelLoaded>>().read(&mut reader).count()
+        };
+
+        let handle = prefab(&world, 3);
+        let root = world.create_entity().with(handle).with(TrackLevel::<Transform>::new("map")).build();
+        let child = spawn_child(&mut world, root);
+        let grandchild = spawn_child(&mut world, child);
+        assert_eq!(published(&world, &mut system), 0);
+
+        // Anything under an entity kept through a reload doesn't count
+        let kept = spawn_child(&mut world, root);
+        world.write_storage::<PreserveOnReload>().insert(kept, PreserveOnReload).unwrap();
+        spawn_child(&mut world, kept);
+        assert_eq!(published(&world, &mut system), 0);
+
+        spawn_child(&mut world, grandchild);
+        assert_eq!(published(&world, &mut system), 1);
+    }
+}
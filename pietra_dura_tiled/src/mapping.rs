@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+
+use amethyst::core::Transform;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use crate::{object_transform, Object, ObjectShape, Properties, PropertyValue, SpriteContext, SpriteRenderPrefab, SpriteSheetPrefab};
+
+/// A RON file describing how object types become prefab details, so new object
+/// types can be converted without writing more converter code.
+///
+/// ```ron
+/// (
+///     objects: {
+///         "sensor": (
+///             detail: "Physics((colliders: [(shape: Rect(width: $width, height: $height), ...)], ...))",
+///         ),
+///     },
+/// )
+/// ```
+///
+/// The `detail` is a RON template for the converter's detail type. `$name` is replaced
+/// with one of the object's values: `x`, `y`, `width`, `height`, `half_width`,
+/// `half_height`, `center_x`, `center_y` (the center in the converter's y-up space),
+/// `layer`, `id`, `name` or `type`. `${property}` is replaced with a custom property
+/// and `${property:default}` falls back to `default` if the object doesn't have it.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ObjectMapping {
+    pub objects: HashMap<String, ObjectRule>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ObjectRule {
+    #[serde(default)]
+    pub requires_sprite: bool,
+    pub detail: String,
+}
+
+/// The output of an `ObjectRule`. The sprite and transform are filled in if the
+/// object has a tile image.
+pub struct MappedObject {
+    pub sheet: Option<SpriteSheetPrefab>,
+    pub render: Option<SpriteRenderPrefab>,
+    pub transform: Option<Transform>,
+    pub detail: String,
+}
+
+impl MappedObject {
+    pub fn detail<D: DeserializeOwned>(&self) -> Result<D, String> {
+        ron::de::from_str(&self.detail)
+            .map_err(|e| format!("mapped detail doesn't match the detail type: {}\n{}", e, self.detail))
+    }
+}
+
+impl ObjectMapping {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let f = File::open(path).map_err(|e| format!("Unable to open object mapping {:?}: {}", path, e))?;
+        ron::de::from_reader(f).map_err(|e| format!("Unable to parse object mapping {:?}: {}", path, e))
+    }
+
+    /// None if the mapping doesn't handle the object, or an error if its template
+    /// can't be filled in
+    pub fn convert(&self, ctx: &Option<SpriteContext>, layer: usize, object: &Object) -> Result<Option<MappedObject>, String> {
+        let rule = match self.objects.get(&object.obj_type) {
+            Some(rule) => rule,
+            None => return Ok(None),
+        };
+        if rule.requires_sprite && ctx.is_none() {
+            return Ok(None);
+        }
+        let (width, height) = match object.shape {
+            ObjectShape::Rect { width, height } | ObjectShape::Ellipse { width, height } => (width, height),
+            _ => (0.0, 0.0),
+        };
+
+        let mut bindings = HashMap::new();
+        bindings.insert("x", object.x.to_string());
+        bindings.insert("y", object.y.to_string());
+        bindings.insert("width", width.to_string());
+        bindings.insert("height", height.to_string());
+        bindings.insert("half_width", (width / 2.0).to_string());
+        bindings.insert("half_height", (height / 2.0).to_string());
+        bindings.insert("center_x", (object.x + width / 2.0).to_string());
+        bindings.insert("center_y", (-object.y - height / 2.0).to_string());
+        bindings.insert("layer", layer.to_string());
+        bindings.insert("id", object.id.to_string());
+        bindings.insert("name", format!("{:?}", object.name));
+        bindings.insert("type", format!("{:?}", object.obj_type));
+        let detail = substitute(&rule.detail, &bindings, &object.properties)
+            .map_err(|e| format!("object mapping for type {:?}: {}", object.obj_type, e))?;
+
+        let (sheet, render, transform) = if let Some(ctx) = ctx {
+            (ctx.sprite_sheet.clone(), Some(ctx.render()), Some(object_transform(object, layer)))
+        } else {
+            (None, None, None)
+        };
+
+        Ok(Some(MappedObject { sheet, render, transform, detail }))
+    }
+}
+
+fn substitute(template: &str, bindings: &HashMap<&str, String>, properties: &Properties) -> Result<String, String> {
+    let mut output = String::with_capacity(template.len());
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            output.push(c);
+            continue;
+        }
+        if chars.peek() == Some(&'{') {
+            chars.next();
+            let mut key = String::new();
+            loop {
+                match chars.next() {
+                    Some('}') => break,
+                    Some(c) => key.push(c),
+                    None => return Err(format!("unterminated property binding ${{{}", key)),
+                }
+            }
+            let mut parts = key.splitn(2, ':');
+            let name = parts.next().unwrap_or("");
+            match (properties.get(name), parts.next()) {
+                (Some(value), _) => output.push_str(&property_literal(value)),
+                (None, Some(default)) => output.push_str(default),
+                (None, None) => return Err(format!("object has no property {:?}", name)),
+            }
+        } else {
+            let mut key = String::new();
+            while let Some(c) = chars.peek() {
+                if c.is_alphanumeric() || *c == '_' {
+                    key.push(*c);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            match bindings.get(key.as_str()) {
+                Some(value) => output.push_str(value),
+                None => return Err(format!("unknown binding ${}", key)),
+            }
+        }
+    }
+    Ok(output)
+}
+
+fn property_literal(value: &PropertyValue) -> String {
+    match value {
+        PropertyValue::Bool(v) => v.to_string(),
+        PropertyValue::Float(v) => v.to_string(),
+        PropertyValue::Int(v) => v.to_string(),
+        PropertyValue::Color(v) => v.to_string(),
+        PropertyValue::String(v) => format!("{:?}", v),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bindings() -> HashMap<&'static str, String> {
+        let mut bindings = HashMap::new();
+        bindings.insert("width", "32".to_string());
+        bindings.insert("center_x", "16".to_string());
+        bindings
+    }
+
+    fn properties() -> Properties {
+        let mut properties = Properties::new();
+        properties.insert("speed".to_string(), PropertyValue::Float(2.5));
+        properties.insert("label".to_string(), PropertyValue::String("door".to_string()));
+        properties
+    }
+
+    #[test]
+    fn bindings_are_substituted() {
+        assert_eq!(
+            substitute("Rect(width: $width, x: $center_x)", &bindings(), &properties()),
+            Ok("Rect(width: 32, x: 16)".to_string()),
+        );
+        assert_eq!(substitute("$$width", &bindings(), &properties()), Err("unknown binding $".to_string()));
+        assert_eq!(substitute("$depth", &bindings(), &properties()), Err("unknown binding $depth".to_string()));
+    }
+
+    #[test]
+    fn properties_are_substituted_as_literals() {
+        assert_eq!(
+            substitute("(speed: ${speed}, label: ${label})", &bindings(), &properties()),
+            Ok("(speed: 2.5, label: \"door\")".to_string()),
+        );
+    }
+
+    #[test]
+    fn missing_properties_use_their_default() {
+        assert_eq!(substitute("${jump:1.5}", &bindings(), &properties()), Ok("1.5".to_string()));
+        assert_eq!(substitute("${speed:1}", &bindings(), &properties()), Ok("2.5".to_string()));
+        assert_eq!(substitute("${label:}", &bindings(), &Properties::new()), Ok("".to_string()));
+    }
+
+    #[test]
+    fn missing_properties_without_a_default_are_errors() {
+        assert_eq!(
+            substitute("(jump: ${jump})", &bindings(), &properties()),
+            Err("object has no property \"jump\"".to_string()),
+        );
+        assert_eq!(
+            substitute("(jump: ${jump", &bindings(), &properties()),
+            Err("unterminated property binding ${jump".to_string()),
+        );
+    }
+}
@@ -0,0 +1,347 @@
+// The map model converters work with. Importers (TMX, Tiled JSON and LDtk) fill it
+// in, so converters and game code don't depend on any particular parser's types.
+
+use std::collections::{BTreeMap, HashMap};
+
+use xml::attribute::OwnedAttribute;
+use xml::reader::{EventReader, XmlEvent};
+
+pub type Properties = BTreeMap<String, PropertyValue>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PropertyValue {
+    Bool(bool),
+    Float(f32),
+    Int(i32),
+    // ARGB
+    Color(u32),
+    String(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ObjectShape {
+    Rect { width: f32, height: f32 },
+    Ellipse { width: f32, height: f32 },
+    Polyline { points: Vec<(f32, f32)> },
+    Polygon { points: Vec<(f32, f32)> },
+    Point(f32, f32),
+}
+
+/// An object placed in an object group. `gid` is 0 unless the object is a tile, in
+/// which case `x` and `y` are its bottom left corner rather than its top left.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Object {
+    pub id: u32,
+    pub gid: u32,
+    pub name: String,
+    pub obj_type: String,
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+    pub rotation: f32,
+    pub visible: bool,
+    pub shape: ObjectShape,
+    pub properties: Properties,
+}
+
+/// `parallax` is the group's horizontal and vertical parallax factor, 1 unless it
+/// scrolls at a different speed to the camera.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectGroup {
+    pub name: String,
+    pub opacity: f32,
+    pub visible: bool,
+    pub parallax: (f32, f32),
+    pub objects: Vec<Object>,
+    pub properties: Properties,
+}
+
+/// A layer of tiles, indexed by row then column. 0 is an empty cell, anything else
+/// is a gid. `parallax` is as for `ObjectGroup`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layer {
+    pub name: String,
+    pub opacity: f32,
+    pub visible: bool,
+    pub parallax: (f32, f32),
+    pub tiles: Vec<Vec<u32>>,
+    pub properties: Properties,
+}
+
+/// An image file, relative to the map it came from
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    pub source: String,
+    pub width: i32,
+    pub height: i32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tile {
+    pub id: u32,
+    pub images: Vec<Image>,
+    pub properties: Properties,
+}
+
+/// Either a single image cut into tiles, in which case `images` has one entry, or a
+/// collection of tiles with an image each.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tileset {
+    pub first_gid: u32,
+    pub name: String,
+    pub tile_width: u32,
+    pub tile_height: u32,
+    pub spacing: u32,
+    pub margin: u32,
+    pub images: Vec<Image>,
+    pub tiles: Vec<Tile>,
+    // The file the tileset was read from, relative to the map, if it wasn't embedded
+    pub source: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Map {
+    pub width: u32,
+    pub height: u32,
+    pub tile_width: u32,
+    pub tile_height: u32,
+    pub tilesets: Vec<Tileset>,
+    pub layers: Vec<Layer>,
+    pub object_groups: Vec<ObjectGroup>,
+    pub properties: Properties,
+}
+
+// Importing from the tiled crate, which all of the map formats currently go through
+
+fn from_tiled_properties(properties: tiled::Properties) -> Properties {
+    properties.into_iter()
+        .map(|(name, value)| {
+            let value = match value {
+                tiled::PropertyValue::BoolValue(v) => PropertyValue::Bool(v),
+                tiled::PropertyValue::FloatValue(v) => PropertyValue::Float(v),
+                tiled::PropertyValue::IntValue(v) => PropertyValue::Int(v),
+                tiled::PropertyValue::ColorValue(v) => PropertyValue::Color(v),
+                tiled::PropertyValue::StringValue(v) => PropertyValue::String(v),
+            };
+            (name, value)
+        })
+        .collect()
+}
+
+impl From<tiled::Image> for Image {
+    fn from(image: tiled::Image) -> Self {
+        Image {
+            source: image.source,
+            width: image.width,
+            height: image.height,
+        }
+    }
+}
+
+impl From<tiled::Object> for Object {
+    fn from(object: tiled::Object) -> Self {
+        let shape = match object.shape {
+            tiled::ObjectShape::Rect { width, height } => ObjectShape::Rect { width, height },
+            tiled::ObjectShape::Ellipse { width, height } => ObjectShape::Ellipse { width, height },
+            tiled::ObjectShape::Polyline { points } => ObjectShape::Polyline { points },
+            tiled::ObjectShape::Polygon { points } => ObjectShape::Polygon { points },
+            tiled::ObjectShape::Point(x, y) => ObjectShape::Point(x, y),
+        };
+        Object {
+            id: object.id,
+            gid: object.gid,
+            name: object.name,
+            obj_type: object.obj_type,
+            x: object.x,
+            y: object.y,
+            width: object.width,
+            height: object.height,
+            rotation: object.rotation,
+            visible: object.visible,
+            shape,
+            properties: from_tiled_properties(object.properties),
+        }
+    }
+}
+
+impl From<tiled::Map> for Map {
+    fn from(map: tiled::Map) -> Self {
+        Map {
+            width: map.width,
+            height: map.height,
+            tile_width: map.tile_width,
+            tile_height: map.tile_height,
+            tilesets: map.tilesets.into_iter()
+                .map(|tileset| Tileset {
+                    first_gid: tileset.first_gid,
+                    name: tileset.name,
+                    tile_width: tileset.tile_width,
+                    tile_height: tileset.tile_height,
+                    spacing: tileset.spacing,
+                    margin: tileset.margin,
+                    images: tileset.images.into_iter().map(Image::from).collect(),
+                    tiles: tileset.tiles.into_iter()
+                        .map(|tile| Tile {
+                            id: tile.id,
+                            images: tile.images.into_iter().map(Image::from).collect(),
+                            properties: from_tiled_properties(tile.properties),
+                        })
+                        .collect(),
+                    // Filled in by read_layer_attributes
+                    source: None,
+                })
+                .collect(),
+            layers: map.layers.into_iter()
+                .map(|layer| {
+                    let properties = from_tiled_properties(layer.properties);
+                    let (parallax_x, parallax_y) = property_parallax(&properties);
+                    Layer {
+                        name: layer.name,
+                        opacity: layer.opacity,
+                        visible: layer.visible,
+                        parallax: (parallax_x.unwrap_or(1.0), parallax_y.unwrap_or(1.0)),
+                        tiles: layer.tiles,
+                        properties,
+                    }
+                })
+                .collect(),
+            object_groups: map.object_groups.into_iter()
+                .map(|group| ObjectGroup {
+                    name: group.name,
+                    opacity: group.opacity,
+                    visible: group.visible,
+                    parallax: (1.0, 1.0),
+                    objects: group.objects.into_iter().map(Object::from).collect(),
+                    properties: Properties::new(),
+                })
+                .collect(),
+            properties: from_tiled_properties(map.properties),
+        }
+    }
+}
+
+// Maps from before Tiled had parallax factors can give them as layer properties,
+// which take precedence over the attributes
+fn property_parallax(properties: &Properties) -> (Option<f32>, Option<f32>) {
+    let factor = |name| match properties.get(name) {
+        Some(PropertyValue::Float(v)) => Some(*v),
+        Some(PropertyValue::Int(v)) => Some(*v as f32),
+        _ => None,
+    };
+    (factor("parallaxx"), factor("parallaxy"))
+}
+
+// The tiled crate doesn't read the parallax factors Tiled 1.5 added to layers, the
+// properties of object groups or which files tilesets were read from, so they're
+// read from the TMX separately. Layers and object groups, which may be nested in
+// group layers, are found by name and tilesets by their first gid. Tile collision
+// shapes are object groups too, so anything inside a tileset is skipped.
+pub(crate) fn read_layer_attributes(map: &mut Map, tmx: &str) {
+    // The elements the current one is inside of
+    let mut path: Vec<String> = Vec::new();
+    // How many layers and groups of each name have been read, as names needn't be unique
+    let mut layers_read = HashMap::new();
+    let mut groups_read = HashMap::new();
+    // The object group being read, with its parallax attributes and properties
+    let mut group: Option<(Option<usize>, (Option<f32>, Option<f32>), Properties)> = None;
+    // The group property being read, whose value may be in its text
+    let mut property: Option<(Vec<OwnedAttribute>, String)> = None;
+    for event in EventReader::new(tmx.as_bytes()) {
+        match event {
+            Ok(XmlEvent::StartElement { name, attributes, .. }) => {
+                let in_tileset = path.iter().any(|element| element == "tileset");
+                match name.local_name.as_str() {
+                    "tileset" if !in_tileset => {
+                        let first_gid = xml_attribute(&attributes, "firstgid").and_then(|v| v.parse().ok());
+                        if let Some(tileset) = map.tilesets.iter_mut().find(|t| Some(t.first_gid) == first_gid) {
+                            tileset.source = xml_attribute(&attributes, "source").map(str::to_string);
+                        }
+                    },
+                    "layer" if !in_tileset => {
+                        let name = xml_attribute(&attributes, "name").unwrap_or("");
+                        let nth = read_nth(&mut layers_read, name);
+                        if let Some(layer) = map.layers.iter_mut().filter(|l| l.name == name).nth(nth) {
+                            let (x, y) = xml_parallax(&attributes);
+                            let (property_x, property_y) = property_parallax(&layer.properties);
+                            layer.parallax = (property_x.or(x).unwrap_or(1.0), property_y.or(y).unwrap_or(1.0));
+                        }
+                    },
+                    "objectgroup" if !in_tileset => {
+                        let name = xml_attribute(&attributes, "name").unwrap_or("");
+                        let nth = read_nth(&mut groups_read, name);
+                        let index = map.object_groups.iter()
+                            .enumerate()
+                            .filter(|(_, g)| g.name == name)
+                            .nth(nth)
+                            .map(|(i, _)| i);
+                        group = Some((index, xml_parallax(&attributes), Properties::new()));
+                    },
+                    "property" if path.len() >= 2 && path[path.len() - 2..] == ["objectgroup", "properties"] => {
+                        property = Some((attributes, String::new()));
+                    },
+                    _ => (),
+                }
+                path.push(name.local_name);
+            },
+            Ok(XmlEvent::Characters(text)) | Ok(XmlEvent::CData(text)) => {
+                if let Some((_, value)) = &mut property {
+                    value.push_str(&text);
+                }
+            },
+            Ok(XmlEvent::EndElement { name }) => {
+                path.pop();
+                match name.local_name.as_str() {
+                    "property" => {
+                        let read = property.take().and_then(|(attributes, text)| xml_property(&attributes, text));
+                        if let (Some((_, _, properties)), Some((name, value))) = (&mut group, read) {
+                            properties.insert(name, value);
+                        }
+                    },
+                    "objectgroup" => {
+                        if let Some((Some(index), (x, y), properties)) = group.take() {
+                            let group = &mut map.object_groups[index];
+                            let (property_x, property_y) = property_parallax(&properties);
+                            group.parallax = (property_x.or(x).unwrap_or(1.0), property_y.or(y).unwrap_or(1.0));
+                            group.properties = properties;
+                        }
+                    },
+                    _ => (),
+                }
+            },
+            // The tiled crate has already parsed it, so anything past an error is left as it is
+            Err(_) => break,
+            _ => (),
+        }
+    }
+}
+
+// Which of the elements with this name so far one is
+fn read_nth(read: &mut HashMap<String, usize>, name: &str) -> usize {
+    let count = read.entry(name.to_string()).or_insert(0);
+    *count += 1;
+    *count - 1
+}
+
+fn xml_attribute<'a>(attributes: &'a [OwnedAttribute], name: &str) -> Option<&'a str> {
+    attributes.iter().find(|a| a.name.local_name == name).map(|a| a.value.as_str())
+}
+
+fn xml_parallax(attributes: &[OwnedAttribute]) -> (Option<f32>, Option<f32>) {
+    let factor = |name: &str| xml_attribute(attributes, name).and_then(|v| v.parse().ok());
+    (factor("parallaxx"), factor("parallaxy"))
+}
+
+// Multi-line string properties keep their value in the element's text
+fn xml_property(attributes: &[OwnedAttribute], text: String) -> Option<(String, PropertyValue)> {
+    let name = xml_attribute(attributes, "name")?.to_string();
+    let value = xml_attribute(attributes, "value").map(str::to_string).unwrap_or(text);
+    let value = match xml_attribute(attributes, "type").unwrap_or("string") {
+        "bool" => PropertyValue::Bool(value == "true"),
+        "float" => PropertyValue::Float(value.parse().ok()?),
+        "int" => PropertyValue::Int(value.parse().ok()?),
+        "color" => PropertyValue::Color(u32::from_str_radix(value.trim_start_matches('#'), 16).ok()?),
+        _ => PropertyValue::String(value),
+    };
+    Some((name, value))
+}
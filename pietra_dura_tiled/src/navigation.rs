@@ -0,0 +1,437 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::fs::read_to_string;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use crate::{Map, ObjectShape, Properties, PropertyValue};
+
+/// Which Tiled properties and object types drive navigation grid generation.
+///
+/// Layer properties apply to every tile in the layer and tile properties (set in the
+/// tileset) override them. Later layers override earlier ones. Any cell overlapped
+/// by an object of `collision_type` is blocked.
+#[derive(Debug, Clone)]
+pub struct NavSettings {
+    pub walkable_property: String,
+    pub cost_property: String,
+    pub collision_type: String,
+}
+
+impl Default for NavSettings {
+    fn default() -> Self {
+        NavSettings {
+            walkable_property: "walkable".to_string(),
+            cost_property: "cost".to_string(),
+            collision_type: "collision".to_string(),
+        }
+    }
+}
+
+/// The lowest cost of entering a cell. Lower costs, including zero and negative ones
+/// which would break path finding, are raised to it.
+pub const MIN_COST: f32 = 0.001;
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NavCell {
+    pub walkable: bool,
+    pub cost: f32,
+}
+
+impl NavCell {
+    /// What entering the cell costs when finding paths, which is never below `MIN_COST`
+    pub fn step_cost(&self) -> f32 {
+        self.cost.max(MIN_COST)
+    }
+}
+
+impl Default for NavCell {
+    fn default() -> Self {
+        NavCell { walkable: true, cost: 1.0 }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NavGrid {
+    pub width: usize,
+    pub height: usize,
+    pub tile_width: u32,
+    pub tile_height: u32,
+    pub cells: Vec<NavCell>,
+}
+
+impl NavGrid {
+    /// Reads a grid written next to a map prefab as `nav.ron`
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = read_to_string(path).map_err(|e| format!("Unable to read {:?}: {}", path, e))?;
+        ron::de::from_str(&text).map_err(|e| format!("Unable to parse {:?}: {}", path, e))
+    }
+
+    pub fn from_map(map: &Map, settings: &NavSettings) -> Self {
+        let width = map.width as usize;
+        let height = map.height as usize;
+        let mut cells = vec![NavCell::default(); width * height];
+
+        for layer in &map.layers {
+            let mut layer_cell = NavCell::default();
+            let layer_has_properties = apply_properties(&mut layer_cell, &layer.properties, settings);
+            for (y, row) in layer.tiles.iter().enumerate().take(height) {
+                for (x, gid) in row.iter().enumerate().take(width) {
+                    if *gid == 0 {
+                        continue;
+                    }
+                    let cell = &mut cells[y * width + x];
+                    if layer_has_properties {
+                        *cell = layer_cell;
+                    }
+                    if let Some(properties) = tile_properties(map, *gid) {
+                        apply_properties(cell, properties, settings);
+                    }
+                }
+            }
+        }
+
+        let tile_width = map.tile_width as f32;
+        let tile_height = map.tile_height as f32;
+        for group in &map.object_groups {
+            for object in group.objects.iter().filter(|o| o.obj_type == settings.collision_type) {
+                let (w, h) = match object.shape {
+                    ObjectShape::Rect { width, height } | ObjectShape::Ellipse { width, height } => (width, height),
+                    _ => continue,
+                };
+                let min_x = (object.x / tile_width).floor().max(0.0) as usize;
+                let min_y = (object.y / tile_height).floor().max(0.0) as usize;
+                let max_x = (((object.x + w) / tile_width).ceil().max(0.0) as usize).min(width);
+                let max_y = (((object.y + h) / tile_height).ceil().max(0.0) as usize).min(height);
+                for y in min_y..max_y {
+                    for x in min_x..max_x {
+                        cells[y * width + x].walkable = false;
+                    }
+                }
+            }
+        }
+
+        NavGrid {
+            width,
+            height,
+            tile_width: map.tile_width,
+            tile_height: map.tile_height,
+            cells,
+        }
+    }
+
+    pub fn cell(&self, x: usize, y: usize) -> Option<&NavCell> {
+        if x < self.width && y < self.height {
+            Some(&self.cells[y * self.width + x])
+        } else {
+            None
+        }
+    }
+
+    pub fn is_walkable(&self, x: usize, y: usize) -> bool {
+        self.cell(x, y).map(|c| c.walkable).unwrap_or(false)
+    }
+
+    // The inverse of `TileCell::position`
+    pub fn cell_at_position(&self, x: f32, y: f32) -> Option<(usize, usize)> {
+        let cx = (x / self.tile_width as f32).floor();
+        let cy = (-y / self.tile_height as f32).floor();
+        if cx < 0.0 || cy < 0.0 || cx as usize >= self.width || cy as usize >= self.height {
+            None
+        } else {
+            Some((cx as usize, cy as usize))
+        }
+    }
+
+    fn neighbors(&self, x: usize, y: usize) -> impl Iterator<Item = (usize, usize)> + '_ {
+        let candidates = [
+            (x as isize - 1, y as isize),
+            (x as isize + 1, y as isize),
+            (x as isize, y as isize - 1),
+            (x as isize, y as isize + 1),
+        ];
+        candidates
+            .to_vec()
+            .into_iter()
+            .filter(move |(nx, ny)| *nx >= 0 && *ny >= 0 && self.is_walkable(*nx as usize, *ny as usize))
+            .map(|(nx, ny)| (nx as usize, ny as usize))
+    }
+
+    /// A* over the walkable cells using four way movement. Entering a cell costs that
+    /// cell's `step_cost`. Returns the cells from `start` to `goal` inclusive, or None
+    /// if either is blocked or outside the grid or the goal can't be reached.
+    pub fn find_path(&self, start: (usize, usize), goal: (usize, usize)) -> Option<Vec<(usize, usize)>> {
+        if !self.is_walkable(start.0, start.1) || !self.is_walkable(goal.0, goal.1) {
+            return None;
+        }
+        let min_cost = self.cells.iter()
+            .filter(|c| c.walkable)
+            .map(NavCell::step_cost)
+            .fold(std::f32::INFINITY, f32::min);
+        let heuristic = |(x, y): (usize, usize)| {
+            let dx = (x as isize - goal.0 as isize).abs();
+            let dy = (y as isize - goal.1 as isize).abs();
+            (dx + dy) as f32 * min_cost
+        };
+
+        let mut distances = vec![std::f32::INFINITY; self.cells.len()];
+        let mut came_from = vec![None; self.cells.len()];
+        let mut open = BinaryHeap::new();
+        distances[self.index(start)] = 0.0;
+        open.push(Candidate { cost: heuristic(start), cell: start });
+
+        while let Some(Candidate { cell, .. }) = open.pop() {
+            if cell == goal {
+                let mut path = vec![goal];
+                let mut current = goal;
+                while let Some(previous) = came_from[self.index(current)] {
+                    path.push(previous);
+                    current = previous;
+                }
+                path.reverse();
+                return Some(path);
+            }
+            let distance = distances[self.index(cell)];
+            for next in self.neighbors(cell.0, cell.1) {
+                let next_distance = distance + self.cells[self.index(next)].step_cost();
+                if next_distance < distances[self.index(next)] {
+                    distances[self.index(next)] = next_distance;
+                    came_from[self.index(next)] = Some(cell);
+                    open.push(Candidate { cost: next_distance + heuristic(next), cell: next });
+                }
+            }
+        }
+        None
+    }
+
+    /// Builds a flow field where every walkable cell which can reach `goal` points at
+    /// its next step along the cheapest route there.
+    pub fn flow_field(&self, goal: (usize, usize)) -> FlowField {
+        let mut distances = vec![std::f32::INFINITY; self.cells.len()];
+        let mut open = BinaryHeap::new();
+        if self.is_walkable(goal.0, goal.1) {
+            distances[self.index(goal)] = 0.0;
+            open.push(Candidate { cost: 0.0, cell: goal });
+        }
+
+        while let Some(Candidate { cost, cell }) = open.pop() {
+            if cost > distances[self.index(cell)] {
+                continue;
+            }
+            // Walking out of `next` into `cell` costs what it costs to enter `cell`
+            let step = self.cells[self.index(cell)].step_cost();
+            for next in self.neighbors(cell.0, cell.1) {
+                let next_distance = cost + step;
+                if next_distance < distances[self.index(next)] {
+                    distances[self.index(next)] = next_distance;
+                    open.push(Candidate { cost: next_distance, cell: next });
+                }
+            }
+        }
+
+        let mut directions = vec![None; self.cells.len()];
+        for y in 0..self.height {
+            for x in 0..self.width {
+                if (x, y) == goal || !distances[self.index((x, y))].is_finite() {
+                    continue;
+                }
+                // The cheapest step counts what it costs to enter the neighbor
+                let through = |n: &(usize, usize)| distances[self.index(*n)] + self.cells[self.index(*n)].step_cost();
+                let best = self.neighbors(x, y)
+                    .filter(|n| distances[self.index(*n)].is_finite())
+                    .min_by(|a, b| through(a).partial_cmp(&through(b)).unwrap_or(Ordering::Equal));
+                directions[self.index((x, y))] = best;
+            }
+        }
+
+        FlowField {
+            width: self.width,
+            height: self.height,
+            goal,
+            directions,
+        }
+    }
+
+    fn index(&self, (x, y): (usize, usize)) -> usize {
+        y * self.width + x
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FlowField {
+    pub width: usize,
+    pub height: usize,
+    pub goal: (usize, usize),
+    directions: Vec<Option<(usize, usize)>>,
+}
+
+impl FlowField {
+    /// The next cell to move to from `(x, y)`, or None if the goal is unreachable
+    /// from there (or `(x, y)` is the goal).
+    pub fn next(&self, x: usize, y: usize) -> Option<(usize, usize)> {
+        if x < self.width && y < self.height {
+            self.directions[y * self.width + x]
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct Candidate {
+    cost: f32,
+    cell: (usize, usize),
+}
+
+impl Eq for Candidate {}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Reversed so the BinaryHeap pops the cheapest candidate first
+        other.cost.partial_cmp(&self.cost).unwrap_or(Ordering::Equal)
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+fn tile_properties(map: &Map, gid: u32) -> Option<&Properties> {
+    let tileset = map.tilesets.iter()
+        .filter(|t| t.first_gid <= gid)
+        .max_by_key(|t| t.first_gid)?;
+    let id = gid - tileset.first_gid;
+    tileset.tiles.iter()
+        .find(|t| t.id == id)
+        .map(|t| &t.properties)
+}
+
+// Returns true if any navigation property was present
+fn apply_properties(cell: &mut NavCell, properties: &Properties, settings: &NavSettings) -> bool {
+    let mut found = false;
+    if let Some(PropertyValue::Bool(walkable)) = properties.get(&settings.walkable_property) {
+        cell.walkable = *walkable;
+        found = true;
+    }
+    let cost = match properties.get(&settings.cost_property) {
+        Some(PropertyValue::Float(cost)) => Some(*cost),
+        Some(PropertyValue::Int(cost)) => Some(*cost as f32),
+        _ => None,
+    };
+    if let Some(cost) = cost {
+        cell.cost = cost.max(MIN_COST);
+        found = true;
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `#` is blocked, a digit is a cell with that cost and anything else costs 1
+    fn grid(rows: &[&str]) -> NavGrid {
+        let cells = rows.iter()
+            .flat_map(|row| row.chars())
+            .map(|c| match c {
+                '#' => NavCell { walkable: false, cost: 1.0 },
+                c => NavCell { walkable: true, cost: c.to_digit(10).map(|d| d as f32).unwrap_or(1.0) },
+            })
+            .collect();
+        NavGrid { width: rows[0].len(), height: rows.len(), tile_width: 32, tile_height: 32, cells }
+    }
+
+    #[test]
+    fn paths_go_around_blocked_cells() {
+        let grid = grid(&[
+            "...",
+            "##.",
+            "...",
+        ]);
+        assert_eq!(
+            grid.find_path((0, 0), (0, 2)),
+            Some(vec![(0, 0), (1, 0), (2, 0), (2, 1), (2, 2), (1, 2), (0, 2)]),
+        );
+        assert_eq!(grid.find_path((1, 1), (0, 2)), None);
+        assert_eq!(grid.find_path((0, 0), (0, 1)), None);
+    }
+
+    #[test]
+    fn paths_avoid_expensive_cells() {
+        let grid = grid(&[
+            ".5.",
+            "...",
+        ]);
+        assert_eq!(grid.find_path((0, 0), (2, 0)), Some(vec![(0, 0), (0, 1), (1, 1), (2, 1), (2, 0)]));
+        let grid = self::grid(&[
+            ".2.",
+            "...",
+        ]);
+        assert_eq!(grid.find_path((0, 0), (2, 0)), Some(vec![(0, 0), (1, 0), (2, 0)]));
+    }
+
+    #[test]
+    fn unreachable_goals_have_no_path() {
+        let grid = grid(&[
+            ".#.",
+            ".#.",
+        ]);
+        assert_eq!(grid.find_path((0, 0), (2, 1)), None);
+        let flow = grid.flow_field((2, 1));
+        assert_eq!(flow.next(0, 0), None);
+        assert_eq!(flow.next(2, 0), Some((2, 1)));
+    }
+
+    #[test]
+    fn out_of_bounds_cells_have_no_path() {
+        let grid = grid(&[
+            "..",
+            "..",
+        ]);
+        assert_eq!(grid.find_path((2, 0), (0, 0)), None);
+        assert_eq!(grid.find_path((0, 0), (0, 5)), None);
+        let flow = grid.flow_field((7, 7));
+        assert_eq!(flow.next(0, 0), None);
+        assert_eq!(grid.flow_field((0, 0)).next(3, 0), None);
+    }
+
+    #[test]
+    fn flow_fields_follow_the_cheapest_route() {
+        let grid = grid(&[
+            ".5.",
+            "...",
+        ]);
+        let flow = grid.flow_field((2, 0));
+        let mut cell = (0, 0);
+        let mut route = vec![cell];
+        while let Some(next) = flow.next(cell.0, cell.1) {
+            cell = next;
+            route.push(cell);
+        }
+        assert_eq!(route, grid.find_path((0, 0), (2, 0)).unwrap());
+        assert_eq!(flow.next(2, 0), None);
+    }
+
+    #[test]
+    fn costs_below_the_minimum_are_raised() {
+        let settings = NavSettings::default();
+        for value in &[PropertyValue::Int(-3), PropertyValue::Float(0.0), PropertyValue::Float(-0.5)] {
+            let mut properties = Properties::new();
+            properties.insert("cost".to_string(), value.clone());
+            let mut cell = NavCell::default();
+            assert!(apply_properties(&mut cell, &properties, &settings));
+            assert_eq!(cell.cost, MIN_COST);
+        }
+
+        // Cells loaded with bad costs still give the shortest route
+        let mut grid = grid(&[
+            "...",
+            "...",
+        ]);
+        grid.cells[1].cost = -10.0;
+        let path = grid.find_path((0, 0), (2, 0)).unwrap();
+        assert_eq!(path.len(), 3);
+    }
+}
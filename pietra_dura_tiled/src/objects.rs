@@ -0,0 +1,113 @@
+// Finding the entities made from Tiled objects by their names and types
+
+use std::collections::HashMap;
+
+use amethyst::{
+    assets::PrefabData,
+    core::Named,
+    derive::PrefabData,
+    ecs::{prelude::*, world::Index},
+    error::Error,
+};
+use serde::{Deserialize, Serialize};
+
+/// The type of the Tiled object an entity was made from, which every object gets
+/// even if its type is empty. Its name, if it had one, goes in Amethyst's `Named`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize, PrefabData)]
+#[prefab(Component)]
+#[serde(transparent)]
+pub struct ObjectType(pub String);
+
+// Flagged so `ObjectIndexSystem` only has to look at objects which were added or removed
+impl Component for ObjectType {
+    type Storage = FlaggedStorage<Self, DenseVecStorage<Self>>;
+}
+
+#[derive(Default)]
+pub struct ObjectIndex {
+    names: HashMap<String, Vec<Entity>>,
+    types: HashMap<String, Vec<Entity>>,
+    // The name and type each entity was indexed under, so it can be removed again
+    entries: HashMap<Index, (Entity, Option<String>, String)>,
+}
+
+impl ObjectIndex {
+    /// The entity with a name, for names which are only used once
+    pub fn named(&self, name: &str) -> Option<Entity> {
+        self.all_named(name).first().cloned()
+    }
+
+    pub fn all_named(&self, name: &str) -> &[Entity] {
+        self.names.get(name).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    pub fn of_type(&self, obj_type: &str) -> &[Entity] {
+        self.types.get(obj_type).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    fn insert(&mut self, entity: Entity, name: Option<String>, obj_type: String) {
+        self.remove(entity.id());
+        if let Some(name) = &name {
+            self.names.entry(name.clone()).or_default().push(entity);
+        }
+        self.types.entry(obj_type.clone()).or_default().push(entity);
+        self.entries.insert(entity.id(), (entity, name, obj_type));
+    }
+
+    fn remove(&mut self, id: Index) {
+        if let Some((entity, name, obj_type)) = self.entries.remove(&id) {
+            if let Some(name) = name {
+                remove_entity(&mut self.names, &name, entity);
+            }
+            remove_entity(&mut self.types, &obj_type, entity);
+        }
+    }
+}
+
+fn remove_entity(entities: &mut HashMap<String, Vec<Entity>>, key: &str, entity: Entity) {
+    if let Some(list) = entities.get_mut(key) {
+        list.retain(|e| *e != entity);
+        if list.is_empty() {
+            entities.remove(key);
+        }
+    }
+}
+
+/// Keeps the `ObjectIndex` up to date as `ObjectType` components are added and
+/// removed, which is when levels and regions are spawned and removed, so it includes
+/// a level's objects from the frame after it is spawned. An object's `Named` is
+/// indexed along with its type.
+#[derive(Default)]
+pub struct ObjectIndexSystem {
+    events: Option<ReaderId<ComponentEvent>>,
+}
+
+impl<'s> System<'s> for ObjectIndexSystem {
+    type SystemData = (
+        Entities<'s>,
+        ReadStorage<'s, Named>,
+        ReadStorage<'s, ObjectType>,
+        Write<'s, ObjectIndex>,
+    );
+
+    fn run(&mut self, (entities, names, types, mut index): Self::SystemData) {
+        let events = self.events.as_mut().expect("ObjectIndexSystem wasn't set up");
+        for event in types.channel().read(events) {
+            match event {
+                ComponentEvent::Inserted(id) | ComponentEvent::Modified(id) => {
+                    let entity = entities.entity(*id);
+                    if let Some(obj_type) = types.get(entity) {
+                        let name = names.get(entity).map(|n| n.name.to_string());
+                        index.insert(entity, name, obj_type.0.clone());
+                    }
+                },
+                ComponentEvent::Removed(id) => index.remove(*id),
+            }
+        }
+    }
+
+    fn setup(&mut self, res: &mut Resources) {
+        Self::SystemData::setup(res);
+        self.events = Some(WriteStorage::<ObjectType>::fetch(res).register_reader());
+    }
+}
@@ -0,0 +1,92 @@
+use std::str::FromStr;
+
+use amethyst::{
+    assets::Format,
+    error::Error,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// How the map prefab is encoded. Pretty RON is easiest to read and diff, the
+/// others are smaller and faster to load in shipping builds.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OutputFormat {
+    PrettyRon,
+    CompactRon,
+    Json,
+    /// Loaded with `BincodeFormat`
+    Bincode,
+}
+
+impl Default for OutputFormat {
+    fn default() -> Self {
+        OutputFormat::PrettyRon
+    }
+}
+
+impl OutputFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::PrettyRon | OutputFormat::CompactRon => "ron",
+            OutputFormat::Json => "json",
+            OutputFormat::Bincode => "bin",
+        }
+    }
+
+    /// The name of the prefab file, `map.ron`, `map.json` or `map.bin`
+    pub fn prefab_file(self) -> String {
+        format!("map.{}", self.extension())
+    }
+
+    pub fn encode<T: Serialize>(self, value: &T) -> Vec<u8> {
+        match self {
+            OutputFormat::PrettyRon => ron::ser::to_string_pretty(value, ron::ser::PrettyConfig::default())
+                .expect("Failed to encode map prefab file")
+                .into_bytes(),
+            OutputFormat::CompactRon => ron::ser::to_string(value)
+                .expect("Failed to encode map prefab file")
+                .into_bytes(),
+            OutputFormat::Json => serde_json::to_vec(value)
+                .expect("Failed to encode map prefab file"),
+            OutputFormat::Bincode => bincode::serialize(value)
+                .expect("Failed to encode map prefab file"),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, String> {
+        match self {
+            OutputFormat::PrettyRon | OutputFormat::CompactRon => ron::de::from_bytes(bytes).map_err(|e| e.to_string()),
+            OutputFormat::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+            OutputFormat::Bincode => bincode::deserialize(bytes).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pretty-ron" => Ok(OutputFormat::PrettyRon),
+            "ron" => Ok(OutputFormat::CompactRon),
+            "json" => Ok(OutputFormat::Json),
+            "bincode" => Ok(OutputFormat::Bincode),
+            _ => Err(format!("Unknown output format {:?}, expected pretty-ron, ron, json or bincode", s)),
+        }
+    }
+}
+
+/// Loads assets encoded with bincode, like maps converted with `OutputFormat::Bincode`.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct BincodeFormat;
+
+impl<D> Format<D> for BincodeFormat
+    where D: DeserializeOwned + Send + Sync + 'static {
+    fn name(&self) -> &'static str {
+        "BINCODE"
+    }
+
+    fn import_simple(&self, bytes: Vec<u8>) -> Result<D, Error> {
+        bincode::deserialize(&bytes)
+            .map_err(|e| Error::from_string(format!("Failed to decode bincode asset: {}", e)))
+    }
+}
@@ -0,0 +1,59 @@
+// Layers which scroll at a different speed to the camera, as set up in Tiled
+
+use amethyst::{
+    assets::PrefabData,
+    core::Transform,
+    derive::PrefabData,
+    ecs::prelude::*,
+    error::Error,
+    renderer::camera::Camera,
+};
+use serde::{Deserialize, Serialize};
+use specs_derive::Component;
+
+/// The parallax factors of the layer or object group an entity came from. At 1 it
+/// moves with the rest of the level, at 0 it stays fixed to the camera and anything
+/// between scrolls more slowly, as in Tiled.
+#[derive(Debug, Copy, Clone, PartialEq, Component, Serialize, Deserialize, PrefabData)]
+#[prefab(Component)]
+pub struct Parallax {
+    pub x: f32,
+    pub y: f32,
+    // Where the entity was placed, taken from its transform the first time
+    // `ParallaxSystem` sees it
+    #[serde(skip)]
+    origin: Option<(f32, f32)>,
+}
+
+impl Parallax {
+    pub fn new(x: f32, y: f32) -> Self {
+        Parallax { x, y, origin: None }
+    }
+}
+
+/// Offsets entities with a `Parallax` by how far the first camera is from the
+/// map's origin, which is where Tiled measures parallax from, so layers line up
+/// the same way they do in Tiled. Add it before the transform system.
+#[derive(Default)]
+pub struct ParallaxSystem;
+
+impl<'s> System<'s> for ParallaxSystem {
+    type SystemData = (
+        ReadStorage<'s, Camera>,
+        WriteStorage<'s, Parallax>,
+        WriteStorage<'s, Transform>,
+    );
+
+    fn run(&mut self, (cameras, mut parallaxes, mut transforms): Self::SystemData) {
+        let (camera_x, camera_y) = match (&cameras, &transforms).join().next() {
+            Some((_, transform)) => (transform.translation().x, transform.translation().y),
+            None => return,
+        };
+        for (parallax, transform) in (&mut parallaxes, &mut transforms).join() {
+            let (x, y) = (transform.translation().x, transform.translation().y);
+            let (x, y) = *parallax.origin.get_or_insert((x, y));
+            transform.set_translation_x(x + camera_x * (1.0 - parallax.x));
+            transform.set_translation_y(y + camera_y * (1.0 - parallax.y));
+        }
+    }
+}
@@ -0,0 +1,320 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::read;
+use std::io::Result;
+use std::path::{Path, PathBuf};
+
+use crate::{BuildManifest, MapFile, MapStats};
+
+// Lines of unchanged context shown around each change in a diff
+const CONTEXT: usize = 3;
+// Lines added and removed past which a diff isn't worth working out
+const MAX_EDITS: usize = 1000;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FileChange {
+    Created,
+    Modified,
+    Unchanged,
+}
+
+#[derive(Debug, Clone)]
+pub struct PlannedFile {
+    pub path: PathBuf,
+    pub change: FileChange,
+}
+
+/// What `MapPrefab::write` would do to an output directory.
+#[derive(Debug, Clone)]
+pub struct WritePlan {
+    pub files: Vec<PlannedFile>,
+    // A diff of the prefab if it is text and has changed
+    pub prefab_diff: Option<String>,
+    // Entities of each kind before and after. Before comes from the build manifest
+    // so is zero for everything if the directory has never been written.
+    pub entity_deltas: BTreeMap<String, (usize, usize)>,
+}
+
+impl WritePlan {
+    pub(crate) fn new(files: &[MapFile], prefab_file: &str, stats: &MapStats, dir: &Path) -> Result<Self> {
+        let mut planned = Vec::with_capacity(files.len());
+        let mut prefab_diff = None;
+        for file in files {
+            let (dest, buffer) = match file {
+                MapFile::Path(src, dest) => (dest, read(src)?),
+                MapFile::Data(dest, buffer) => (dest, buffer.clone()),
+            };
+            let existing = read(dir.join(dest)).ok();
+            let change = match &existing {
+                None => FileChange::Created,
+                Some(existing) if *existing == buffer => FileChange::Unchanged,
+                Some(_) => FileChange::Modified,
+            };
+            if change != FileChange::Unchanged && dest == Path::new(prefab_file) {
+                let old = existing.map(String::from_utf8).unwrap_or_else(|| Ok(String::new()));
+                if let (Ok(old), Ok(new)) = (old, String::from_utf8(buffer)) {
+                    prefab_diff = Some(line_diff(&old, &new));
+                }
+            }
+            planned.push(PlannedFile { path: dest.clone(), change });
+        }
+
+        let before = BuildManifest::load(dir).map(|m| m.entity_kinds).unwrap_or_default();
+        let mut entity_deltas = BTreeMap::new();
+        for (kind, count) in &before {
+            entity_deltas.insert(kind.clone(), (*count, 0));
+        }
+        for (kind, count) in &stats.entity_kinds {
+            entity_deltas.entry(kind.clone()).or_insert((0, 0)).1 = *count;
+        }
+
+        Ok(WritePlan { files: planned, prefab_diff, entity_deltas })
+    }
+
+    pub fn has_changes(&self) -> bool {
+        self.files.iter().any(|f| f.change != FileChange::Unchanged)
+    }
+}
+
+impl fmt::Display for WritePlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for file in &self.files {
+            let change = match file.change {
+                FileChange::Created => "create",
+                FileChange::Modified => "modify",
+                FileChange::Unchanged => "unchanged",
+            };
+            writeln!(f, "{:>10} {}", change, file.path.display())?;
+        }
+        for (kind, (before, after)) in &self.entity_deltas {
+            if before != after {
+                writeln!(f, "{:>10} {} -> {} ({:+})", kind, before, after, *after as i64 - *before as i64)?;
+            }
+        }
+        if let Some(diff) = &self.prefab_diff {
+            write!(f, "{}", diff)?;
+        }
+        Ok(())
+    }
+}
+
+enum Edit<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// A line based diff in the style of `diff -u`, without the file headers. If more
+/// than `MAX_EDITS` lines differ it only says the file was rewritten.
+pub fn line_diff(old: &str, new: &str) -> String {
+    let old: Vec<_> = old.lines().collect();
+    let new: Vec<_> = new.lines().collect();
+
+    // Only the part between the common prefix and suffix needs the full comparison,
+    // which for regenerated maps is usually small
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let middle = match shortest_edit(&old[prefix..old.len() - suffix], &new[prefix..new.len() - suffix]) {
+        Some(middle) => middle,
+        None => return format!("file rewritten: {} lines -> {} lines\n", old.len(), new.len()),
+    };
+    let mut edits: Vec<Edit<'_>> = old[..prefix].iter().map(|l| Edit::Same(l)).collect();
+    edits.extend(middle);
+    edits.extend(old[old.len() - suffix..].iter().map(|l| Edit::Same(l)));
+
+    // Line of the old and new file each edit starts at, counting from 0
+    let mut positions = Vec::with_capacity(edits.len() + 1);
+    let (mut old_line, mut new_line) = (0, 0);
+    for edit in &edits {
+        positions.push((old_line, new_line));
+        match edit {
+            Edit::Same(_) => {
+                old_line += 1;
+                new_line += 1;
+            },
+            Edit::Removed(_) => old_line += 1,
+            Edit::Added(_) => new_line += 1,
+        }
+    }
+    positions.push((old_line, new_line));
+
+    let next_change = |from: usize| edits[from..].iter().position(|e| !matches_same(e)).map(|i| i + from);
+    let mut out = String::new();
+    let mut from = 0;
+    while let Some(first) = next_change(from) {
+        // Changes close enough for their context to touch share a hunk
+        let mut last = first;
+        while let Some(next) = next_change(last + 1) {
+            if next - last - 1 > 2 * CONTEXT {
+                break;
+            }
+            last = next;
+        }
+        let start = first.saturating_sub(CONTEXT);
+        let end = (last + CONTEXT + 1).min(edits.len());
+        let (old_start, new_start) = positions[start];
+        let (old_end, new_end) = positions[end];
+        out.push_str(&format!(
+            "@@ -{} +{} @@\n",
+            hunk_range(old_start, old_end - old_start),
+            hunk_range(new_start, new_end - new_start),
+        ));
+        for edit in &edits[start..end] {
+            match edit {
+                Edit::Same(line) => out.push_str(&format!(" {}\n", line)),
+                Edit::Removed(line) => out.push_str(&format!("-{}\n", line)),
+                Edit::Added(line) => out.push_str(&format!("+{}\n", line)),
+            }
+        }
+        from = end;
+    }
+    out
+}
+
+// As in `diff -u` an empty range is numbered after the line it follows
+fn hunk_range(start: usize, len: usize) -> String {
+    if len == 0 {
+        format!("{},0", start)
+    } else {
+        format!("{},{}", start + 1, len)
+    }
+}
+
+// Myers' diff, which takes time proportional to the lengths times the number of
+// lines added and removed. None if that is more than `MAX_EDITS`.
+fn shortest_edit<'a>(old: &[&'a str], new: &[&'a str]) -> Option<Vec<Edit<'a>>> {
+    let (n, m) = (old.len() as isize, new.len() as isize);
+    let max = (n + m).min(MAX_EDITS as isize);
+    let offset = max + 1;
+    // Furthest x reached on each diagonal k = x - y, kept from before every round
+    // so the path can be walked back
+    let mut furthest = vec![0isize; 2 * offset as usize + 1];
+    let mut trace = Vec::new();
+    for d in 0..=max {
+        trace.push(furthest.clone());
+        for k in (-d..=d).step_by(2) {
+            let mut x = if is_addition(&furthest, offset, d, k) {
+                furthest[(offset + k + 1) as usize]
+            } else {
+                furthest[(offset + k - 1) as usize] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && old[x as usize] == new[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            furthest[(offset + k) as usize] = x;
+            if x >= n && y >= m {
+                return Some(walk_back(old, new, &trace, offset));
+            }
+        }
+    }
+    None
+}
+
+// Whether the furthest point on diagonal k after d edits is reached by adding a
+// line from diagonal k + 1 rather than removing one from k - 1
+fn is_addition(furthest: &[isize], offset: isize, d: isize, k: isize) -> bool {
+    k == -d || (k != d && furthest[(offset + k - 1) as usize] < furthest[(offset + k + 1) as usize])
+}
+
+fn walk_back<'a>(old: &[&'a str], new: &[&'a str], trace: &[Vec<isize>], offset: isize) -> Vec<Edit<'a>> {
+    let mut edits = Vec::new();
+    let (mut x, mut y) = (old.len() as isize, new.len() as isize);
+    for (d, furthest) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let k = x - y;
+        let prev_k = if is_addition(furthest, offset, d, k) { k + 1 } else { k - 1 };
+        let prev_x = furthest[(offset + prev_k) as usize];
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            x -= 1;
+            y -= 1;
+            edits.push(Edit::Same(old[x as usize]));
+        }
+        if d > 0 {
+            if x == prev_x {
+                y -= 1;
+                edits.push(Edit::Added(new[y as usize]));
+            } else {
+                x -= 1;
+                edits.push(Edit::Removed(old[x as usize]));
+            }
+        }
+    }
+    edits.reverse();
+    edits
+}
+
+fn matches_same(edit: &Edit<'_>) -> bool {
+    match edit {
+        Edit::Same(_) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbered(lines: std::ops::Range<usize>) -> String {
+        lines.map(|i| format!("line {}\n", i)).collect()
+    }
+
+    #[test]
+    fn identical_files_have_no_hunks() {
+        assert_eq!(line_diff("a\nb\n", "a\nb\n"), "");
+    }
+
+    #[test]
+    fn changed_line_has_context_and_ranges() {
+        let new = numbered(1..11).replace("line 5\n", "five\n");
+        assert_eq!(
+            line_diff(&numbered(1..11), &new),
+            "@@ -2,7 +2,7 @@\n line 2\n line 3\n line 4\n-line 5\n+five\n line 6\n line 7\n line 8\n",
+        );
+    }
+
+    #[test]
+    fn empty_ranges_are_numbered_after_the_line_they_follow() {
+        assert_eq!(line_diff("", "a\nb\n"), "@@ -0,0 +1,2 @@\n+a\n+b\n");
+        assert_eq!(line_diff("a\nb\n", "a\n"), "@@ -1,2 +1,1 @@\n a\n-b\n");
+    }
+
+    #[test]
+    fn distant_changes_get_separate_hunks() {
+        let old = numbered(1..21);
+        let new = old.replace("line 2\n", "two\n").replace("line 18\n", "eighteen\n");
+        assert_eq!(
+            line_diff(&old, &new),
+            "@@ -1,5 +1,5 @@\n line 1\n-line 2\n+two\n line 3\n line 4\n line 5\n\
+             @@ -15,6 +15,6 @@\n line 15\n line 16\n line 17\n-line 18\n+eighteen\n line 19\n line 20\n",
+        );
+    }
+
+    #[test]
+    fn nearby_changes_share_a_hunk() {
+        let old = numbered(1..21);
+        let new = old.replace("line 5\n", "five\n").replace("line 11\n", "eleven\n");
+        let diff = line_diff(&old, &new);
+        assert!(diff.starts_with("@@ -2,13 +2,13 @@\n"), "{}", diff);
+        assert_eq!(diff.matches("@@").count(), 2, "{}", diff);
+    }
+
+    #[test]
+    fn finds_the_shortest_edit() {
+        assert_eq!(
+            line_diff("a\nb\nc\na\nb\nb\na\n", "c\nb\na\nb\na\nc\n"),
+            "@@ -1,7 +1,6 @@\n-a\n-b\n c\n+b\n a\n b\n-b\n a\n+c\n",
+        );
+    }
+
+    #[test]
+    fn large_rewrites_are_not_diffed() {
+        let old = numbered(0..600);
+        let new = numbered(1000..1600);
+        assert_eq!(line_diff(&old, &new), "file rewritten: 600 lines -> 600 lines\n");
+    }
+}
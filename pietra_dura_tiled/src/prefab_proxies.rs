@@ -3,12 +3,35 @@ use amethyst::{
     renderer::{sprite::prefab::SpriteSheetReference, sprite::Sprites},
 };
 use rendy::hal::image::SamplerInfo;
-use serde::Serialize;
+use serde::{ser::SerializeStruct, Serialize, Serializer};
 
-#[derive(Serialize, Debug, Clone)]
+#[derive(Debug, Clone)]
 pub struct PrefabEntity<P> {
+    pub parent: Option<usize>,
     pub data: Option<P>
 }
+
+// Text formats leave out a missing parent, but binary formats can't skip fields so
+// it is always written for them.
+impl<P: Serialize> Serialize for PrefabEntity<P> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let skip_parent = self.parent.is_none() && serializer.is_human_readable();
+        let mut state = serializer.serialize_struct("PrefabEntity", if skip_parent { 1 } else { 2 })?;
+        if skip_parent {
+            state.skip_field("parent")?;
+        } else {
+            state.serialize_field("parent", &self.parent)?;
+        }
+        state.serialize_field("data", &self.data)?;
+        state.end()
+    }
+}
+
+impl<P> PrefabEntity<P> {
+    pub fn new(data: P) -> Self {
+        PrefabEntity { parent: None, data: Some(data) }
+    }
+}
 #[derive(Serialize, Debug)]
 pub struct Prefab<P> {
     pub entities: Vec<PrefabEntity<P>>,
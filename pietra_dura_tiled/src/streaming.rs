@@ -0,0 +1,227 @@
+// Streaming the regions of a map in and out around the camera
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::marker::PhantomData;
+use std::path::Path;
+
+use amethyst::{
+    assets::{Handle, Prefab, PrefabData, PrefabLoader},
+    core::{transform::Parent, Transform},
+    ecs::prelude::*,
+    renderer::camera::Camera,
+};
+use serde::{Deserialize, Serialize};
+
+pub const REGION_MANIFEST_FILE: &str = "regions.ron";
+
+// Where region prefabs go, inside the map's directory
+pub(crate) const REGION_DIR: &str = "regions";
+
+/// A region's grid position and the asset path of its prefab. Region `(x, y)`
+/// covers `x * region_width` up to but not including `(x + 1) * region_width` and
+/// likewise for y, in pixels with y going down as in Tiled.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MapRegion {
+    pub x: i32,
+    pub y: i32,
+    pub prefab: String,
+}
+
+/// Written as `regions.ron` next to a map converted with
+/// `ConversionSettings::with_regions`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RegionManifest {
+    pub region_width: f32,
+    pub region_height: f32,
+    pub regions: Vec<MapRegion>,
+}
+
+impl RegionManifest {
+    pub fn load(path: &Path) -> Option<Self> {
+        let f = File::open(path).ok()?;
+        ron::de::from_reader(f).ok()
+    }
+
+    /// Regions overlapping the area within `margin` of a point, in pixels with y going down
+    pub fn regions_near(&self, x: f32, y: f32, margin: (f32, f32)) -> impl Iterator<Item = &MapRegion> {
+        let (width, height) = (self.region_width, self.region_height);
+        self.regions.iter().filter(move |r| {
+            let (left, top) = (r.x as f32 * width, r.y as f32 * height);
+            left <= x + margin.0 && x - margin.0 < left + width
+                && top <= y + margin.1 && y - margin.1 < top + height
+        })
+    }
+}
+
+/// Attach to an entity, usually the level's root, to stream the regions of a map
+/// in and out as the camera moves. Regions which come within `load_margin` of the
+/// camera are loaded and ones which get further than `unload_margin` away are
+/// removed along with everything in them, physics bodies included. `load` is used
+/// to load each region's prefab from its asset path.
+pub struct StreamRegions<P> {
+    manifest: RegionManifest,
+    load_margin: (f32, f32),
+    unload_margin: (f32, f32),
+    load: Box<dyn Fn(&PrefabLoader<'_, P>, &str) -> Handle<Prefab<P>> + Send + Sync>,
+    // The root entity of each loaded region
+    loaded: BTreeMap<(i32, i32), Entity>,
+}
+
+impl<P> StreamRegions<P> {
+    /// By default regions a region's size away are loaded and kept until they are
+    /// twice that away, so moving back and forth over a border doesn't keep
+    /// reloading them.
+    pub fn new<F>(manifest: RegionManifest, load: F) -> Self
+        where F: Fn(&PrefabLoader<'_, P>, &str) -> Handle<Prefab<P>> + Send + Sync + 'static {
+        let size = (manifest.region_width, manifest.region_height);
+        StreamRegions {
+            manifest,
+            load_margin: size,
+            unload_margin: (size.0 * 2.0, size.1 * 2.0),
+            load: Box::new(load),
+            loaded: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_margins(mut self, load: (f32, f32), unload: (f32, f32)) -> Self {
+        self.load_margin = load;
+        self.unload_margin = unload_margin(load, unload);
+        self
+    }
+
+    pub fn manifest(&self) -> &RegionManifest {
+        &self.manifest
+    }
+
+    pub fn is_loaded(&self, x: i32, y: i32) -> bool {
+        self.loaded.contains_key(&(x, y))
+    }
+
+    pub fn loaded(&self) -> impl Iterator<Item = ((i32, i32), Entity)> + '_ {
+        self.loaded.iter().map(|(region, entity)| (*region, *entity))
+    }
+}
+
+// `unload` is kept at least as large as `load`, otherwise regions would be
+// unloaded as soon as they were loaded
+fn unload_margin(load: (f32, f32), unload: (f32, f32)) -> (f32, f32) {
+    (unload.0.max(load.0), unload.1.max(load.1))
+}
+
+impl<P: Send + Sync + 'static> Component for StreamRegions<P> {
+    type Storage = HashMapStorage<Self>;
+}
+
+/// Loads and unloads the regions of every `StreamRegions` around the first camera.
+/// The camera's translation is taken as pixels with y going up, so it is flipped
+/// to compare with the regions.
+pub struct RegionStreamingSystem<P> {
+    phantom: PhantomData<P>,
+}
+
+impl<P> Default for RegionStreamingSystem<P> {
+    fn default() -> Self {
+        RegionStreamingSystem { phantom: PhantomData }
+    }
+}
+
+impl<'s, P> System<'s> for RegionStreamingSystem<P>
+    where P: PrefabData<'s> + Send + Sync + 'static {
+    type SystemData = (
+        Entities<'s>,
+        ReadStorage<'s, Camera>,
+        ReadStorage<'s, Transform>,
+        ReadStorage<'s, Parent>,
+        WriteStorage<'s, StreamRegions<P>>,
+        WriteStorage<'s, Handle<Prefab<P>>>,
+        PrefabLoader<'s, P>,
+    );
+
+    fn run(&mut self, (entities, cameras, transforms, parents, mut streams, mut handles, loader): Self::SystemData) {
+        let (x, y) = match (&cameras, &transforms).join().next() {
+            Some((_, transform)) => (transform.translation().x, -transform.translation().y),
+            None => return,
+        };
+        for stream in (&mut streams).join() {
+            let wanted: Vec<_> = stream.manifest.regions_near(x, y, stream.load_margin)
+                .filter(|r| !stream.loaded.contains_key(&(r.x, r.y)))
+                .cloned()
+                .collect();
+            for region in wanted {
+                let root = entities.create();
+                handles.insert(root, (stream.load)(&loader, &region.prefab)).expect("Unable to load region");
+                stream.loaded.insert((region.x, region.y), root);
+            }
+
+            let kept: Vec<_> = stream.manifest.regions_near(x, y, stream.unload_margin)
+                .map(|r| (r.x, r.y))
+                .collect();
+            let unloaded: Vec<_> = stream.loaded.keys()
+                .filter(|r| !kept.contains(r))
+                .cloned()
+                .collect();
+            for region in unloaded {
+                let root = stream.loaded.remove(&region).unwrap();
+                // Physics bodies go with their entities through the physics cleanup
+                for (entity, parent) in (&entities, &parents).join() {
+                    if parent.entity == root {
+                        entities.delete(entity).expect("Unable to delete region entity");
+                    }
+                }
+                entities.delete(root).expect("Unable to delete region");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest() -> RegionManifest {
+        let regions = (0..2)
+            .flat_map(|y| (0..2).map(move |x| MapRegion { x, y, prefab: format!("regions/{}_{}.ron", x, y) }))
+            .collect();
+        RegionManifest { region_width: 100.0, region_height: 50.0, regions }
+    }
+
+    fn near(x: f32, y: f32, margin: (f32, f32)) -> Vec<(i32, i32)> {
+        manifest().regions_near(x, y, margin).map(|r| (r.x, r.y)).collect()
+    }
+
+    #[test]
+    fn regions_near_borders() {
+        let cases = vec![
+            ("inside a region", (50.0, 25.0), (0.0, 0.0), vec![(0, 0)]),
+            ("on a vertical border", (100.0, 25.0), (0.0, 0.0), vec![(1, 0)]),
+            ("on a horizontal border", (50.0, 50.0), (0.0, 0.0), vec![(0, 1)]),
+            ("on a corner", (100.0, 50.0), (0.0, 0.0), vec![(1, 1)]),
+            ("just before a border", (99.0, 25.0), (0.0, 0.0), vec![(0, 0)]),
+            ("margin over a border", (90.0, 25.0), (20.0, 0.0), vec![(0, 0), (1, 0)]),
+            ("margin up to a border", (80.0, 25.0), (20.0, 0.0), vec![(0, 0), (1, 0)]),
+            ("margin short of a border", (79.0, 25.0), (20.0, 0.0), vec![(0, 0)]),
+            ("margin over a corner", (95.0, 45.0), (10.0, 10.0), vec![(0, 0), (1, 0), (0, 1), (1, 1)]),
+            ("at the far edge of the map", (200.0, 100.0), (0.0, 0.0), vec![]),
+            ("outside the map", (-10.0, 25.0), (5.0, 5.0), vec![]),
+            ("margin reaching into the map", (-10.0, 25.0), (15.0, 0.0), vec![(0, 0)]),
+        ];
+        for (name, (x, y), margin, expected) in cases {
+            assert_eq!(near(x, y, margin), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn unload_margin_is_at_least_the_load_margin() {
+        let cases = vec![
+            ((100.0, 50.0), (200.0, 100.0), (200.0, 100.0)),
+            ((100.0, 50.0), (100.0, 50.0), (100.0, 50.0)),
+            ((100.0, 50.0), (50.0, 25.0), (100.0, 50.0)),
+            ((100.0, 50.0), (50.0, 80.0), (100.0, 80.0)),
+            ((0.0, 0.0), (0.0, 0.0), (0.0, 0.0)),
+        ];
+        for (load, unload, expected) in cases {
+            assert_eq!(unload_margin(load, unload), expected, "load {:?} unload {:?}", load, unload);
+        }
+    }
+}
@@ -0,0 +1,393 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use crate::{Map, Object, ObjectMapping, ObjectShape, Properties, PropertyValue, TiledDetail, Tileset};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub enum IssueKind {
+    ParseError,
+    UnknownObjectType,
+    MissingImage,
+    MissingTileset,
+    ZeroTileSize,
+    ZeroSizeCollider,
+    NonConvexPolygon,
+    WrongPropertyType,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Issue {
+    pub severity: Severity,
+    pub kind: IssueKind,
+    pub location: String,
+    pub message: String,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PropertyKind {
+    Bool,
+    Float,
+    Int,
+    Color,
+    String,
+}
+
+impl PropertyKind {
+    pub fn of(value: &PropertyValue) -> Self {
+        match value {
+            PropertyValue::Bool(_) => PropertyKind::Bool,
+            PropertyValue::Float(_) => PropertyKind::Float,
+            PropertyValue::Int(_) => PropertyKind::Int,
+            PropertyValue::Color(_) => PropertyKind::Color,
+            PropertyValue::String(_) => PropertyKind::String,
+        }
+    }
+}
+
+/// What a map is checked against.
+///
+/// If `object_types` is None object types aren't checked. Objects whose type is in
+/// `collider_types` are checked for zero sizes and concave polygons. Properties named
+/// in `properties` must have that type, other properties must have the same type
+/// everywhere they are used.
+#[derive(Debug, Clone, Default)]
+pub struct ValidationRules {
+    pub object_types: Option<BTreeSet<String>>,
+    pub collider_types: BTreeSet<String>,
+    pub properties: BTreeMap<String, PropertyKind>,
+}
+
+impl ValidationRules {
+    pub fn from_detail<D: TiledDetail>() -> Self {
+        ValidationRules {
+            object_types: Some(D::OBJECT_TYPES.iter().map(|t| t.to_string()).collect()),
+            collider_types: D::COLLIDER_TYPES.iter().map(|t| t.to_string()).collect(),
+            properties: BTreeMap::new(),
+        }
+    }
+
+    pub fn add_mapping(&mut self, mapping: &ObjectMapping) {
+        if let Some(object_types) = &mut self.object_types {
+            object_types.extend(mapping.objects.keys().cloned());
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ValidationReport {
+    pub issues: Vec<Issue>,
+}
+
+impl ValidationReport {
+    pub fn has_errors(&self) -> bool {
+        self.issues.iter().any(|i| i.severity == Severity::Error)
+    }
+
+    pub fn parse_error(message: String) -> Self {
+        ValidationReport {
+            issues: vec![Issue {
+                severity: Severity::Error,
+                kind: IssueKind::ParseError,
+                location: "map".to_string(),
+                message,
+            }],
+        }
+    }
+
+    fn push(&mut self, severity: Severity, kind: IssueKind, location: String, message: String) {
+        self.issues.push(Issue { severity, kind, location, message });
+    }
+}
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for issue in &self.issues {
+            let severity = match issue.severity {
+                Severity::Warning => "warning",
+                Severity::Error => "error",
+            };
+            writeln!(f, "{}[{:?}] {}: {}", severity, issue.kind, issue.location, issue.message)?;
+        }
+        let errors = self.issues.iter().filter(|i| i.severity == Severity::Error).count();
+        write!(f, "{} errors, {} warnings", errors, self.issues.len() - errors)
+    }
+}
+
+pub fn validate_map(map: &Map, rules: &ValidationRules) -> ValidationReport {
+    let mut report = ValidationReport::default();
+    let mut property_kinds = rules.properties.clone();
+
+    check_properties(&mut report, &mut property_kinds, &rules.properties, "map".to_string(), &map.properties);
+    for tileset in &map.tilesets {
+        if tileset.tile_width == 0 || tileset.tile_height == 0 {
+            report.push(
+                Severity::Error,
+                IssueKind::ZeroTileSize,
+                format!("tileset {:?}", tileset.name),
+                format!("tiles are {}x{}", tileset.tile_width, tileset.tile_height),
+            );
+        }
+        for tile in &tileset.tiles {
+            let location = format!("tileset {:?} tile {}", tileset.name, tile.id);
+            check_properties(&mut report, &mut property_kinds, &rules.properties, location, &tile.properties);
+        }
+    }
+
+    for layer in &map.layers {
+        let location = format!("layer {:?}", layer.name);
+        check_properties(&mut report, &mut property_kinds, &rules.properties, location, &layer.properties);
+        for (y, row) in layer.tiles.iter().enumerate() {
+            for (x, gid) in row.iter().enumerate() {
+                if *gid != 0 && tile_exists(map, *gid).is_none() {
+                    report.push(
+                        Severity::Error,
+                        IssueKind::MissingTileset,
+                        format!("layer {:?} ({}, {})", layer.name, x, y),
+                        format!("tile {} isn't in any tileset", gid),
+                    );
+                }
+            }
+        }
+    }
+
+    for group in &map.object_groups {
+        for object in &group.objects {
+            let location = format!("object group {:?} object {}", group.name, object.id);
+            check_object(&mut report, map, rules, &location, object);
+            check_properties(&mut report, &mut property_kinds, &rules.properties, location, &object.properties);
+        }
+    }
+
+    report
+}
+
+fn check_object(report: &mut ValidationReport, map: &Map, rules: &ValidationRules, location: &str, object: &Object) {
+    if let Some(object_types) = &rules.object_types {
+        if !object_types.contains(&object.obj_type) {
+            report.push(
+                Severity::Warning,
+                IssueKind::UnknownObjectType,
+                location.to_string(),
+                format!("object type {:?} isn't handled by the converter", object.obj_type),
+            );
+        }
+    }
+
+    if object.gid != 0 {
+        match tile_exists(map, object.gid) {
+            None => report.push(
+                Severity::Error,
+                IssueKind::MissingTileset,
+                location.to_string(),
+                format!("tile {} isn't in any tileset", object.gid),
+            ),
+            Some(false) => report.push(
+                Severity::Error,
+                IssueKind::MissingImage,
+                location.to_string(),
+                format!("tile {} has no image", object.gid),
+            ),
+            Some(true) => (),
+        }
+    }
+
+    if rules.collider_types.contains(&object.obj_type) {
+        match &object.shape {
+            ObjectShape::Rect { width, height } | ObjectShape::Ellipse { width, height } => {
+                if *width <= 0.0 || *height <= 0.0 {
+                    report.push(
+                        Severity::Error,
+                        IssueKind::ZeroSizeCollider,
+                        location.to_string(),
+                        format!("collider is {}x{}", width, height),
+                    );
+                }
+            },
+            ObjectShape::Polygon { points } => {
+                if points.len() < 3 {
+                    report.push(
+                        Severity::Error,
+                        IssueKind::ZeroSizeCollider,
+                        location.to_string(),
+                        format!("polygon collider has only {} points", points.len()),
+                    );
+                } else if !is_convex(points) {
+                    report.push(
+                        Severity::Error,
+                        IssueKind::NonConvexPolygon,
+                        location.to_string(),
+                        "polygon collider isn't convex".to_string(),
+                    );
+                }
+            },
+            ObjectShape::Polyline { .. } | ObjectShape::Point(..) => report.push(
+                Severity::Error,
+                IssueKind::ZeroSizeCollider,
+                location.to_string(),
+                "collider has no area".to_string(),
+            ),
+        }
+    }
+}
+
+fn check_properties(
+    report: &mut ValidationReport,
+    seen: &mut BTreeMap<String, PropertyKind>,
+    declared: &BTreeMap<String, PropertyKind>,
+    location: String,
+    properties: &Properties,
+) {
+    for (name, value) in properties {
+        let kind = PropertyKind::of(value);
+        match seen.get(name) {
+            Some(expected) if *expected != kind => {
+                let reason = if declared.contains_key(name) { "should be" } else { "is elsewhere" };
+                report.push(
+                    Severity::Error,
+                    IssueKind::WrongPropertyType,
+                    location.clone(),
+                    format!("property {:?} is {:?} but {} {:?}", name, kind, reason, expected),
+                );
+            },
+            Some(_) => (),
+            None => {
+                seen.insert(name.clone(), kind);
+            },
+        }
+    }
+}
+
+// None if the gid isn't in any tileset, otherwise whether the tile has an image
+fn tile_exists(map: &Map, gid: u32) -> Option<bool> {
+    let tileset: &Tileset = map.tilesets.iter()
+        .filter(|t| t.first_gid <= gid)
+        .max_by_key(|t| t.first_gid)?;
+    let id = gid - tileset.first_gid;
+    if tileset.tile_width == 0 || tileset.tile_height == 0 {
+        // Reported once for the tileset rather than for every tile using it
+        Some(true)
+    } else if let Some(img) = tileset.images.first() {
+        let columns = (img.width / tileset.tile_width as i32).max(0) as u32;
+        let rows = (img.height / tileset.tile_height as i32).max(0) as u32;
+        if id < columns * rows {
+            Some(true)
+        } else {
+            None
+        }
+    } else {
+        tileset.tiles.iter()
+            .find(|t| t.id == id)
+            .map(|t| !t.images.is_empty())
+    }
+}
+
+// Convex if every turn goes the same way and the turns add up to a single loop,
+// which rules out self intersecting shapes like a pentagram
+fn is_convex(points: &[(f32, f32)]) -> bool {
+    let mut sign = 0.0;
+    let mut turning = 0.0;
+    for i in 0..points.len() {
+        let (ax, ay) = points[i];
+        let (bx, by) = points[(i + 1) % points.len()];
+        let (cx, cy) = points[(i + 2) % points.len()];
+        let (ux, uy) = (bx - ax, by - ay);
+        let (vx, vy) = (cx - bx, cy - by);
+        let cross = ux * vy - uy * vx;
+        if cross != 0.0 {
+            if sign != 0.0 && cross.signum() != sign {
+                return false;
+            }
+            sign = cross.signum();
+        }
+        turning += cross.atan2(ux * vx + uy * vy);
+    }
+    sign != 0.0 && (turning.abs() - 2.0 * std::f32::consts::PI).abs() < 1e-3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MapBuilder;
+
+    #[test]
+    fn convex_polygons() {
+        let cases: Vec<(&str, Vec<(f32, f32)>, bool)> = vec![
+            ("triangle", vec![(0.0, 0.0), (10.0, 0.0), (0.0, 10.0)], true),
+            ("square", vec![(0.0, 0.0), (10.0, 0.0), (10.0, 10.0), (0.0, 10.0)], true),
+            ("square the other way round", vec![(0.0, 0.0), (0.0, 10.0), (10.0, 10.0), (10.0, 0.0)], true),
+            ("square with a point on an edge", vec![(0.0, 0.0), (5.0, 0.0), (10.0, 0.0), (10.0, 10.0), (0.0, 10.0)], true),
+            ("arrow", vec![(0.0, 0.0), (10.0, 5.0), (0.0, 10.0), (3.0, 5.0)], false),
+            ("bow tie", vec![(0.0, 0.0), (10.0, 10.0), (10.0, 0.0), (0.0, 10.0)], false),
+            ("pentagram", (0..5).map(|i| {
+                let angle = i as f32 * 4.0 * std::f32::consts::PI / 5.0;
+                (angle.cos() * 10.0, angle.sin() * 10.0)
+            }).collect(), false),
+            ("line", vec![(0.0, 0.0), (5.0, 0.0), (10.0, 0.0)], false),
+        ];
+        for (name, points, convex) in cases {
+            assert_eq!(is_convex(&points), convex, "{}", name);
+        }
+    }
+
+    #[test]
+    fn map_issues() {
+        let rules = ValidationRules {
+            object_types: Some(vec!["Wall".to_string()].into_iter().collect()),
+            collider_types: vec!["Wall".to_string()].into_iter().collect(),
+            properties: vec![("speed".to_string(), PropertyKind::Float)].into_iter().collect(),
+        };
+        fn map(build: impl Fn(&mut MapBuilder, u32)) -> Map {
+            let mut builder = MapBuilder::new(4, 4, 16, 16);
+            let gid = builder.image_tileset("tiles", "tiles.png", 16, 16, 32, 32);
+            build(&mut builder, gid);
+            builder.build()
+        }
+        let cases: Vec<(&str, Map, Vec<IssueKind>)> = vec![
+            ("valid", map(|b, gid| {
+                b.layer("ground").fill(gid + 3);
+                b.object_group("walls").add(Object::rect("Wall", 0.0, 0.0, 16.0, 16.0));
+            }), vec![]),
+            ("tile past the end of its tileset", map(|b, gid| {
+                b.layer("ground").set(1, 2, gid + 4);
+            }), vec![IssueKind::MissingTileset]),
+            ("zero size tiles", map(|b, _| {
+                let gid = b.image_tileset("broken", "broken.png", 0, 16, 32, 32);
+                b.layer("ground").set(0, 0, gid);
+            }), vec![IssueKind::ZeroTileSize]),
+            ("tile object past every tileset", map(|b, gid| {
+                b.object_group("walls").add(Object::tile("Wall", gid + 10, 0.0, 16.0, 16.0, 16.0));
+            }), vec![IssueKind::MissingTileset]),
+            ("unknown object type", map(|b, _| {
+                b.object_group("things").add(Object::point("Spawn", 0.0, 0.0));
+            }), vec![IssueKind::UnknownObjectType]),
+            ("zero size collider", map(|b, _| {
+                b.object_group("walls").add(Object::rect("Wall", 0.0, 0.0, 0.0, 16.0));
+            }), vec![IssueKind::ZeroSizeCollider]),
+            ("pentagram collider", map(|b, _| {
+                let points = (0..5).map(|i| {
+                    let angle = i as f32 * 4.0 * std::f32::consts::PI / 5.0;
+                    (angle.cos() * 10.0, angle.sin() * 10.0)
+                }).collect();
+                b.object_group("walls").add(Object::polygon("Wall", 20.0, 20.0, points));
+            }), vec![IssueKind::NonConvexPolygon]),
+            ("declared property type", map(|b, _| {
+                b.property("speed", 2);
+            }), vec![IssueKind::WrongPropertyType]),
+            ("inconsistent property types", map(|b, _| {
+                b.layer("ground").property("solid", true);
+                b.object_group("walls").add(Object::rect("Wall", 0.0, 0.0, 16.0, 16.0).with_property("solid", 1));
+            }), vec![IssueKind::WrongPropertyType]),
+        ];
+        for (name, map, expected) in cases {
+            let kinds: Vec<IssueKind> = validate_map(&map, &rules).issues.iter().map(|i| i.kind).collect();
+            assert_eq!(kinds, expected, "{}", name);
+        }
+    }
+}
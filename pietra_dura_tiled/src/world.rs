@@ -0,0 +1,167 @@
+// Tiled worlds, which lay several maps out in one larger space
+
+use std::collections::BTreeMap;
+use std::fs::{create_dir_all, File};
+use std::io::prelude::*;
+use std::io::Result;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::json::read_json;
+use crate::{path_key, write_map_file, MapFile, MapPrefab};
+
+pub const WORLD_MANIFEST_FILE: &str = "world.ron";
+
+// Where the textures shared by a world's maps go, inside the world's directory
+const SHARED_TEXTURE_DIR: &str = "sprite_sheets";
+
+/// A map's place in a world, in pixels with y going down as in Tiled.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct WorldMap {
+    #[serde(rename = "fileName")]
+    pub file_name: String,
+    pub x: i32,
+    pub y: i32,
+    #[serde(default)]
+    pub width: u32,
+    #[serde(default)]
+    pub height: u32,
+}
+
+impl WorldMap {
+    // Maps are converted into directories named after their files
+    pub fn name(&self) -> String {
+        Path::new(&self.file_name)
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_else(|| self.file_name.clone())
+    }
+}
+
+/// The contents of a `.world` file. Maps in `file_name` are relative to the world file.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TiledWorld {
+    #[serde(default)]
+    pub maps: Vec<WorldMap>,
+    #[serde(default)]
+    patterns: Vec<serde_json::Value>,
+}
+
+pub fn parse_world(path: &Path) -> std::result::Result<TiledWorld, String> {
+    let world: TiledWorld = serde_json::from_value(read_json(path)?)
+        .map_err(|e| format!("Unable to parse {:?}: {}", path, e))?;
+    if !world.patterns.is_empty() {
+        return Err(format!("{:?}: worlds with patterns aren't supported, list the maps instead", path));
+    }
+    Ok(world)
+}
+
+/// A converted map's entry in the world manifest. `x`, `y`, `width` and `height` are
+/// the map's bounds in world pixels with y going down, as in Tiled.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WorldManifestMap {
+    pub name: String,
+    // Asset path of the map's prefab
+    pub prefab: String,
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+/// Written as `world.ron` next to a converted world's maps, for streaming them in
+/// and out at runtime.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct WorldManifest {
+    pub maps: Vec<WorldManifestMap>,
+}
+
+impl WorldManifest {
+    pub fn load(path: &Path) -> Option<Self> {
+        let f = File::open(path).ok()?;
+        ron::de::from_reader(f).ok()
+    }
+
+    /// The maps whose bounds overlap the given rectangle, in world pixels
+    pub fn maps_in(&self, x: f32, y: f32, width: f32, height: f32) -> impl Iterator<Item = &WorldManifestMap> {
+        self.maps.iter().filter(move |m| {
+            m.x < x + width && x < m.x + m.width && m.y < y + height && y < m.y + m.height
+        })
+    }
+}
+
+/// Every map of a world converted by `TiledConverter::from_world`, each offset to its
+/// place in the world and sharing sprite sheet textures with the others.
+pub struct WorldPrefab<Prefab, Proxy> {
+    world_prefix: PathBuf,
+    maps: Vec<(String, MapPrefab<Prefab, Proxy>)>,
+    // Keyed by destination, which includes a hash of the contents, so each texture
+    // is kept once however many maps use it
+    shared_files: BTreeMap<PathBuf, MapFile>,
+    manifest: WorldManifest,
+}
+
+impl<Prefab, Proxy> WorldPrefab<Prefab, Proxy> {
+    pub fn new(world_prefix: &Path) -> Self {
+        WorldPrefab {
+            world_prefix: world_prefix.to_path_buf(),
+            maps: Vec::new(),
+            shared_files: BTreeMap::new(),
+            manifest: WorldManifest::default(),
+        }
+    }
+
+    // The asset path shared textures are written under, for `ConversionSettings::texture_prefix`
+    pub fn texture_prefix(&self) -> PathBuf {
+        self.world_prefix.join(SHARED_TEXTURE_DIR)
+    }
+
+    pub fn add_map(&mut self, world_map: &WorldMap, mut map: MapPrefab<Prefab, Proxy>) {
+        let name = world_map.name();
+        for file in map.shared_files.drain(..) {
+            let dest = match &file {
+                MapFile::Path(_, dest) | MapFile::Data(dest, _) => dest.clone(),
+            };
+            self.shared_files.entry(dest).or_insert(file);
+        }
+        self.manifest.maps.push(WorldManifestMap {
+            name: name.clone(),
+            prefab: path_key(&self.world_prefix.join(&name).join(map.output_format().prefab_file())),
+            x: world_map.x as f32,
+            y: world_map.y as f32,
+            width: world_map.width as f32,
+            height: world_map.height as f32,
+        });
+        self.maps.push((name, map));
+    }
+
+    pub fn maps(&self) -> impl Iterator<Item = (&str, &MapPrefab<Prefab, Proxy>)> {
+        self.maps.iter().map(|(name, map)| (name.as_str(), map))
+    }
+
+    // Textures used by the maps, each only once
+    pub fn shared_files(&self) -> impl Iterator<Item = &MapFile> {
+        self.shared_files.values()
+    }
+
+    pub fn manifest(&self) -> &WorldManifest {
+        &self.manifest
+    }
+
+    /// Writes each map into a directory named after it inside `dir`, the shared
+    /// textures into `sprite_sheets` and the manifest into `world.ron`.
+    pub fn write(&self, dir: &Path) -> Result<()> {
+        for (name, map) in &self.maps {
+            map.write(&dir.join(name))?;
+        }
+        for file in self.shared_files.values() {
+            write_map_file(file, &dir.join(SHARED_TEXTURE_DIR));
+        }
+        create_dir_all(dir)?;
+        let buffer = ron::ser::to_string_pretty(&self.manifest, ron::ser::PrettyConfig::default())
+            .expect("Failed to encode world manifest");
+        let mut f = File::create(dir.join(WORLD_MANIFEST_FILE))?;
+        f.write_all(buffer.as_bytes())
+    }
+}
@@ -0,0 +1,800 @@
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{
+    parse_macro_input, Attribute, Data, DeriveInput, Error, Fields, GenericArgument, Ident, Lit,
+    LitStr, Meta, NestedMeta, Path, PathArguments, Type,
+};
+
+/// Implements `TiledConverter` for a prefab whose fields are some of `sheet`,
+/// `render`, `transform` and a `detail` implementing `TiledDetail`.
+///
+/// ```ignore
+/// #[derive(TiledConverter)]
+/// #[tiled(proxy = "LevelPrefabProxy")]
+/// pub struct LevelPrefab {
+///     sheet: Option<SpriteSheetPrefab>,
+///     render: Option<SpriteRenderPrefab>,
+///     transform: Option<Transform>,
+///     detail: Detail,
+/// }
+/// ```
+///
+/// An `Option<Parallax>` field named `parallax` is filled in for tiles and objects
+/// from layers with parallax factors, and an `Option<YSort>` field named `y_sort`
+/// for objects from y sorted object groups. The transform's z comes from
+/// `ConversionSettings::depth`. Objects' names and types go in `Option<Named>` and
+/// `Option<ObjectType>` fields named `name` and `object_type`, and the tile cells
+/// under collider rectangles in an `Option<ColliderCells>` field named
+/// `collider_cells`.
+///
+/// Fields with other names can be given a role with `#[tiled(sheet)]`,
+/// `#[tiled(render)]`, `#[tiled(transform)]`, `#[tiled(parallax)]`, `#[tiled(y_sort)]`,
+/// `#[tiled(name)]`, `#[tiled(object_type)]`, `#[tiled(collider_cells)]` or
+/// `#[tiled(detail)]`.
+#[proc_macro_derive(TiledConverter, attributes(tiled))]
+pub fn derive_tiled_converter(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    converter_impl(&input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+/// Implements `TiledDetail` for an enum. Each variant is either a unit variant or
+/// holds a single value, and is chosen by `tiled` attributes:
+///
+/// * `#[tiled(tile)]` builds the variant for every tile. The value is made with
+///   `From<TileCell>`.
+/// * `#[tiled(object_type = "static")]` builds the variant for objects of that type.
+///   The value is made with `FromTiledObject`.
+/// * `requires_sprite` skips objects which don't have a tile image.
+/// * `collider` marks objects of the type as colliders, so validation checks
+///   their shapes.
+/// * `with = "path::to::function"` makes the value by calling
+///   `function(&SpriteContext, TileCell)` for tiles or
+///   `function(&Option<SpriteContext>, &Object)` for objects, which returns an `Option`.
+///
+/// A variant may have several `tiled` attributes to handle several object types.
+#[proc_macro_derive(TiledDetail, attributes(tiled))]
+pub fn derive_tiled_detail(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    detail_impl(&input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+/// Implements `FromTiledObject` by reading each field from the object's custom
+/// property of the same name. `#[tiled(property = "name")]` reads a differently
+/// named property and `#[tiled(default)]` uses `Default` if it is missing, otherwise
+/// the object isn't converted. Unit structs always convert.
+#[proc_macro_derive(FromTiledObject, attributes(tiled))]
+pub fn derive_from_tiled_object(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    from_tiled_object_impl(&input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+/// Generates the serialize-only proxy for a prefab, swapping Amethyst's sprite
+/// prefabs for the versions in `pietra_dura_tiled` which produce the same RON.
+///
+/// ```ignore
+/// #[derive(PrefabProxy)]
+/// #[proxy(name = "LevelPrefabProxy")]
+/// pub struct LevelPrefab { ... }
+/// ```
+///
+/// Any other field which can't be serialized can be given a replacement type with
+/// `#[proxy(with = "path::to::Type")]`. `Option` fields marked `#[serde(default)]`
+/// are left out of human-readable formats when they're `None`, since they read back
+/// the same. Binary formats like Bincode can't leave fields out, so always get them.
+#[proc_macro_derive(PrefabProxy, attributes(proxy))]
+pub fn derive_prefab_proxy(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    prefab_proxy_impl(&input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+fn tiled_attributes(attrs: &[Attribute]) -> Result<Vec<Vec<Meta>>, Error> {
+    named_attributes(attrs, "tiled")
+}
+
+fn named_attributes(attrs: &[Attribute], name: &str) -> Result<Vec<Vec<Meta>>, Error> {
+    let mut result = Vec::new();
+    for attr in attrs {
+        if attr.path.segments.len() != 1 || attr.path.segments[0].ident != name {
+            continue;
+        }
+        match attr.parse_meta()? {
+            Meta::List(list) => {
+                let mut metas = Vec::new();
+                for nested in list.nested {
+                    match nested {
+                        NestedMeta::Meta(meta) => metas.push(meta),
+                        NestedMeta::Literal(lit) => {
+                            return Err(Error::new_spanned(lit, "expected a name or name = \"value\""))
+                        }
+                    }
+                }
+                result.push(metas);
+            }
+            meta => return Err(Error::new_spanned(meta, format!("expected #[{}(...)]", name))),
+        }
+    }
+    Ok(result)
+}
+
+fn meta_name(meta: &Meta) -> &Ident {
+    match meta {
+        Meta::Word(ident) => ident,
+        Meta::List(list) => &list.ident,
+        Meta::NameValue(nv) => &nv.ident,
+    }
+}
+
+fn meta_str(meta: &Meta) -> Result<LitStr, Error> {
+    match meta {
+        Meta::NameValue(nv) => match &nv.lit {
+            Lit::Str(s) => Ok(s.clone()),
+            lit => Err(Error::new_spanned(lit, "expected a string")),
+        },
+        meta => Err(Error::new_spanned(meta, "expected name = \"value\"")),
+    }
+}
+
+fn converter_impl(input: &DeriveInput) -> Result<TokenStream2, Error> {
+    let name = &input.ident;
+    if !input.generics.params.is_empty() {
+        return Err(Error::new_spanned(&input.generics, "TiledConverter can't be derived for generic prefabs"));
+    }
+
+    let mut proxy: Option<Type> = None;
+    for metas in tiled_attributes(&input.attrs)? {
+        for meta in metas {
+            if *meta_name(&meta) == "proxy" {
+                proxy = Some(meta_str(&meta)?.parse()?);
+            } else {
+                return Err(Error::new_spanned(meta, "unknown tiled attribute"));
+            }
+        }
+    }
+    let proxy = proxy.ok_or_else(|| Error::new_spanned(name, "missing #[tiled(proxy = \"ProxyType\")]"))?;
+
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => return Err(Error::new_spanned(name, "TiledConverter needs named fields")),
+        },
+        _ => return Err(Error::new_spanned(name, "TiledConverter can only be derived for structs")),
+    };
+
+    let mut sheet = None;
+    let mut render = None;
+    let mut transform = None;
+    let mut parallax = None;
+    let mut y_sort = None;
+    let mut object_name = None;
+    let mut object_type = None;
+    let mut collider_cells = None;
+    let mut detail = None;
+    for field in fields {
+        let ident = field.ident.clone().expect("Named field without a name");
+        let mut role = ident.to_string();
+        for metas in tiled_attributes(&field.attrs)? {
+            for meta in metas {
+                role = meta_name(&meta).to_string();
+            }
+        }
+        match role.as_ref() {
+            "sheet" => sheet = Some(ident),
+            "render" => render = Some(ident),
+            "transform" => transform = Some(ident),
+            "parallax" => parallax = Some(ident),
+            "y_sort" => y_sort = Some(ident),
+            "name" => object_name = Some(ident),
+            "object_type" => object_type = Some(ident),
+            "collider_cells" => collider_cells = Some(ident),
+            "detail" => detail = Some((ident, field.ty.clone())),
+            _ => return Err(Error::new_spanned(
+                field,
+                "field isn't one of sheet, render, transform, parallax, y_sort, name, object_type, collider_cells or detail",
+            )),
+        }
+    }
+    let (detail, detail_ty) = detail.ok_or_else(|| Error::new_spanned(name, "TiledConverter needs a detail field"))?;
+
+    let sprite_fields = |sheet_value: TokenStream2, render_value: TokenStream2, transform_value: TokenStream2| {
+        let mut tokens = TokenStream2::new();
+        if let Some(sheet) = &sheet {
+            tokens.extend(quote!(#sheet: #sheet_value,));
+        }
+        if let Some(render) = &render {
+            tokens.extend(quote!(#render: #render_value,));
+        }
+        if let Some(transform) = &transform {
+            tokens.extend(quote!(#transform: #transform_value,));
+        }
+        if let Some(parallax) = &parallax {
+            tokens.extend(quote!(#parallax: None,));
+        }
+        for field in y_sort.iter().chain(&object_name).chain(&object_type).chain(&collider_cells) {
+            tokens.extend(quote!(#field: None,));
+        }
+        tokens
+    };
+    let with_parallax = match &parallax {
+        Some(parallax) => quote! {
+            fn with_parallax(mut proxy: Self::PrefabProxy, parallax: ::pietra_dura_tiled::Parallax) -> Self::PrefabProxy {
+                proxy.#parallax = Some(parallax);
+                proxy
+            }
+        },
+        None => quote!(),
+    };
+    let with_z = match &transform {
+        Some(transform) => quote! {
+            fn with_z(mut proxy: Self::PrefabProxy, z: f32) -> Self::PrefabProxy {
+                if let Some(transform) = proxy.#transform.as_mut() {
+                    transform.set_translation_z(z);
+                }
+                proxy
+            }
+        },
+        None => quote!(),
+    };
+    let set_name = object_name.iter().map(|field| quote! {
+        if !object.name.is_empty() {
+            proxy.#field = Some(::amethyst::core::Named::new(object.name.clone()));
+        }
+    });
+    // Every object gets a type, even an empty one, as it's what `ObjectIndexSystem`
+    // indexes names by
+    let set_type = object_type.iter().map(|field| quote! {
+        proxy.#field = Some(::pietra_dura_tiled::ObjectType(object.obj_type.clone()));
+    });
+    let with_object_names = if object_name.is_some() || object_type.is_some() {
+        quote! {
+            fn with_object_names(mut proxy: Self::PrefabProxy, object: &::pietra_dura_tiled::Object) -> Self::PrefabProxy {
+                #(#set_name)*
+                #(#set_type)*
+                proxy
+            }
+        }
+    } else {
+        quote!()
+    };
+    // Only objects the detail makes colliders from keep their cells
+    let with_collider_cells = match &collider_cells {
+        Some(collider_cells) => quote! {
+            fn with_collider_cells(
+                mut proxy: Self::PrefabProxy,
+                object: &::pietra_dura_tiled::Object,
+                cells: ::pietra_dura_tiled::ColliderCells,
+            ) -> Self::PrefabProxy {
+                if <#detail_ty as ::pietra_dura_tiled::TiledDetail>::COLLIDER_TYPES.contains(&object.obj_type.as_str()) {
+                    proxy.#collider_cells = Some(cells);
+                }
+                proxy
+            }
+        },
+        None => quote!(),
+    };
+    let with_y_sort = match &y_sort {
+        Some(y_sort) => quote! {
+            fn with_y_sort(mut proxy: Self::PrefabProxy, y_sort: ::pietra_dura_tiled::YSort) -> Self::PrefabProxy {
+                proxy.#y_sort = Some(y_sort);
+                proxy
+            }
+        },
+        None => quote!(),
+    };
+    let tile_fields = sprite_fields(
+        quote!(ctx.sprite_sheet.clone()),
+        quote!(Some(ctx.render())),
+        quote!(Some(::pietra_dura_tiled::tile_transform(x, y, cell.layer))),
+    );
+    let sprite_object_fields = sprite_fields(
+        quote!(ctx.sprite_sheet.clone()),
+        quote!(Some(ctx.render())),
+        quote!(Some(::pietra_dura_tiled::object_transform(object, layer))),
+    );
+    let plain_object_fields = sprite_fields(quote!(None), quote!(None), quote!(None));
+    let mapped_fields = sprite_fields(quote!(mapped.sheet), quote!(mapped.render), quote!(mapped.transform));
+
+    Ok(quote! {
+        impl<'s> ::pietra_dura_tiled::TiledConverter<'s, #name> for #name {
+            type PrefabProxy = #proxy;
+
+            fn convert_tile(ctx: &Option<::pietra_dura_tiled::SpriteContext>, x: f32, y: f32, layer: usize) -> Option<Self::PrefabProxy> {
+                Self::convert_tile_at_cell(ctx, ::pietra_dura_tiled::TileCell { layer, ..Default::default() }, x, y)
+            }
+
+            fn convert_tile_at_cell(ctx: &Option<::pietra_dura_tiled::SpriteContext>, cell: ::pietra_dura_tiled::TileCell, x: f32, y: f32) -> Option<Self::PrefabProxy> {
+                let ctx = ctx.as_ref()?;
+                let detail = <#detail_ty as ::pietra_dura_tiled::TiledDetail>::from_tile(ctx, cell)?;
+                Some(#proxy {
+                    #tile_fields
+                    #detail: detail,
+                })
+            }
+
+            fn convert_object(ctx: &Option<::pietra_dura_tiled::SpriteContext>, layer: usize, object: &::pietra_dura_tiled::Object) -> Option<Self::PrefabProxy> {
+                let detail = <#detail_ty as ::pietra_dura_tiled::TiledDetail>::from_object(ctx, layer, object)?;
+                match ctx {
+                    Some(ctx) => Some(#proxy {
+                        #sprite_object_fields
+                        #detail: detail,
+                    }),
+                    None => Some(#proxy {
+                        #plain_object_fields
+                        #detail: detail,
+                    }),
+                }
+            }
+
+            fn convert_mapped_object(
+                _ctx: &Option<::pietra_dura_tiled::SpriteContext>,
+                _layer: usize,
+                _object: &::pietra_dura_tiled::Object,
+                mapped: ::pietra_dura_tiled::MappedObject,
+            ) -> ::std::result::Result<Option<Self::PrefabProxy>, String> {
+                let detail = mapped.detail()?;
+                Ok(Some(#proxy {
+                    #mapped_fields
+                    #detail: detail,
+                }))
+            }
+
+            #with_parallax
+
+            #with_z
+
+            #with_y_sort
+
+            #with_object_names
+
+            #with_collider_cells
+
+            fn entity_kind(proxy: &Self::PrefabProxy) -> &'static str {
+                ::pietra_dura_tiled::TiledDetail::kind(&proxy.#detail)
+            }
+
+            fn validation_rules() -> ::pietra_dura_tiled::ValidationRules {
+                ::pietra_dura_tiled::ValidationRules::from_detail::<#detail_ty>()
+            }
+        }
+    })
+}
+
+enum Rule {
+    Tile { with: Option<Path> },
+    Object { object_type: LitStr, requires_sprite: bool, collider: bool, with: Option<Path> },
+}
+
+fn detail_impl(input: &DeriveInput) -> Result<TokenStream2, Error> {
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let variants = match &input.data {
+        Data::Enum(data) => &data.variants,
+        _ => return Err(Error::new_spanned(name, "TiledDetail can only be derived for enums")),
+    };
+
+    let mut tile_arm = None;
+    let mut object_arms = Vec::new();
+    let mut object_types: Vec<String> = Vec::new();
+    let mut collider_types: Vec<String> = Vec::new();
+    let mut kind_arms = Vec::new();
+    for variant in variants {
+        let ident = &variant.ident;
+        let kind = ident.to_string();
+        kind_arms.push(quote!(#name::#ident { .. } => #kind,));
+        let field_ty = match &variant.fields {
+            Fields::Unit => None,
+            Fields::Unnamed(fields) if fields.unnamed.len() == 1 => Some(fields.unnamed[0].ty.clone()),
+            _ => return Err(Error::new_spanned(variant, "TiledDetail variants must be unit variants or hold a single value")),
+        };
+
+        for metas in tiled_attributes(&variant.attrs)? {
+            let mut tile = false;
+            let mut object_type = None;
+            let mut requires_sprite = false;
+            let mut collider = false;
+            let mut with = None;
+            for meta in metas {
+                let meta_ident = meta_name(&meta).to_string();
+                match meta_ident.as_ref() {
+                    "tile" => tile = true,
+                    "object_type" => object_type = Some(meta_str(&meta)?),
+                    "requires_sprite" => requires_sprite = true,
+                    "collider" => collider = true,
+                    "with" => with = Some(meta_str(&meta)?.parse::<Path>()?),
+                    _ => return Err(Error::new_spanned(meta, "unknown tiled attribute")),
+                }
+            }
+            let rule = match (tile, object_type) {
+                (true, None) => Rule::Tile { with },
+                (false, Some(object_type)) => Rule::Object { object_type, requires_sprite, collider, with },
+                _ => return Err(Error::new_spanned(variant, "expected exactly one of tile or object_type")),
+            };
+
+            match rule {
+                Rule::Tile { with } => {
+                    if tile_arm.is_some() {
+                        return Err(Error::new_spanned(variant, "only one variant can be built from tiles"));
+                    }
+                    tile_arm = Some(match (&field_ty, with) {
+                        (None, _) => quote!(Some(#name::#ident)),
+                        (Some(_), Some(with)) => quote!(#with(ctx, cell).map(#name::#ident)),
+                        (Some(ty), None) => quote!(Some(#name::#ident(<#ty as ::std::convert::From<::pietra_dura_tiled::TileCell>>::from(cell)))),
+                    });
+                }
+                Rule::Object { object_type, requires_sprite, collider, with } => {
+                    if object_types.contains(&object_type.value()) {
+                        return Err(Error::new_spanned(object_type, "object type is handled by more than one variant"));
+                    }
+                    object_types.push(object_type.value());
+                    if collider {
+                        collider_types.push(object_type.value());
+                    }
+                    let value = match (&field_ty, with) {
+                        (None, _) => quote!(Some(#name::#ident)),
+                        (Some(_), Some(with)) => quote!(#with(ctx, object).map(#name::#ident)),
+                        (Some(ty), None) => quote!(<#ty as ::pietra_dura_tiled::FromTiledObject>::from_tiled_object(object).map(#name::#ident)),
+                    };
+                    let sprite_check = if requires_sprite {
+                        quote!(if ctx.is_none() { return None; })
+                    } else {
+                        quote!()
+                    };
+                    object_arms.push(quote! {
+                        #object_type => {
+                            #sprite_check
+                            #value
+                        }
+                    });
+                }
+            }
+        }
+    }
+    let tile_arm = tile_arm.unwrap_or_else(|| quote!(None));
+
+    Ok(quote! {
+        impl #impl_generics ::pietra_dura_tiled::TiledDetail for #name #ty_generics #where_clause {
+            const OBJECT_TYPES: &'static [&'static str] = &[#(#object_types),*];
+            const COLLIDER_TYPES: &'static [&'static str] = &[#(#collider_types),*];
+
+            #[allow(unused_variables)]
+            fn from_tile(ctx: &::pietra_dura_tiled::SpriteContext, cell: ::pietra_dura_tiled::TileCell) -> Option<Self> {
+                #tile_arm
+            }
+
+            #[allow(unused_variables)]
+            fn from_object(ctx: &Option<::pietra_dura_tiled::SpriteContext>, layer: usize, object: &::pietra_dura_tiled::Object) -> Option<Self> {
+                match object.obj_type.as_str() {
+                    #(#object_arms)*
+                    _ => None,
+                }
+            }
+
+            fn kind(&self) -> &'static str {
+                match self {
+                    #(#kind_arms)*
+                }
+            }
+        }
+    })
+}
+
+fn from_tiled_object_impl(input: &DeriveInput) -> Result<TokenStream2, Error> {
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let fields = match &input.data {
+        Data::Struct(data) => &data.fields,
+        _ => return Err(Error::new_spanned(name, "FromTiledObject can only be derived for structs")),
+    };
+
+    let body = match fields {
+        Fields::Unit => quote!(Some(#name)),
+        Fields::Named(fields) => {
+            let mut reads = Vec::new();
+            let mut idents = Vec::new();
+            for field in &fields.named {
+                let ident = field.ident.clone().expect("Named field without a name");
+                let ty = &field.ty;
+                let mut property = LitStr::new(&ident.to_string(), ident.span());
+                let mut default = false;
+                for metas in tiled_attributes(&field.attrs)? {
+                    for meta in metas {
+                        let meta_ident = meta_name(&meta).to_string();
+                        match meta_ident.as_ref() {
+                            "property" => property = meta_str(&meta)?,
+                            "default" => default = true,
+                            _ => return Err(Error::new_spanned(meta, "unknown tiled attribute")),
+                        }
+                    }
+                }
+                let missing = if default {
+                    quote!(::std::default::Default::default())
+                } else {
+                    quote!(return None)
+                };
+                reads.push(quote! {
+                    let #ident = match object.properties.get(#property).and_then(<#ty as ::pietra_dura_tiled::FromProperty>::from_property) {
+                        Some(value) => value,
+                        None => #missing,
+                    };
+                });
+                idents.push(ident);
+            }
+            quote! {
+                #(#reads)*
+                Some(#name { #(#idents),* })
+            }
+        }
+        Fields::Unnamed(_) => {
+            return Err(Error::new_spanned(name, "FromTiledObject can't be derived for tuple structs"))
+        }
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::pietra_dura_tiled::FromTiledObject for #name #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn from_tiled_object(object: &::pietra_dura_tiled::Object) -> Option<Self> {
+                #body
+            }
+        }
+    })
+}
+
+// Replaces the sprite prefabs with their proxies, looking inside `Option`
+fn proxy_type(ty: &Type) -> Type {
+    if let Type::Path(type_path) = ty {
+        let mut type_path = type_path.clone();
+        if let Some(segment) = type_path.path.segments.last_mut() {
+            let segment = segment.into_value();
+            let replacement = match segment.ident.to_string().as_ref() {
+                "SpriteSheetPrefab" => Some(quote!(::pietra_dura_tiled::SpriteSheetPrefab)),
+                "SpriteRenderPrefab" => Some(quote!(::pietra_dura_tiled::SpriteRenderPrefab)),
+                "SpriteScenePrefab" => Some(quote!(::pietra_dura_tiled::SpriteScenePrefab)),
+                _ => None,
+            };
+            if let Some(replacement) = replacement {
+                return syn::parse2(replacement).expect("Invalid proxy type");
+            }
+            if segment.ident == "Option" {
+                if let PathArguments::AngleBracketed(args) = &mut segment.arguments {
+                    for arg in args.args.iter_mut() {
+                        if let GenericArgument::Type(inner) = arg {
+                            *inner = proxy_type(inner);
+                        }
+                    }
+                }
+            }
+        }
+        return Type::Path(type_path);
+    }
+    ty.clone()
+}
+
+fn prefab_proxy_impl(input: &DeriveInput) -> Result<TokenStream2, Error> {
+    let name = &input.ident;
+    let vis = &input.vis;
+    let generics = &input.generics;
+    let where_clause = &input.generics.where_clause;
+
+    let mut proxy_name = None;
+    for metas in named_attributes(&input.attrs, "proxy")? {
+        for meta in metas {
+            if *meta_name(&meta) == "name" {
+                proxy_name = Some(meta_str(&meta)?.parse::<Ident>()?);
+            } else {
+                return Err(Error::new_spanned(meta, "unknown proxy attribute"));
+            }
+        }
+    }
+    let proxy_name = proxy_name.unwrap_or_else(|| Ident::new(&format!("{}Proxy", name), name.span()));
+
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => return Err(Error::new_spanned(name, "PrefabProxy needs named fields")),
+        },
+        _ => return Err(Error::new_spanned(name, "PrefabProxy can only be derived for structs")),
+    };
+
+    let mut proxy_fields = Vec::new();
+    let mut field_counts = Vec::new();
+    let mut field_writes = Vec::new();
+    for field in fields {
+        let ident = field.ident.clone().expect("Named field without a name");
+        let field_name = ident.to_string();
+        let mut ty = proxy_type(&field.ty);
+        for metas in named_attributes(&field.attrs, "proxy")? {
+            for meta in metas {
+                if *meta_name(&meta) == "with" {
+                    ty = meta_str(&meta)?.parse()?;
+                } else {
+                    return Err(Error::new_spanned(meta, "unknown proxy attribute"));
+                }
+            }
+        }
+        if is_option(&field.ty) && has_serde_default(&field.attrs)? {
+            field_counts.push(quote!(if readable && self.#ident.is_none() { 0 } else { 1 }));
+            field_writes.push(quote! {
+                if readable && self.#ident.is_none() {
+                    state.skip_field(#field_name)?;
+                } else {
+                    state.serialize_field(#field_name, &self.#ident)?;
+                }
+            });
+        } else {
+            field_counts.push(quote!(1));
+            field_writes.push(quote!(state.serialize_field(#field_name, &self.#ident)?;));
+        }
+        proxy_fields.push(quote!(pub #ident: #ty));
+    }
+
+    // Like `PrefabEntity`, fields are only skipped for human-readable formats
+    let mut serialize_generics = generics.clone();
+    for param in serialize_generics.type_params_mut() {
+        param.bounds.push(syn::parse_quote!(::serde::Serialize));
+    }
+    let (impl_generics, ty_generics, serialize_where) = serialize_generics.split_for_impl();
+    let proxy_str = proxy_name.to_string();
+
+    Ok(quote! {
+        #[derive(Debug, Clone)]
+        #vis struct #proxy_name #generics #where_clause {
+            #(#proxy_fields),*
+        }
+
+        impl #impl_generics ::serde::Serialize for #proxy_name #ty_generics #serialize_where {
+            fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error> {
+                use ::serde::ser::SerializeStruct;
+                let readable = serializer.is_human_readable();
+                let len = 0 #(+ #field_counts)*;
+                let mut state = serializer.serialize_struct(#proxy_str, len)?;
+                #(#field_writes)*
+                state.end()
+            }
+        }
+    })
+}
+
+fn is_option(ty: &Type) -> bool {
+    match ty {
+        Type::Path(type_path) => type_path.path.segments.last()
+            .map(|segment| segment.value().ident == "Option")
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+fn has_serde_default(attrs: &[Attribute]) -> Result<bool, Error> {
+    Ok(named_attributes(attrs, "serde")?.iter()
+        .flat_map(|metas| metas.iter())
+        .any(|meta| *meta_name(meta) == "default"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(result: Result<TokenStream2, Error>) -> String {
+        match result {
+            Ok(tokens) => panic!("expected an error, got {}", tokens),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    fn converter_error(input: &str) -> String {
+        error(converter_impl(&syn::parse_str(input).expect("Invalid test input")))
+    }
+
+    fn detail_error(input: &str) -> String {
+        error(detail_impl(&syn::parse_str(input).expect("Invalid test input")))
+    }
+
+    #[test]
+    fn converter_errors() {
+        let cases = [
+            (
+                r#"#[tiled(proxy = "P")] struct L { sheet: Option<S>, detail: D, extra: u32 }"#,
+                "field isn't one of sheet, render, transform, parallax, y_sort, name, object_type, collider_cells or detail",
+            ),
+            (r#"#[tiled(proxy = "P")] struct L { sheet: Option<S> }"#, "TiledConverter needs a detail field"),
+            (r#"struct L { detail: D }"#, "missing #[tiled(proxy = \"ProxyType\")]"),
+            (r#"#[tiled(proxy = "P")] struct L<T> { detail: T }"#, "TiledConverter can't be derived for generic prefabs"),
+            (r#"#[tiled(proxy = "P")] struct L(D);"#, "TiledConverter needs named fields"),
+            (r#"#[tiled(proxy = "P")] enum L { A }"#, "TiledConverter can only be derived for structs"),
+            (r#"#[tiled(proxy = "P", sprite)] struct L { detail: D }"#, "unknown tiled attribute"),
+            (r#"#[tiled(proxy = 1)] struct L { detail: D }"#, "expected a string"),
+        ];
+        for (input, expected) in &cases {
+            assert_eq!(converter_error(input), *expected, "for {}", input);
+        }
+    }
+
+    #[test]
+    fn converter_fields_can_be_renamed() {
+        let input = syn::parse_str(r#"#[tiled(proxy = "P")] struct L { #[tiled(detail)] kind: D, #[tiled(transform)] at: Option<T> }"#)
+            .unwrap();
+        let tokens = converter_impl(&input).expect("Renamed fields should be accepted").to_string();
+        assert!(tokens.contains("kind : detail"));
+        assert!(tokens.contains("fn with_z"));
+        assert!(!tokens.contains("fn with_parallax"));
+    }
+
+    #[test]
+    fn detail_errors() {
+        let cases = [
+            (r#"enum D { #[tiled(tile, object_type = "a")] A }"#, "expected exactly one of tile or object_type"),
+            (r#"enum D { #[tiled(requires_sprite)] A }"#, "expected exactly one of tile or object_type"),
+            (r#"enum D { #[tiled(tile)] A, #[tiled(tile)] B }"#, "only one variant can be built from tiles"),
+            (
+                r#"enum D { #[tiled(object_type = "a")] A, #[tiled(object_type = "a")] B }"#,
+                "object type is handled by more than one variant",
+            ),
+            (r#"enum D { #[tiled(tile, solid)] A }"#, "unknown tiled attribute"),
+            (r#"enum D { A(u32, u32) }"#, "TiledDetail variants must be unit variants or hold a single value"),
+            (r#"struct D;"#, "TiledDetail can only be derived for enums"),
+        ];
+        for (input, expected) in &cases {
+            assert_eq!(detail_error(input), *expected, "for {}", input);
+        }
+    }
+
+    #[test]
+    fn detail_lists_object_and_collider_types() {
+        let input = syn::parse_str(
+            r#"enum D { #[tiled(object_type = "a", collider)] #[tiled(object_type = "b")] A(X), #[tiled(tile)] T }"#,
+        ).unwrap();
+        let tokens = detail_impl(&input).expect("Valid detail").to_string();
+        assert!(tokens.contains(r#"const OBJECT_TYPES : & 'static [ & 'static str ] = & [ "a" , "b" ]"#));
+        assert!(tokens.contains(r#"const COLLIDER_TYPES : & 'static [ & 'static str ] = & [ "a" ]"#));
+    }
+
+    #[test]
+    fn from_tiled_object_errors() {
+        let cases = [
+            (r#"struct S(u32);"#, "FromTiledObject can't be derived for tuple structs"),
+            (r#"enum S { A }"#, "FromTiledObject can only be derived for structs"),
+            (r#"struct S { #[tiled(required)] a: u32 }"#, "unknown tiled attribute"),
+            (r#"struct S { #[tiled(property)] a: u32 }"#, "expected name = \"value\""),
+        ];
+        for (input, expected) in &cases {
+            let input = syn::parse_str(input).expect("Invalid test input");
+            assert_eq!(error(from_tiled_object_impl(&input)), *expected);
+        }
+    }
+
+    #[test]
+    fn proxy_errors() {
+        let cases = [
+            (r#"#[proxy(title = "P")] struct L { a: u32 }"#, "unknown proxy attribute"),
+            (r#"struct L { #[proxy(skip)] a: u32 }"#, "unknown proxy attribute"),
+            (r#"struct L(u32);"#, "PrefabProxy needs named fields"),
+            (r#"enum L { A }"#, "PrefabProxy can only be derived for structs"),
+        ];
+        for (input, expected) in &cases {
+            let input = syn::parse_str(input).expect("Invalid test input");
+            assert_eq!(error(prefab_proxy_impl(&input)), *expected);
+        }
+    }
+
+    #[test]
+    fn proxy_only_skips_defaulted_options() {
+        let input = syn::parse_str(
+            r#"struct L { sheet: Option<SpriteSheetPrefab>, #[serde(default)] parallax: Option<P>, count: u32 }"#,
+        ).unwrap();
+        let tokens = prefab_proxy_impl(&input).expect("Valid proxy").to_string();
+        assert!(tokens.contains("struct LProxy"));
+        assert!(tokens.contains(":: pietra_dura_tiled :: SpriteSheetPrefab"));
+        assert!(tokens.contains(r#"state . skip_field ( "parallax" )"#));
+        assert!(!tokens.contains(r#"skip_field ( "sheet" )"#));
+        assert!(!tokens.contains(r#"skip_field ( "count" )"#));
+    }
+}